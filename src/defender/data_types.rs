@@ -0,0 +1,569 @@
+use serde::ser::{SerializeMap, SerializeSeq, SerializeStruct};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Version of the metrics document format.
+pub const VERSION: &str = "1.0";
+
+/// A listening TCP or UDP port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ListeningPort<'a> {
+    /// Optional. The name of the interface the port is listening on.
+    pub interface: Option<&'a str>,
+
+    /// The port number.
+    pub port: u16,
+}
+
+/// An established TCP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TcpConnection<'a> {
+    /// Optional. The name of the local network interface.
+    pub local_interface: Option<&'a str>,
+
+    /// Optional. The local port number.
+    pub local_port: Option<u16>,
+
+    /// The remote IP address and port number of the connection, eg.
+    /// `192.168.0.1:8000`
+    pub remote_addr: &'a str,
+}
+
+/// Network traffic counters since the device booted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetworkStats {
+    /// Number of incoming bytes.
+    pub bytes_in: u64,
+
+    /// Number of outgoing bytes.
+    pub bytes_out: u64,
+
+    /// Number of incoming packets.
+    pub packets_in: u64,
+
+    /// Number of outgoing packets.
+    pub packets_out: u64,
+}
+
+/// Value of a custom metric, matching the metric type configured in Device
+/// Defender.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CustomMetric<'a> {
+    /// A single numeric value.
+    Number(f64),
+
+    /// A list of numeric values.
+    NumberList(&'a [f64]),
+
+    /// A list of strings.
+    StringList(&'a [&'a str]),
+
+    /// A list of IP addresses in IPv4 or IPv6 format.
+    IpList(&'a [&'a str]),
+}
+
+/// Key names used in the metrics document.
+///
+/// Device Defender accepts both long names, used for JSON reports, and short
+/// names, used for the compact CBOR reports.
+#[derive(Debug)]
+pub(crate) struct Keys {
+    header: &'static str,
+    report_id: &'static str,
+    version: &'static str,
+    metrics: &'static str,
+    listening_tcp_ports: &'static str,
+    listening_udp_ports: &'static str,
+    ports: &'static str,
+    interface: &'static str,
+    port: &'static str,
+    total: &'static str,
+    network_stats: &'static str,
+    bytes_in: &'static str,
+    bytes_out: &'static str,
+    packets_in: &'static str,
+    packets_out: &'static str,
+    tcp_connections: &'static str,
+    established_connections: &'static str,
+    connections: &'static str,
+    local_interface: &'static str,
+    local_port: &'static str,
+    remote_addr: &'static str,
+    custom_metrics: &'static str,
+}
+
+impl Keys {
+    pub(crate) const LONG: Self = Self {
+        header: "header",
+        report_id: "report_id",
+        version: "version",
+        metrics: "metrics",
+        listening_tcp_ports: "listening_tcp_ports",
+        listening_udp_ports: "listening_udp_ports",
+        ports: "ports",
+        interface: "interface",
+        port: "port",
+        total: "total",
+        network_stats: "network_stats",
+        bytes_in: "bytes_in",
+        bytes_out: "bytes_out",
+        packets_in: "packets_in",
+        packets_out: "packets_out",
+        tcp_connections: "tcp_connections",
+        established_connections: "established_connections",
+        connections: "connections",
+        local_interface: "local_interface",
+        local_port: "local_port",
+        remote_addr: "remote_addr",
+        custom_metrics: "custom_metrics",
+    };
+
+    #[cfg(feature = "defender_cbor")]
+    pub(crate) const SHORT: Self = Self {
+        header: "hed",
+        report_id: "rid",
+        version: "v",
+        metrics: "met",
+        listening_tcp_ports: "tp",
+        listening_udp_ports: "up",
+        ports: "pts",
+        interface: "if",
+        port: "pt",
+        total: "t",
+        network_stats: "ns",
+        bytes_in: "bi",
+        bytes_out: "bo",
+        packets_in: "pi",
+        packets_out: "po",
+        tcp_connections: "tc",
+        established_connections: "ec",
+        connections: "cs",
+        local_interface: "li",
+        local_port: "lp",
+        remote_addr: "rad",
+        custom_metrics: "cmet",
+    };
+}
+
+/// A complete Device Defender metrics document.
+///
+/// Serialized with long key names or short key names depending on the `keys`
+/// it was created with. Use [`super::Report`] to build and publish it.
+#[derive(Debug)]
+pub struct MetricsReport<'a> {
+    pub(crate) keys: &'static Keys,
+    pub(crate) report_id: u64,
+    pub(crate) listening_tcp_ports: Option<&'a [ListeningPort<'a>]>,
+    pub(crate) listening_udp_ports: Option<&'a [ListeningPort<'a>]>,
+    pub(crate) network_stats: Option<NetworkStats>,
+    pub(crate) tcp_connections: Option<&'a [TcpConnection<'a>]>,
+    pub(crate) custom_metrics: &'a [(&'a str, CustomMetric<'a>)],
+}
+
+impl<'a> Serialize for MetricsReport<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len = 2 + usize::from(!self.custom_metrics.is_empty());
+        let mut s = serializer.serialize_struct("MetricsReport", len)?;
+        s.serialize_field(self.keys.header, &Header(self.keys, self.report_id))?;
+        s.serialize_field(self.keys.metrics, &Metrics(self))?;
+        if !self.custom_metrics.is_empty() {
+            s.serialize_field(
+                self.keys.custom_metrics,
+                &CustomMetrics(self.custom_metrics),
+            )?;
+        }
+        s.end()
+    }
+}
+
+struct Header(&'static Keys, u64);
+
+impl Serialize for Header {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Header", 2)?;
+        s.serialize_field(self.0.report_id, &self.1)?;
+        s.serialize_field(self.0.version, VERSION)?;
+        s.end()
+    }
+}
+
+struct Metrics<'a, 'b>(&'b MetricsReport<'a>);
+
+impl<'a, 'b> Serialize for Metrics<'a, 'b> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let report = self.0;
+        let keys = report.keys;
+
+        let len = usize::from(report.listening_tcp_ports.is_some())
+            + usize::from(report.listening_udp_ports.is_some())
+            + usize::from(report.network_stats.is_some())
+            + usize::from(report.tcp_connections.is_some());
+
+        let mut s = serializer.serialize_struct("Metrics", len)?;
+        if let Some(ports) = report.listening_tcp_ports {
+            s.serialize_field(keys.listening_tcp_ports, &Ports(keys, ports))?;
+        }
+        if let Some(ports) = report.listening_udp_ports {
+            s.serialize_field(keys.listening_udp_ports, &Ports(keys, ports))?;
+        }
+        if let Some(ref stats) = report.network_stats {
+            s.serialize_field(keys.network_stats, &Stats(keys, stats))?;
+        }
+        if let Some(connections) = report.tcp_connections {
+            s.serialize_field(keys.tcp_connections, &Tcp(keys, connections))?;
+        }
+        s.end()
+    }
+}
+
+struct Ports<'a>(&'static Keys, &'a [ListeningPort<'a>]);
+
+impl<'a> Serialize for Ports<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ListeningPorts", 2)?;
+        s.serialize_field(self.0.ports, &List(self.0, self.1))?;
+        s.serialize_field(self.0.total, &self.1.len())?;
+        s.end()
+    }
+}
+
+struct Stats<'a>(&'static Keys, &'a NetworkStats);
+
+impl<'a> Serialize for Stats<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("NetworkStats", 4)?;
+        s.serialize_field(self.0.bytes_in, &self.1.bytes_in)?;
+        s.serialize_field(self.0.bytes_out, &self.1.bytes_out)?;
+        s.serialize_field(self.0.packets_in, &self.1.packets_in)?;
+        s.serialize_field(self.0.packets_out, &self.1.packets_out)?;
+        s.end()
+    }
+}
+
+struct Tcp<'a>(&'static Keys, &'a [TcpConnection<'a>]);
+
+impl<'a> Serialize for Tcp<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("TcpConnections", 1)?;
+        s.serialize_field(
+            self.0.established_connections,
+            &EstablishedConnections(self.0, self.1),
+        )?;
+        s.end()
+    }
+}
+
+struct EstablishedConnections<'a>(&'static Keys, &'a [TcpConnection<'a>]);
+
+impl<'a> Serialize for EstablishedConnections<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("EstablishedConnections", 2)?;
+        s.serialize_field(self.0.connections, &List(self.0, self.1))?;
+        s.serialize_field(self.0.total, &self.1.len())?;
+        s.end()
+    }
+}
+
+/// A list of items that each needs the key names to serialize.
+struct List<'a, T>(&'static Keys, &'a [T]);
+
+impl<'a, T> Serialize for List<'a, T>
+where
+    for<'b> Keyed<'b, T>: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_seq(Some(self.1.len()))?;
+        for item in self.1 {
+            s.serialize_element(&Keyed(self.0, item))?;
+        }
+        s.end()
+    }
+}
+
+struct Keyed<'a, T>(&'static Keys, &'a T);
+
+impl<'a, 'b> Serialize for Keyed<'a, ListeningPort<'b>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let port = self.1;
+        let mut s = serializer
+            .serialize_struct("ListeningPort", 1 + usize::from(port.interface.is_some()))?;
+        if let Some(interface) = port.interface {
+            s.serialize_field(self.0.interface, interface)?;
+        }
+        s.serialize_field(self.0.port, &port.port)?;
+        s.end()
+    }
+}
+
+impl<'a, 'b> Serialize for Keyed<'a, TcpConnection<'b>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let connection = self.1;
+        let len = 1
+            + usize::from(connection.local_interface.is_some())
+            + usize::from(connection.local_port.is_some());
+        let mut s = serializer.serialize_struct("TcpConnection", len)?;
+        if let Some(local_interface) = connection.local_interface {
+            s.serialize_field(self.0.local_interface, local_interface)?;
+        }
+        if let Some(ref local_port) = connection.local_port {
+            s.serialize_field(self.0.local_port, local_port)?;
+        }
+        s.serialize_field(self.0.remote_addr, connection.remote_addr)?;
+        s.end()
+    }
+}
+
+struct CustomMetrics<'a>(&'a [(&'a str, CustomMetric<'a>)]);
+
+impl<'a> Serialize for CustomMetrics<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, metric) in self.0 {
+            // Each custom metric is reported as an array holding a single
+            // value object.
+            s.serialize_entry(name, &[metric])?;
+        }
+        s.end()
+    }
+}
+
+impl<'a> Serialize for CustomMetric<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CustomMetric", 1)?;
+        match self {
+            CustomMetric::Number(n) => s.serialize_field("number", n)?,
+            CustomMetric::NumberList(l) => s.serialize_field("number_list", l)?,
+            CustomMetric::StringList(l) => s.serialize_field("string_list", l)?,
+            CustomMetric::IpList(l) => s.serialize_field("ip_list", l)?,
+        }
+        s.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportStatus {
+    #[serde(rename = "ACCEPTED")]
+    Accepted,
+    #[serde(rename = "REJECTED")]
+    Rejected,
+}
+
+/// Details on why a metrics report was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RejectedDetails<'a> {
+    /// The error code, eg. `InvalidPayload`.
+    #[serde(rename = "ErrorCode")]
+    pub error_code: &'a str,
+
+    /// Optional. A human readable description of the error.
+    #[serde(rename = "ErrorMessage")]
+    pub error_message: Option<&'a str>,
+}
+
+/// Subscribe to `$aws/things/<thingName>/defender/metrics/<payloadFormat>/accepted`
+/// and `$aws/things/<thingName>/defender/metrics/<payloadFormat>/rejected` to
+/// receive the outcome of a published metrics report.
+///
+/// **<payloadFormat>:** The message payload format as `cbor` or `json`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReportResponse<'a> {
+    /// The name of the thing that published the report.
+    #[serde(rename = "thingName")]
+    pub thing_name: &'a str,
+
+    /// The `report_id` from the header of the published report.
+    #[serde(rename = "reportId")]
+    pub report_id: u64,
+
+    /// Whether the report was accepted or rejected.
+    #[serde(rename = "status")]
+    pub status: ReportStatus,
+
+    /// Only present if the report was rejected.
+    #[serde(rename = "statusDetails")]
+    pub status_details: Option<RejectedDetails<'a>>,
+
+    /// The time, in seconds since the epoch, when the response was sent.
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report<'a>(
+        keys: &'static Keys,
+        tcp: &'a [ListeningPort<'a>],
+        connections: &'a [TcpConnection<'a>],
+        custom: &'a [(&'a str, CustomMetric<'a>)],
+    ) -> MetricsReport<'a> {
+        MetricsReport {
+            keys,
+            report_id: 1530304554,
+            listening_tcp_ports: Some(tcp),
+            listening_udp_ports: None,
+            network_stats: Some(NetworkStats {
+                bytes_in: 29358693495,
+                bytes_out: 26485035,
+                packets_in: 10013573555,
+                packets_out: 11382615,
+            }),
+            tcp_connections: Some(connections),
+            custom_metrics: custom,
+        }
+    }
+
+    #[test]
+    fn serialize_long_names() {
+        let tcp = [ListeningPort {
+            interface: Some("eth0"),
+            port: 24800,
+        }];
+        let connections = [TcpConnection {
+            local_interface: Some("eth0"),
+            local_port: Some(80),
+            remote_addr: "192.168.0.1:8000",
+        }];
+        let custom = [
+            ("cpu_usage", CustomMetric::Number(12.5)),
+            ("cert_names", CustomMetric::StringList(&["a", "b"])),
+        ];
+
+        let payload = serde_json_core::to_string::<_, 1024>(&report(
+            &Keys::LONG,
+            &tcp,
+            &connections,
+            &custom,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            payload.as_str(),
+            r#"{"header":{"report_id":1530304554,"version":"1.0"},"metrics":{"listening_tcp_ports":{"ports":[{"interface":"eth0","port":24800}],"total":1},"network_stats":{"bytes_in":29358693495,"bytes_out":26485035,"packets_in":10013573555,"packets_out":11382615},"tcp_connections":{"established_connections":{"connections":[{"local_interface":"eth0","local_port":80,"remote_addr":"192.168.0.1:8000"}],"total":1}}},"custom_metrics":{"cpu_usage":[{"number":12.5}],"cert_names":[{"string_list":["a","b"]}]}}"#
+        );
+    }
+
+    #[test]
+    fn serialize_empty_metrics() {
+        let payload = serde_json_core::to_string::<_, 128>(&MetricsReport {
+            keys: &Keys::LONG,
+            report_id: 1,
+            listening_tcp_ports: None,
+            listening_udp_ports: None,
+            network_stats: None,
+            tcp_connections: None,
+            custom_metrics: &[],
+        })
+        .unwrap();
+
+        assert_eq!(
+            payload.as_str(),
+            r#"{"header":{"report_id":1,"version":"1.0"},"metrics":{}}"#
+        );
+    }
+
+    #[cfg(feature = "defender_cbor")]
+    #[test]
+    fn serialize_short_names() {
+        use heapless::LinearMap;
+
+        let tcp = [ListeningPort {
+            interface: None,
+            port: 8883,
+        }];
+        let connections = [TcpConnection {
+            local_interface: None,
+            local_port: None,
+            remote_addr: "10.0.0.1:443",
+        }];
+
+        let buf = &mut [0u8; 256];
+        let mut serializer =
+            serde_cbor::ser::Serializer::new(serde_cbor::ser::SliceWrite::new(buf));
+        report(&Keys::SHORT, &tcp, &connections, &[])
+            .serialize(&mut serializer)
+            .unwrap();
+        let len = serializer.into_inner().bytes_written();
+
+        #[derive(Debug, Deserialize)]
+        struct Short<'a> {
+            #[serde(borrow)]
+            hed: ShortHeader<'a>,
+            #[serde(borrow)]
+            met: ShortMetrics<'a>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct ShortHeader<'a> {
+            rid: u64,
+            v: &'a str,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct ShortMetrics<'a> {
+            #[serde(borrow)]
+            tp: ShortPorts<'a>,
+            #[serde(borrow)]
+            ns: LinearMap<&'a str, u64, 4>,
+            #[serde(borrow)]
+            tc: LinearMap<&'a str, ShortConnections<'a>, 1>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct ShortPorts<'a> {
+            #[serde(borrow)]
+            pts: heapless::Vec<LinearMap<&'a str, u16, 1>, 1>,
+            t: usize,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct ShortConnections<'a> {
+            #[serde(borrow)]
+            cs: heapless::Vec<LinearMap<&'a str, &'a str, 1>, 1>,
+            t: usize,
+        }
+
+        let short: Short = serde_cbor::de::from_mut_slice(&mut buf[..len]).unwrap();
+
+        assert_eq!(short.hed.rid, 1530304554);
+        assert_eq!(short.hed.v, VERSION);
+        assert_eq!(short.met.tp.t, 1);
+        assert_eq!(short.met.tp.pts[0].get("pt"), Some(&8883));
+        assert_eq!(short.met.ns.get("bi"), Some(&29358693495));
+        let ec = short.met.tc.get("ec").unwrap();
+        assert_eq!(ec.t, 1);
+        assert_eq!(ec.cs[0].get("rad"), Some(&"10.0.0.1:443"));
+    }
+
+    #[test]
+    fn deserialize_responses() {
+        let (accepted, _) = serde_json_core::from_str::<ReportResponse>(
+            r#"{"thingName":"my-thing","reportId":1530304554,"status":"ACCEPTED","timestamp":1530304556}"#,
+        )
+        .unwrap();
+
+        assert_eq!(accepted.status, ReportStatus::Accepted);
+        assert_eq!(accepted.report_id, 1530304554);
+        assert_eq!(accepted.status_details, None);
+
+        let (rejected, _) = serde_json_core::from_str::<ReportResponse>(
+            r#"{"thingName":"my-thing","reportId":1530304554,"status":"REJECTED","statusDetails":{"ErrorCode":"InvalidPayload","ErrorMessage":"Malformed JSON"},"timestamp":1530304556}"#,
+        )
+        .unwrap();
+
+        assert_eq!(rejected.status, ReportStatus::Rejected);
+        assert_eq!(
+            rejected.status_details,
+            Some(RejectedDetails {
+                error_code: "InvalidPayload",
+                error_message: Some("Malformed JSON"),
+            })
+        );
+    }
+}
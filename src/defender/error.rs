@@ -0,0 +1,38 @@
+use mqttrust::MqttError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Overflow,
+    InvalidPayload,
+    Mqtt(MqttError),
+}
+
+impl From<MqttError> for Error {
+    fn from(e: MqttError) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+impl From<serde_json_core::ser::Error> for Error {
+    fn from(_: serde_json_core::ser::Error) -> Self {
+        Self::Overflow
+    }
+}
+
+impl From<serde_json_core::de::Error> for Error {
+    fn from(_: serde_json_core::de::Error) -> Self {
+        Self::InvalidPayload
+    }
+}
+
+#[cfg(feature = "defender_cbor")]
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Self {
+        if e.is_scratch_too_small() {
+            Self::Overflow
+        } else {
+            Self::InvalidPayload
+        }
+    }
+}
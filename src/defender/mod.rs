@@ -0,0 +1,282 @@
+//! # AWS IoT Device Defender
+//!
+//! Devices report metrics to Device Defender by publishing a metrics document
+//! to `$aws/things/{thingName}/defender/metrics/{json|cbor}`. The outcome of
+//! each report is published by AWS IoT to the `accepted` and `rejected`
+//! sub-topics, which the device should subscribe to before reporting.
+//!
+//! JSON reports use the long key names (eg. `listening_tcp_ports`), while CBOR
+//! reports use the compact short key names (eg. `tp`) to keep payloads small.
+//!
+//! ## Workflow:
+//! 1. Subscribe to the accepted and rejected topics using
+//!    [`Defender::subscribe`].
+//! 2. Periodically (at most every 5 minutes) build a report with
+//!    [`Defender::report`], using a monotonically increasing `report_id`, and
+//!    publish it.
+//! 3. Pass incoming messages on the defender topics to
+//!    [`Defender::handle_message`] to learn whether the report was accepted.
+//!
+//! <https://docs.aws.amazon.com/iot/latest/developerguide/detect-device-side-metrics.html>
+pub mod data_types;
+mod error;
+pub mod topics;
+
+use core::str::FromStr;
+
+use mqttrust::{Mqtt, QoS};
+#[cfg(feature = "defender_cbor")]
+use serde::Serialize;
+
+use self::data_types::{
+    CustomMetric, Keys, ListeningPort, MetricsReport, NetworkStats, ReportResponse, TcpConnection,
+};
+pub use self::error::Error;
+use self::topics::{PayloadFormat, Subscribe, Topic, Unsubscribe, MAX_TOPIC_LEN};
+
+/// Builder for a Device Defender metrics report.
+pub struct Report<'a> {
+    report_id: u64,
+    payload_format: PayloadFormat,
+    listening_tcp_ports: Option<&'a [ListeningPort<'a>]>,
+    listening_udp_ports: Option<&'a [ListeningPort<'a>]>,
+    network_stats: Option<NetworkStats>,
+    tcp_connections: Option<&'a [TcpConnection<'a>]>,
+    custom_metrics: &'a [(&'a str, CustomMetric<'a>)],
+}
+
+impl<'a> Report<'a> {
+    pub fn new(report_id: u64) -> Self {
+        Self {
+            report_id,
+            payload_format: PayloadFormat::Json,
+            listening_tcp_ports: None,
+            listening_udp_ports: None,
+            network_stats: None,
+            tcp_connections: None,
+            custom_metrics: &[],
+        }
+    }
+
+    #[cfg(feature = "defender_cbor")]
+    pub fn cbor(self) -> Self {
+        Self {
+            payload_format: PayloadFormat::Cbor,
+            ..self
+        }
+    }
+
+    pub fn listening_tcp_ports(self, ports: &'a [ListeningPort<'a>]) -> Self {
+        Self {
+            listening_tcp_ports: Some(ports),
+            ..self
+        }
+    }
+
+    pub fn listening_udp_ports(self, ports: &'a [ListeningPort<'a>]) -> Self {
+        Self {
+            listening_udp_ports: Some(ports),
+            ..self
+        }
+    }
+
+    pub fn network_stats(self, network_stats: NetworkStats) -> Self {
+        Self {
+            network_stats: Some(network_stats),
+            ..self
+        }
+    }
+
+    pub fn tcp_connections(self, connections: &'a [TcpConnection<'a>]) -> Self {
+        Self {
+            tcp_connections: Some(connections),
+            ..self
+        }
+    }
+
+    /// Custom metrics as `(metric name, value)` pairs. The metric names must
+    /// match custom metrics created in Device Defender.
+    pub fn custom_metrics(self, custom_metrics: &'a [(&'a str, CustomMetric<'a>)]) -> Self {
+        Self {
+            custom_metrics,
+            ..self
+        }
+    }
+
+    fn metrics_report(&self) -> MetricsReport<'a> {
+        let keys = match self.payload_format {
+            #[cfg(feature = "defender_cbor")]
+            PayloadFormat::Cbor => &Keys::SHORT,
+            PayloadFormat::Json => &Keys::LONG,
+        };
+
+        MetricsReport {
+            keys,
+            report_id: self.report_id,
+            listening_tcp_ports: self.listening_tcp_ports,
+            listening_udp_ports: self.listening_udp_ports,
+            network_stats: self.network_stats,
+            tcp_connections: self.tcp_connections,
+            custom_metrics: self.custom_metrics,
+        }
+    }
+
+    /// Serialize the report into `buf`, returning the topic to publish it on
+    /// along with the number of bytes written.
+    pub fn topic_payload(
+        &self,
+        client_id: &str,
+        buf: &mut [u8],
+    ) -> Result<(heapless::String<MAX_TOPIC_LEN>, usize), Error> {
+        let report = self.metrics_report();
+
+        let len = match self.payload_format {
+            #[cfg(feature = "defender_cbor")]
+            PayloadFormat::Cbor => {
+                let mut serializer =
+                    serde_cbor::ser::Serializer::new(serde_cbor::ser::SliceWrite::new(buf));
+                report.serialize(&mut serializer)?;
+                serializer.into_inner().bytes_written()
+            }
+            PayloadFormat::Json => serde_json_core::to_slice(&report, buf)?,
+        };
+
+        Ok((Topic::Metrics(self.payload_format).format(client_id)?, len))
+    }
+
+    /// Publish the report, using `buf` as scratch space for the serialized
+    /// payload.
+    pub fn send<M: Mqtt>(self, mqtt: &M, buf: &mut [u8], qos: QoS) -> Result<(), Error> {
+        let (topic, len) = self.topic_payload(mqtt.client_id(), buf)?;
+
+        mqtt.publish(topic.as_str(), &buf[..len], qos)?;
+
+        Ok(())
+    }
+}
+
+pub struct Defender;
+
+impl Defender {
+    pub fn report<'a>(report_id: u64) -> Report<'a> {
+        Report::new(report_id)
+    }
+
+    /// Subscribe to the accepted and rejected topics of `payload_format`.
+    pub fn subscribe<M: Mqtt>(mqtt: &M, payload_format: PayloadFormat) -> Result<(), Error> {
+        Subscribe::<2>::new()
+            .topic(Topic::MetricsAccepted(payload_format), QoS::AtLeastOnce)
+            .topic(Topic::MetricsRejected(payload_format), QoS::AtLeastOnce)
+            .send(mqtt)
+    }
+
+    pub fn unsubscribe<M: Mqtt>(mqtt: &M, payload_format: PayloadFormat) -> Result<(), Error> {
+        Unsubscribe::<2>::new()
+            .topic(Topic::MetricsAccepted(payload_format))
+            .topic(Topic::MetricsRejected(payload_format))
+            .send(mqtt)
+    }
+
+    /// Parse a message received on one of the defender response topics.
+    ///
+    /// Returns `Ok(None)` if `topic_name` is not a defender response topic.
+    pub fn handle_message<'b>(
+        topic_name: &'b str,
+        payload: &'b mut [u8],
+    ) -> Result<Option<ReportResponse<'b>>, Error> {
+        match Topic::from_str(topic_name) {
+            Ok(Topic::MetricsAccepted(format) | Topic::MetricsRejected(format)) => {
+                let response = match format {
+                    #[cfg(feature = "defender_cbor")]
+                    PayloadFormat::Cbor => {
+                        serde_cbor::de::from_mut_slice::<ReportResponse>(payload)?
+                    }
+                    PayloadFormat::Json => {
+                        serde_json_core::from_slice::<ReportResponse>(payload)?.0
+                    }
+                };
+
+                if let Some(ref details) = response.status_details {
+                    warn!(
+                        "Metrics report {:?} rejected: {:?}",
+                        response.report_id, details.error_code
+                    );
+                }
+
+                Ok(Some(response))
+            }
+            t => {
+                trace!("{:?}", t);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::data_types::ReportStatus;
+    use super::*;
+    use crate::test::MockMqtt;
+
+    #[test]
+    fn send_report() {
+        let mqtt = &MockMqtt::new();
+
+        let udp = [ListeningPort {
+            interface: None,
+            port: 5353,
+        }];
+
+        Defender::report(1)
+            .listening_udp_ports(&udp)
+            .custom_metrics(&[("temperatures", CustomMetric::NumberList(&[1.5, 2.0]))])
+            .send(mqtt, &mut [0u8; 256], QoS::AtMostOnce)
+            .unwrap();
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        let publish = match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => p,
+            _ => panic!(),
+        };
+
+        assert_eq!(
+            publish.topic_name,
+            "$aws/things/test_client/defender/metrics/json"
+        );
+        assert_eq!(
+            publish.payload,
+            br#"{"header":{"report_id":1,"version":"1.0"},"metrics":{"listening_udp_ports":{"ports":[{"port":5353}],"total":1}},"custom_metrics":{"temperatures":[{"number_list":[1.5,2.0]}]}}"#
+        );
+    }
+
+    #[test]
+    fn report_overflow() {
+        assert_eq!(
+            Defender::report(1).topic_payload("test_client", &mut [0u8; 16]),
+            Err(Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn handle_message() {
+        let payload = &mut br#"{"thingName":"test_client","reportId":5,"status":"ACCEPTED","timestamp":1530304556}"#.to_owned();
+
+        let response = Defender::handle_message(
+            "$aws/things/test_client/defender/metrics/json/accepted",
+            payload,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(response.report_id, 5);
+        assert_eq!(response.status, ReportStatus::Accepted);
+
+        let payload = &mut br#"{"thingName":"test_client"}"#.to_owned();
+        assert_eq!(
+            Defender::handle_message("$aws/things/test_client/jobs/notify-next", payload),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use core::fmt::{Display, Write};
+use core::str::FromStr;
+
+use heapless::String;
+use mqttrust::{Mqtt, QoS, SubscribeTopic};
+
+use crate::jobs::MAX_THING_NAME_LEN;
+
+use super::Error;
+
+/// Maximum length of any Device Defender topic, given a thing name of at most
+/// `MAX_THING_NAME_LEN`.
+pub const MAX_TOPIC_LEN: usize = MAX_THING_NAME_LEN + 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PayloadFormat {
+    #[cfg(feature = "defender_cbor")]
+    Cbor,
+    Json,
+}
+
+impl Display for PayloadFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            #[cfg(feature = "defender_cbor")]
+            Self::Cbor => write!(f, "cbor"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for PayloadFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "defender_cbor")]
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Topic {
+    // ---- Outgoing Topics
+    /// `$aws/things/<thingName>/defender/metrics/<payloadFormat>`
+    Metrics(PayloadFormat),
+
+    // ---- Incoming Topics
+    /// `$aws/things/<thingName>/defender/metrics/<payloadFormat>/accepted`
+    MetricsAccepted(PayloadFormat),
+
+    /// `$aws/things/<thingName>/defender/metrics/<payloadFormat>/rejected`
+    MetricsRejected(PayloadFormat),
+}
+
+impl FromStr for Topic {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tt = s.splitn(7, '/').collect::<heapless::Vec<&str, 7>>();
+        match (tt.first(), tt.get(1), tt.get(3), tt.get(4)) {
+            (Some(&"$aws"), Some(&"things"), Some(&"defender"), Some(&"metrics")) => {
+                // This is a defender topic, now figure out which one.
+                let payload_format = PayloadFormat::from_str(tt.get(5).ok_or(())?)?;
+
+                match tt.get(6) {
+                    None => Ok(Topic::Metrics(payload_format)),
+                    Some(&"accepted") => Ok(Topic::MetricsAccepted(payload_format)),
+                    Some(&"rejected") => Ok(Topic::MetricsRejected(payload_format)),
+                    _ => Err(()),
+                }
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl Topic {
+    const PREFIX: &'static str = "$aws/things";
+
+    pub fn check(s: &str) -> bool {
+        s.starts_with(Self::PREFIX) && s.contains("/defender/metrics/")
+    }
+
+    pub fn direction(&self) -> Direction {
+        if matches!(self, Topic::Metrics(_)) {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+
+    pub fn format<const L: usize>(&self, client_id: &str) -> Result<String<L>, Error> {
+        let mut topic_path = String::new();
+        match self {
+            Self::Metrics(payload_format) => topic_path.write_fmt(format_args!(
+                "{}/{}/defender/metrics/{}",
+                Self::PREFIX,
+                client_id,
+                payload_format,
+            )),
+            Self::MetricsAccepted(payload_format) => topic_path.write_fmt(format_args!(
+                "{}/{}/defender/metrics/{}/accepted",
+                Self::PREFIX,
+                client_id,
+                payload_format,
+            )),
+            Self::MetricsRejected(payload_format) => topic_path.write_fmt(format_args!(
+                "{}/{}/defender/metrics/{}/rejected",
+                Self::PREFIX,
+                client_id,
+                payload_format,
+            )),
+        }
+        .map_err(|_| Error::Overflow)?;
+
+        Ok(topic_path)
+    }
+}
+
+#[derive(Default)]
+pub struct Subscribe<const N: usize> {
+    topics: heapless::Vec<(Topic, QoS), N>,
+}
+
+impl<const N: usize> Subscribe<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn topic(self, topic: Topic, qos: QoS) -> Self {
+        // Ignore attempts to subscribe to outgoing topics
+        if topic.direction() != Direction::Incoming {
+            return self;
+        }
+
+        if self.topics.iter().any(|(t, _)| t == &topic) {
+            return self;
+        }
+
+        let mut topics = self.topics;
+        topics.push((topic, qos)).ok();
+
+        Self { topics }
+    }
+
+    pub fn topics(
+        self,
+        client_id: &str,
+    ) -> Result<heapless::Vec<(heapless::String<MAX_TOPIC_LEN>, QoS), N>, Error> {
+        self.topics
+            .iter()
+            .map(|(topic, qos)| Ok((topic.format(client_id)?, *qos)))
+            .collect()
+    }
+
+    pub fn send<M: Mqtt>(self, mqtt: &M) -> Result<(), Error> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(mqtt.client_id())?;
+
+        debug!("Subscribing! {:?}", topic_paths);
+
+        let topics: heapless::Vec<_, N> = topic_paths
+            .iter()
+            .map(|(s, qos)| SubscribeTopic {
+                topic_path: s.as_str(),
+                qos: *qos,
+            })
+            .collect();
+
+        for t in topics.chunks(5) {
+            mqtt.subscribe(t)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Unsubscribe<const N: usize> {
+    topics: heapless::Vec<Topic, N>,
+}
+
+impl<const N: usize> Unsubscribe<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn topic(self, topic: Topic) -> Self {
+        // Ignore attempts to unsubscribe from outgoing topics
+        if topic.direction() != Direction::Incoming {
+            return self;
+        }
+
+        if self.topics.iter().any(|t| t == &topic) {
+            return self;
+        }
+
+        let mut topics = self.topics;
+        topics.push(topic).ok();
+        Self { topics }
+    }
+
+    pub fn topics(
+        self,
+        client_id: &str,
+    ) -> Result<heapless::Vec<heapless::String<MAX_TOPIC_LEN>, N>, Error> {
+        self.topics
+            .iter()
+            .map(|topic| topic.format(client_id))
+            .collect()
+    }
+
+    pub fn send<M: Mqtt>(self, mqtt: &M) -> Result<(), Error> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(mqtt.client_id())?;
+        let topics: heapless::Vec<_, N> = topic_paths.iter().map(|s| s.as_str()).collect();
+
+        for t in topics.chunks(5) {
+            mqtt.unsubscribe(t)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrust::{encoding::v4::decode_slice, Packet, QoS, SubscribeTopic};
+
+    use super::*;
+    use crate::test::MockMqtt;
+
+    #[test]
+    fn topic_roundtrip() {
+        let topics = [
+            Topic::Metrics(PayloadFormat::Json),
+            Topic::MetricsAccepted(PayloadFormat::Json),
+            Topic::MetricsRejected(PayloadFormat::Json),
+            #[cfg(feature = "defender_cbor")]
+            Topic::MetricsAccepted(PayloadFormat::Cbor),
+        ];
+
+        for topic in topics {
+            let path = topic.format::<MAX_TOPIC_LEN>("test_client").unwrap();
+            assert!(Topic::check(path.as_str()));
+            assert_eq!(Topic::from_str(path.as_str()), Ok(topic));
+        }
+
+        assert_eq!(
+            Topic::MetricsRejected(PayloadFormat::Json)
+                .format::<MAX_TOPIC_LEN>("test_client")
+                .unwrap()
+                .as_str(),
+            "$aws/things/test_client/defender/metrics/json/rejected"
+        );
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/jobs/notify-next"),
+            Err(())
+        );
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/defender/metrics/xml/accepted"),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn subscribe_ignores_outgoing() {
+        let mqtt = &MockMqtt::new();
+
+        Subscribe::<3>::new()
+            .topic(Topic::Metrics(PayloadFormat::Json), QoS::AtLeastOnce)
+            .topic(
+                Topic::MetricsAccepted(PayloadFormat::Json),
+                QoS::AtLeastOnce,
+            )
+            .topic(
+                Topic::MetricsRejected(PayloadFormat::Json),
+                QoS::AtLeastOnce,
+            )
+            .send(mqtt)
+            .unwrap();
+
+        assert_eq!(mqtt.tx.borrow_mut().len(), 1);
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        let packet = decode_slice(bytes.as_slice()).unwrap();
+
+        let topics = match packet {
+            Some(Packet::Subscribe(ref s)) => s.topics().collect::<Vec<_>>(),
+            _ => panic!(),
+        };
+
+        assert_eq!(
+            topics,
+            vec![
+                SubscribeTopic {
+                    topic_path: "$aws/things/test_client/defender/metrics/json/accepted",
+                    qos: QoS::AtLeastOnce
+                },
+                SubscribeTopic {
+                    topic_path: "$aws/things/test_client/defender/metrics/json/rejected",
+                    qos: QoS::AtLeastOnce
+                },
+            ]
+        );
+    }
+}
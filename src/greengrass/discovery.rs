@@ -0,0 +1,287 @@
+//! Greengrass cloud discovery.
+//!
+//! A client device calls `GET /greengrass/discover/thing/{thingName}` on the
+//! Greengrass data plane endpoint (`greengrass-ats.iot.{region}.amazonaws.com`,
+//! port 8443), authenticating with its device certificate. The response lists,
+//! for each group the device is associated with, the core devices with their
+//! connectivity information and the CA certificates to trust when connecting to
+//! them.
+//!
+//! The response is parsed in place, so all strings borrow from the buffer
+//! passed to [`Discover::send`]. Note that the CA certificates are kept
+//! JSON-escaped, use [`unescape`] to get the PEM encoded certificate.
+use core::fmt::Write;
+
+use serde::Deserialize;
+
+use crate::http::{self, HttpTransport, Request};
+use crate::jobs::MAX_THING_NAME_LEN;
+
+/// Port of the Greengrass discovery endpoint when using mutual TLS
+/// authentication without ALPN.
+pub const DEFAULT_PORT: u16 = 8443;
+
+pub const MAX_PATH_LEN: usize = MAX_THING_NAME_LEN + 27;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DiscoverResponse<
+    'a,
+    const GROUPS: usize = 1,
+    const CORES: usize = 1,
+    const CONNECTIVITY: usize = 4,
+    const CAS: usize = 1,
+> {
+    /// The groups the client device is associated with.
+    #[serde(rename = "GGGroups", borrow)]
+    pub groups: heapless::Vec<GroupInfo<'a, CORES, CONNECTIVITY, CAS>, GROUPS>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GroupInfo<'a, const CORES: usize, const CONNECTIVITY: usize, const CAS: usize> {
+    /// The ID of the group, eg. `greengrassV2-coreDevice-MyGreengrassCore`.
+    #[serde(rename = "GGGroupId")]
+    pub group_id: &'a str,
+
+    /// The core devices of the group.
+    #[serde(rename = "Cores", borrow)]
+    pub cores: heapless::Vec<CoreInfo<'a, CONNECTIVITY>, CORES>,
+
+    /// The JSON-escaped PEM encoded CA certificates of the group.
+    #[serde(rename = "CAs", borrow)]
+    pub cas: heapless::Vec<&'a str, CAS>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoreInfo<'a, const CONNECTIVITY: usize> {
+    /// The ARN of the core device thing.
+    #[serde(rename = "thingArn")]
+    pub thing_arn: &'a str,
+
+    /// The endpoints the core device is reachable at.
+    #[serde(rename = "Connectivity", borrow)]
+    pub connectivity: heapless::Vec<ConnectivityInfo<'a>, CONNECTIVITY>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectivityInfo<'a> {
+    /// The ID of the connectivity information.
+    #[serde(rename = "Id")]
+    pub id: &'a str,
+
+    /// The IP address or DNS name of the core device.
+    #[serde(rename = "HostAddress")]
+    pub host_address: &'a str,
+
+    /// The MQTT port of the core device.
+    #[serde(rename = "PortNumber")]
+    pub port_number: u16,
+
+    /// Additional information about the endpoint.
+    #[serde(rename = "Metadata")]
+    #[serde(default)]
+    pub metadata: Option<&'a str>,
+}
+
+/// Builder for a discovery request.
+pub struct Discover<'a> {
+    host: &'a str,
+    port: u16,
+    thing_name: &'a str,
+}
+
+impl<'a> Discover<'a> {
+    /// Discover the core devices `thing_name` can connect to, using the
+    /// Greengrass data plane endpoint `host`.
+    pub fn new(host: &'a str, thing_name: &'a str) -> Self {
+        Self {
+            host,
+            port: DEFAULT_PORT,
+            thing_name,
+        }
+    }
+
+    /// Use a different port, eg. 443 when the transport negotiates the
+    /// `x-amzn-http-ca` ALPN protocol.
+    pub fn port(self, port: u16) -> Self {
+        Self { port, ..self }
+    }
+
+    pub fn path(&self) -> Result<heapless::String<MAX_PATH_LEN>, http::Error<()>> {
+        let mut path = heapless::String::new();
+        path.write_fmt(format_args!(
+            "/greengrass/discover/thing/{}",
+            self.thing_name
+        ))
+        .map_err(|_| http::Error::Overflow)?;
+        Ok(path)
+    }
+
+    /// Send the discovery request, using `buf` for both the request and the
+    /// response.
+    pub fn send<
+        'b,
+        T: HttpTransport,
+        const GROUPS: usize,
+        const CORES: usize,
+        const CONNECTIVITY: usize,
+        const CAS: usize,
+    >(
+        &self,
+        transport: &mut T,
+        buf: &'b mut [u8],
+    ) -> Result<DiscoverResponse<'b, GROUPS, CORES, CONNECTIVITY, CAS>, http::Error<T::Error>> {
+        let path = self.path().map_err(|_| http::Error::Overflow)?;
+
+        let body = Request {
+            host: self.host,
+            port: self.port,
+            path: path.as_str(),
+            headers: &[],
+        }
+        .send(transport, buf)?;
+
+        let (response, _) =
+            serde_json_core::from_slice(body).map_err(|_| http::Error::InvalidPayload)?;
+
+        Ok(response)
+    }
+}
+
+/// Unescape a JSON string, such as a CA certificate of [`GroupInfo::cas`],
+/// into `buf`.
+///
+/// Returns `None` if `buf` is too small or `s` contains an escape sequence
+/// for a non-ASCII character.
+pub fn unescape<'b>(s: &str, buf: &'b mut [u8]) -> Option<&'b str> {
+    let mut len = 0;
+    let mut chars = s.bytes();
+
+    while let Some(c) = chars.next() {
+        let c = if c == b'\\' {
+            match chars.next()? {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'b' => 0x08,
+                b'f' => 0x0c,
+                b'u' => {
+                    let mut code = 0u32;
+                    for _ in 0..4 {
+                        code = code * 16 + char::from(chars.next()?).to_digit(16)?;
+                    }
+                    u8::try_from(code).ok().filter(u8::is_ascii)?
+                }
+                c => c,
+            }
+        } else {
+            c
+        };
+
+        *buf.get_mut(len)? = c;
+        len += 1;
+    }
+
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 347\r\n\r\n{\"GGGroups\":[{\"GGGroupId\":\"greengrassV2-coreDevice-MyGreengrassCore\",\"Cores\":[{\"thingArn\":\"arn:aws:iot:us-west-2:123456789012:thing/MyGreengrassCore\",\"Connectivity\":[{\"Id\":\"AUTOIP_192.168.1.4_1\",\"HostAddress\":\"192.168.1.5\",\"PortNumber\":8883,\"Metadata\":\"\"}]}],\"CAs\":[\"-----BEGIN CERTIFICATE-----\\nMIICiT...EXAMPLE=\\n-----END CERTIFICATE-----\\n\"]}]}";
+
+    struct FakeTransport {
+        request: Vec<u8>,
+        response: &'static [u8],
+    }
+
+    impl HttpTransport for FakeTransport {
+        type Error = ();
+
+        fn request(
+            &mut self,
+            host: &str,
+            port: u16,
+            request: &[u8],
+            response: &mut [u8],
+        ) -> Result<usize, Self::Error> {
+            assert_eq!(host, "greengrass-ats.iot.us-west-2.amazonaws.com");
+            assert_eq!(port, DEFAULT_PORT);
+            self.request = request.to_vec();
+            response[..self.response.len()].copy_from_slice(self.response);
+            Ok(self.response.len())
+        }
+    }
+
+    #[test]
+    fn discover() {
+        let transport = &mut FakeTransport {
+            request: Vec::new(),
+            response: RESPONSE,
+        };
+        let buf = &mut [0u8; 1024];
+
+        let response: DiscoverResponse =
+            Discover::new("greengrass-ats.iot.us-west-2.amazonaws.com", "test_client")
+                .send(transport, buf)
+                .unwrap();
+
+        assert!(transport
+            .request
+            .starts_with(b"GET /greengrass/discover/thing/test_client HTTP/1.1\r\n"));
+
+        let group = &response.groups[0];
+        assert_eq!(group.group_id, "greengrassV2-coreDevice-MyGreengrassCore");
+        assert_eq!(
+            group.cores[0].connectivity[0],
+            ConnectivityInfo {
+                id: "AUTOIP_192.168.1.4_1",
+                host_address: "192.168.1.5",
+                port_number: 8883,
+                metadata: Some(""),
+            }
+        );
+
+        let pem = unescape(group.cas[0], &mut [0u8; 128]).map(heapless::String::<128>::from);
+        assert_eq!(
+            pem.as_deref(),
+            Some("-----BEGIN CERTIFICATE-----\nMIICiT...EXAMPLE=\n-----END CERTIFICATE-----\n")
+        );
+    }
+
+    #[test]
+    fn discover_errors() {
+        let buf = &mut [0u8; 1024];
+        let transport = &mut FakeTransport {
+            request: Vec::new(),
+            response: b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        };
+        let response: Result<DiscoverResponse, _> =
+            Discover::new("greengrass-ats.iot.us-west-2.amazonaws.com", "test_client")
+                .send(transport, buf);
+        assert_eq!(response, Err(http::Error::Status(404)));
+
+        let buf = &mut [0u8; 1024];
+
+        let transport = &mut FakeTransport {
+            request: Vec::new(),
+            response: b"HTTP/1.1 200 OK\r\n\r\n{\"GGGroups\":",
+        };
+        let response: Result<DiscoverResponse, _> =
+            Discover::new("greengrass-ats.iot.us-west-2.amazonaws.com", "test_client")
+                .send(transport, buf);
+        assert_eq!(response, Err(http::Error::InvalidPayload));
+    }
+
+    #[test]
+    fn unescape_strings() {
+        let buf = &mut [0u8; 16];
+        assert_eq!(unescape(r#"a\"b\\c\/A"#, buf), Some("a\"b\\c/A"));
+        assert_eq!(unescape(r#"\u0041\u00e9"#, buf), None);
+        assert_eq!(unescape("too long for the buffer", buf), None);
+    }
+}
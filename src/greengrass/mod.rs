@@ -0,0 +1,8 @@
+//! # AWS IoT Greengrass
+//!
+//! Client devices locate the Greengrass core devices they can connect to
+//! through the Greengrass cloud discovery API, served over HTTPS by the AWS IoT
+//! data plane. See [`discovery`].
+//!
+//! <https://docs.aws.amazon.com/greengrass/v2/developerguide/greengrass-discover-api.html>
+pub mod discovery;
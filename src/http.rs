@@ -0,0 +1,223 @@
+//! Minimal HTTP/1.1 support for the AWS IoT HTTPS endpoints, such as
+//! Greengrass discovery and the credential provider.
+//!
+//! The actual connection, including the mutual TLS authentication using the
+//! device certificate, is left to the application through [`HttpTransport`].
+
+use core::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// The transport failed to exchange the request.
+    Transport(E),
+    /// The request or the response did not fit in the provided buffer.
+    Overflow,
+    /// The response is not a valid HTTP/1.1 response.
+    InvalidResponse,
+    /// The server responded with a non-success status code.
+    Status(u16),
+    /// The response body could not be parsed.
+    InvalidPayload,
+}
+
+/// A blocking HTTP transport, typically a TLS connection authenticated with
+/// the device certificate.
+pub trait HttpTransport {
+    type Error;
+
+    /// Connect to `host:port`, send `request` and read the full response into
+    /// `response`, returning the number of bytes read.
+    ///
+    /// Requests are always sent with `Connection: close`, so the response is
+    /// complete once the server closes the connection.
+    fn request(
+        &mut self,
+        host: &str,
+        port: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Self::Error>;
+}
+
+/// A `GET` request with a fixed set of headers.
+pub struct Request<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+}
+
+impl<'a> Request<'a> {
+    /// Write the request into `buf`, returning the number of bytes written.
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, Error<()>> {
+        let mut w = SliceWriter { buf, pos: 0 };
+
+        write!(
+            w,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+            self.path, self.host
+        )
+        .map_err(|_| Error::Overflow)?;
+        for (name, value) in self.headers {
+            write!(w, "{}: {}\r\n", name, value).map_err(|_| Error::Overflow)?;
+        }
+        w.write_str("\r\n").map_err(|_| Error::Overflow)?;
+
+        Ok(w.pos)
+    }
+
+    /// Send the request using `buf` for both the request and the response,
+    /// returning the body of a successful response.
+    pub fn send<'b, T: HttpTransport>(
+        &self,
+        transport: &mut T,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], Error<T::Error>> {
+        let (request, response) = buf.split_at_mut(buf.len() / 2);
+        let len = self.write(request).map_err(|_| Error::Overflow)?;
+
+        debug!("GET {}{}", self.host, self.path);
+
+        let len = transport
+            .request(self.host, self.port, &request[..len], response)
+            .map_err(Error::Transport)?;
+
+        let response = Response::parse(&response[..len])?;
+        if !(200..300).contains(&response.status) {
+            return Err(Error::Status(response.status));
+        }
+
+        Ok(response.body)
+    }
+}
+
+/// A parsed HTTP/1.1 response.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response<'a> {
+    pub status: u16,
+    pub body: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    pub fn parse<E>(buf: &'a [u8]) -> Result<Self, Error<E>> {
+        let header_end = buf
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(Error::InvalidResponse)?;
+        let head = core::str::from_utf8(&buf[..header_end]).map_err(|_| Error::InvalidResponse)?;
+        let body = &buf[header_end + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|status_line| {
+                let mut parts = status_line.splitn(3, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+                        code.parse().ok()
+                    }
+                    _ => None,
+                }
+            })
+            .ok_or(Error::InvalidResponse)?;
+
+        let mut content_length = None;
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(Error::InvalidResponse)?;
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse().map_err(|_| Error::InvalidResponse)?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding")
+                && !value.eq_ignore_ascii_case("identity")
+            {
+                // Chunked responses are not supported
+                return Err(Error::InvalidResponse);
+            }
+        }
+
+        let body = match content_length {
+            Some(len) => body.get(..len).ok_or(Error::Overflow)?,
+            None => body,
+        };
+
+        Ok(Self { status, body })
+    }
+}
+
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.buf
+            .get_mut(self.pos..self.pos + s.len())
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.pos += s.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_request() {
+        let buf = &mut [0u8; 128];
+        let len = Request {
+            host: "example.com",
+            port: 443,
+            path: "/some/path",
+            headers: &[("x-custom", "value")],
+        }
+        .write(buf)
+        .unwrap();
+
+        assert_eq!(
+            &buf[..len],
+            b"GET /some/path HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\nx-custom: value\r\n\r\n"
+        );
+
+        assert_eq!(
+            Request {
+                host: "example.com",
+                port: 443,
+                path: "/some/path",
+                headers: &[],
+            }
+            .write(&mut [0u8; 16]),
+            Err(Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn parse_response() {
+        let response = Response::parse::<()>(
+            b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\nContent-Length: 2\r\n\r\n{}trailing",
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"{}");
+
+        let response = Response::parse::<()>(b"HTTP/1.0 404 Not Found\r\n\r\nmissing").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"missing");
+
+        assert_eq!(
+            Response::parse::<()>(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{}"),
+            Err(Error::Overflow)
+        );
+        assert_eq!(
+            Response::parse::<()>(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(Error::InvalidResponse)
+        );
+        assert_eq!(
+            Response::parse::<()>(b"garbage\r\n\r\n"),
+            Err(Error::InvalidResponse)
+        );
+    }
+}
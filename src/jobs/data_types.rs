@@ -289,9 +289,10 @@ pub struct Jobs {
 /// service operation.
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct ErrorResponse<'a> {
-    code: ErrorCode,
+    /// An error code indicating why the request was rejected.
+    pub code: ErrorCode,
     /// An error message string.
-    message: &'a str,
+    pub message: &'a str,
     /// A client token used to correlate requests and responses. Enter an
     /// arbitrary value here and it is reflected in the response.
     #[serde(rename = "clientToken")]
@@ -121,8 +121,39 @@ pub const MAX_STREAM_ID_LEN: usize = MAX_JOB_ID_LEN;
 pub const MAX_PENDING_JOBS: usize = 1;
 pub const MAX_RUNNING_JOBS: usize = 1;
 
-pub type StatusDetails<'a> = heapless::LinearMap<&'a str, &'a str, 4>;
-pub type StatusDetailsOwned = heapless::LinearMap<heapless::String<15>, heapless::String<11>, 4>;
+/// Default maximum length of a status details key.
+pub const MAX_STATUS_DETAIL_KEY_LEN: usize = 24;
+/// Default maximum length of a status details value.
+pub const MAX_STATUS_DETAIL_VALUE_LEN: usize = 16;
+/// Default maximum number of status details, leaving room for the ones set by
+/// the OTA agent.
+pub const MAX_STATUS_DETAILS: usize = 6;
+
+/// Default maximum length of an update request payload, fitting a full map of
+/// status details of the default sizes.
+pub const MAX_UPDATE_PAYLOAD_LEN: usize = update_payload_len(
+    MAX_STATUS_DETAIL_KEY_LEN,
+    MAX_STATUS_DETAIL_VALUE_LEN,
+    MAX_STATUS_DETAILS,
+);
+
+/// Maximum length of an update request payload with every field set, and `n`
+/// status details with keys of at most `k` bytes and values of at most `v`
+/// bytes, none of them needing escapes in JSON.
+pub const fn update_payload_len(k: usize, v: usize, n: usize) -> usize {
+    // Every other field, with numbers of 20 characters
+    238 + MAX_CLIENT_TOKEN_LEN + 2 + n * (k + v + 6)
+}
+
+pub type StatusDetails<'a> = heapless::LinearMap<&'a str, &'a str, MAX_STATUS_DETAILS>;
+
+/// Owned status details, holding at most `N` entries with keys of at most `K`
+/// bytes and values of at most `V` bytes.
+pub type StatusDetailsOwned<
+    const K: usize = MAX_STATUS_DETAIL_KEY_LEN,
+    const V: usize = MAX_STATUS_DETAIL_VALUE_LEN,
+    const N: usize = MAX_STATUS_DETAILS,
+> = heapless::LinearMap<heapless::String<K>, heapless::String<V>, N>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JobError {
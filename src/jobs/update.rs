@@ -5,7 +5,10 @@ use crate::jobs::{
     data_types::JobStatus, JobTopic, MAX_CLIENT_TOKEN_LEN, MAX_JOB_ID_LEN, MAX_THING_NAME_LEN,
 };
 
-use super::{JobError, StatusDetailsOwned};
+use super::{
+    update_payload_len, JobError, StatusDetailsOwned, MAX_STATUS_DETAILS,
+    MAX_STATUS_DETAIL_KEY_LEN, MAX_STATUS_DETAIL_VALUE_LEN, MAX_UPDATE_PAYLOAD_LEN,
+};
 
 /// Updates the status of a job execution. You can optionally create a step
 /// timer by setting a value for the stepTimeoutInMinutes property. If you don't
@@ -14,7 +17,12 @@ use super::{JobError, StatusDetailsOwned};
 ///
 /// Topic: $aws/things/{thingName}/jobs/{jobId}/update
 #[derive(Debug, PartialEq, Serialize)]
-pub struct UpdateJobExecutionRequest<'a> {
+pub struct UpdateJobExecutionRequest<
+    'a,
+    const K: usize = MAX_STATUS_DETAIL_KEY_LEN,
+    const V: usize = MAX_STATUS_DETAIL_VALUE_LEN,
+    const N: usize = MAX_STATUS_DETAILS,
+> {
     /// Optional. A number that identifies a particular job execution on a
     /// particular device.
     #[serde(rename = "executionNumber")]
@@ -48,7 +56,7 @@ pub struct UpdateJobExecutionRequest<'a> {
     // the job execution. If not specified, the statusDetails are unchanged.
     #[serde(rename = "statusDetails")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub status_details: Option<&'a StatusDetailsOwned>,
+    pub status_details: Option<&'a StatusDetailsOwned<K, V, N>>,
     // Specifies the amount of time this device has to finish execution of this
     // job. If the job execution status is not set to a terminal state before
     // this timer expires, or before the timer is reset (by again calling
@@ -69,11 +77,20 @@ pub struct UpdateJobExecutionRequest<'a> {
     pub client_token: Option<&'a str>,
 }
 
-pub struct Update<'a> {
+/// Update of a job execution, with status details of at most `N` entries with
+/// keys of at most `K` bytes and values of at most `V` bytes, encoded in a
+/// payload of at most `P` bytes.
+pub struct Update<
+    'a,
+    const K: usize = MAX_STATUS_DETAIL_KEY_LEN,
+    const V: usize = MAX_STATUS_DETAIL_VALUE_LEN,
+    const N: usize = MAX_STATUS_DETAILS,
+    const P: usize = MAX_UPDATE_PAYLOAD_LEN,
+> {
     job_id: &'a str,
     status: JobStatus,
     client_token: Option<&'a str>,
-    status_details: Option<&'a StatusDetailsOwned>,
+    status_details: Option<&'a StatusDetailsOwned<K, V, N>>,
     include_job_document: bool,
     execution_number: Option<i64>,
     include_job_execution_state: bool,
@@ -97,6 +114,15 @@ impl<'a> Update<'a> {
             step_timeout_in_minutes: None,
         }
     }
+}
+
+impl<'a, const K: usize, const V: usize, const N: usize, const P: usize> Update<'a, K, V, N, P> {
+    /// Fails the build if a full map of status details doesn't fit the
+    /// payload.
+    const PAYLOAD_FITS: () = assert!(
+        P >= update_payload_len(K, V, N),
+        "The payload length can't fit the status details"
+    );
 
     pub fn client_token(self, client_token: &'a str) -> Self {
         assert!(client_token.len() < MAX_CLIENT_TOKEN_LEN);
@@ -107,10 +133,38 @@ impl<'a> Update<'a> {
         }
     }
 
-    pub fn status_details(self, status_details: &'a StatusDetailsOwned) -> Self {
-        Self {
+    /// Status details of the job execution, with any capacity and string
+    /// sizes.
+    pub fn status_details<const K2: usize, const V2: usize, const N2: usize>(
+        self,
+        status_details: &'a StatusDetailsOwned<K2, V2, N2>,
+    ) -> Update<'a, K2, V2, N2, P> {
+        Update {
+            job_id: self.job_id,
+            status: self.status,
+            client_token: self.client_token,
             status_details: Some(status_details),
-            ..self
+            include_job_document: self.include_job_document,
+            execution_number: self.execution_number,
+            include_job_execution_state: self.include_job_execution_state,
+            expected_version: self.expected_version,
+            step_timeout_in_minutes: self.step_timeout_in_minutes,
+        }
+    }
+
+    /// Encode the update in a payload of at most `P2` bytes, eg. to fit
+    /// larger status details, see [`update_payload_len`].
+    pub fn payload_len<const P2: usize>(self) -> Update<'a, K, V, N, P2> {
+        Update {
+            job_id: self.job_id,
+            status: self.status,
+            client_token: self.client_token,
+            status_details: self.status_details,
+            include_job_document: self.include_job_document,
+            execution_number: self.execution_number,
+            include_job_execution_state: self.include_job_execution_state,
+            expected_version: self.expected_version,
+            step_timeout_in_minutes: self.step_timeout_in_minutes,
         }
     }
 
@@ -155,11 +209,14 @@ impl<'a> Update<'a> {
     ) -> Result<
         (
             heapless::String<{ MAX_THING_NAME_LEN + MAX_JOB_ID_LEN + 25 }>,
-            heapless::Vec<u8, 512>,
+            heapless::Vec<u8, P>,
         ),
         JobError,
     > {
-        let payload = serde_json_core::to_vec(&UpdateJobExecutionRequest {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::PAYLOAD_FITS;
+
+        let payload = serde_json_core::to_vec(&UpdateJobExecutionRequest::<K, V, N> {
             execution_number: self.execution_number,
             include_job_document: self.include_job_document.then(|| true),
             expected_version: self.expected_version,
@@ -186,11 +243,12 @@ impl<'a> Update<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use core::fmt::Write;
     use serde_json_core::to_string;
 
     #[test]
     fn serialize_requests() {
-        let req = UpdateJobExecutionRequest {
+        let req: UpdateJobExecutionRequest = UpdateJobExecutionRequest {
             client_token: Some("test_client:token_update"),
             step_timeout_in_minutes: Some(50),
             execution_number: Some(5),
@@ -225,4 +283,71 @@ mod test {
             "$aws/things/test_client/jobs/test_job_id/update"
         );
     }
+
+    #[test]
+    fn topic_payload_custom_status_details() {
+        let mut status_details: StatusDetailsOwned<32, 8, 1> = StatusDetailsOwned::new();
+        status_details
+            .insert(
+                heapless::String::from("bootloader_version"),
+                heapless::String::from("2.1.0"),
+            )
+            .unwrap();
+
+        let (_, payload) = Update::new("test_job_id", JobStatus::InProgress)
+            .status_details(&status_details)
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert_eq!(
+            payload,
+            br#"{"status":"IN_PROGRESS","statusDetails":{"bootloader_version":"2.1.0"}}"#
+        );
+    }
+
+    fn full_status_details<const K: usize, const V: usize, const N: usize>(
+    ) -> StatusDetailsOwned<K, V, N> {
+        let mut status_details = StatusDetailsOwned::new();
+        for i in 0..N {
+            let mut key = heapless::String::new();
+            let mut value = heapless::String::new();
+            write!(key, "{:0>1$}", i, K).unwrap();
+            write!(value, "{:0>1$}", i, V).unwrap();
+            status_details.insert(key, value).unwrap();
+        }
+        status_details
+    }
+
+    #[test]
+    fn topic_payload_full_status_details() {
+        let client_token = "c".repeat(MAX_CLIENT_TOKEN_LEN - 1);
+        let status_details: StatusDetailsOwned = full_status_details();
+
+        let (_, payload) = Update::new("test_job_id", JobStatus::InProgress)
+            .client_token(&client_token)
+            .step_timeout_in_minutes(i64::MIN)
+            .execution_number(i64::MIN)
+            .expected_version(i64::MIN)
+            .include_job_document()
+            .include_job_execution_state()
+            .status_details(&status_details)
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(payload.capacity(), MAX_UPDATE_PAYLOAD_LEN);
+
+        let status_details = full_status_details::<32, 64, 12>();
+
+        let (_, payload) = Update::new("test_job_id", JobStatus::InProgress)
+            .client_token(&client_token)
+            .step_timeout_in_minutes(i64::MIN)
+            .execution_number(i64::MIN)
+            .expected_version(i64::MIN)
+            .include_job_document()
+            .include_job_execution_state()
+            .status_details(&status_details)
+            .payload_len::<{ update_payload_len(32, 64, 12) }>()
+            .topic_payload("test_client")
+            .unwrap();
+        assert!(payload.len() > 1024);
+    }
 }
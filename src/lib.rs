@@ -5,11 +5,18 @@
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+pub mod credentials;
+pub mod defender;
+pub mod greengrass;
+pub mod http;
 pub mod jobs;
 #[cfg(any(feature = "ota_mqtt_data", feature = "ota_http_data"))]
 pub mod ota;
 pub mod provisioning;
 pub mod shadows;
+#[cfg(feature = "ota_mqtt_data")]
+pub mod streams;
+pub mod tunneling;
 
 pub use serde_cbor;
 
@@ -1,30 +1,99 @@
+use core::str::FromStr;
+
+use heapless::String;
+
 use super::{
+    backoff::{NoRng, Rng},
     builder::{self, NoTimer},
     control_interface::ControlInterface,
     data_interface::{DataInterface, NoInterface},
-    encoding::json::OtaJob,
+    encoding::JobDocument,
+    error::OtaError,
+    health::{HealthCheck, NoHealthCheck},
+    observer::{NoObserver, OtaObserver, OtaObserverEvent},
     pal::OtaPal,
+    policy::{AllowAll, UpdatePolicy},
     state::{Error, Events, JobEventData, SmContext, StateMachine, States},
 };
-use crate::jobs::StatusDetails;
+use crate::jobs::{
+    data_types::{ErrorCode, ErrorResponse},
+    StatusDetails, MAX_STATUS_DETAILS, MAX_STATUS_DETAIL_KEY_LEN, MAX_STATUS_DETAIL_VALUE_LEN,
+    MAX_UPDATE_PAYLOAD_LEN,
+};
+
+type AgentStateMachine<
+    'a,
+    C,
+    DP,
+    DS,
+    T,
+    ST,
+    PAL,
+    const TIMER_HZ: u32,
+    OB,
+    UP,
+    HC,
+    R,
+    const K: usize,
+    const V: usize,
+    const N: usize,
+    const P: usize,
+> = StateMachine<SmContext<'a, C, DP, DS, T, ST, PAL, 3, TIMER_HZ, OB, UP, HC, R, K, V, N, P>>;
 
 // OTA Agent driving the FSM of an OTA update
-pub struct OtaAgent<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32>
-where
+pub struct OtaAgent<
+    'a,
+    C,
+    DP,
+    DS,
+    T,
+    ST,
+    PAL,
+    const TIMER_HZ: u32,
+    OB = NoObserver,
+    UP = AllowAll,
+    HC = NoHealthCheck,
+    R = NoRng,
+    const K: usize = MAX_STATUS_DETAIL_KEY_LEN,
+    const V: usize = MAX_STATUS_DETAIL_VALUE_LEN,
+    const N: usize = MAX_STATUS_DETAILS,
+    const P: usize = MAX_UPDATE_PAYLOAD_LEN,
+> where
     C: ControlInterface,
     DP: DataInterface,
     DS: DataInterface,
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
-    pub(crate) state: StateMachine<SmContext<'a, C, DP, DS, T, ST, PAL, 3, TIMER_HZ>>,
+    pub(crate) state:
+        AgentStateMachine<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>,
 }
 
 // Make sure any active OTA session is cleaned up, and the topics are
 // unsubscribed on drop.
-impl<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32> Drop
-    for OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ>
+impl<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    > Drop for OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
 where
     C: ControlInterface,
     DP: DataInterface,
@@ -32,6 +101,10 @@ where
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     fn drop(&mut self) {
         let sm_context = self.state.context_mut();
@@ -59,7 +132,24 @@ where
 }
 
 /// Public interface of the OTA Agent
-impl<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32> OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ>
+impl<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    > OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
 where
     C: ControlInterface,
     DP: DataInterface,
@@ -67,57 +157,88 @@ where
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     pub fn init(&mut self) {
         if matches!(self.state(), &States::Ready) {
-            self.state.process_event(Events::Start).ok();
+            self.process_state_event(Events::Start).ok();
         } else {
-            self.state.process_event(Events::Resume).ok();
+            self.process_state_event(Events::Resume).ok();
         }
     }
 
     pub fn job_update(
         &mut self,
         job_name: &str,
-        ota_document: &OtaJob,
+        ota_document: &dyn JobDocument,
         status_details: Option<&StatusDetails>,
     ) -> Result<&States, Error> {
-        self.state
-            .process_event(Events::ReceivedJobDocument(JobEventData {
-                job_name,
-                ota_document,
-                status_details,
-            }))
+        self.process_state_event(Events::ReceivedJobDocument(JobEventData {
+            job_name,
+            ota_document,
+            status_details,
+        }))
     }
 
     pub fn timer_callback(&mut self) -> Result<(), Error> {
         let ctx = self.state.context_mut();
         if ctx.request_timer.wait().is_ok() {
-            return self.state.process_event(Events::RequestTimer).map(drop);
+            return self.process_state_event(Events::RequestTimer).map(drop);
         }
 
-        if let Some(ref mut self_test_timer) = ctx.self_test_timer {
-            if self_test_timer.wait().is_ok() {
-                error!(
-                    "Self test failed to complete within {} ms",
-                    ctx.config.self_test_timeout_ms
-                );
-                ctx.pal.reset_device().ok();
+        let self_test_expired = ctx
+            .self_test_timer
+            .as_mut()
+            .map_or(false, |timer| timer.wait().is_ok());
+
+        if self_test_expired {
+            if matches!(self.state(), States::SelfTesting) {
+                // Reject the image that failed to pass its health checks in time
+                return self.process_state_event(Events::SelfTestTimeout).map(drop);
             }
+
+            let ctx = self.state.context_mut();
+            error!(
+                "Self test failed to complete within {} ms",
+                ctx.config.self_test_timeout_ms
+            );
+            ctx.pal.reset_device().ok();
         }
         Ok(())
     }
 
     pub fn process_event(&mut self) -> Result<&States, Error> {
         if let Some(event) = self.state.context_mut().events.dequeue() {
-            self.state.process_event(event)
+            self.process_state_event(event)
         } else {
             Ok(self.state())
         }
     }
 
     pub fn handle_message(&mut self, payload: &mut [u8]) -> Result<&States, Error> {
-        self.state.process_event(Events::ReceivedFileBlock(payload))
+        self.process_state_event(Events::ReceivedFileBlock(payload))
+    }
+
+    /// Handle a stream description received on the
+    /// `$aws/things/{thingName}/streams/{streamId}/description/cbor` topic,
+    /// when the agent is built with `describe_stream`.
+    pub fn handle_stream_description(&mut self, payload: &mut [u8]) -> Result<&States, Error> {
+        self.process_state_event(Events::ReceivedStreamDescription(payload))
+    }
+
+    /// Activate an image waiting in `States::ReadyToActivate`, when the agent
+    /// is built with `manual_activation`.
+    pub fn activate(&mut self) -> Result<&States, Error> {
+        self.process_state_event(Events::Activate)
+    }
+
+    /// Activate an image waiting in `States::ReadyToActivate` once `delay_ms`
+    /// has passed, driven by [`Self::timer_callback`].
+    pub fn schedule_activation(&mut self, delay_ms: u32) -> Result<&States, Error> {
+        self.process_state_event(Events::ScheduleActivation(delay_ms))
     }
 
     pub fn check_for_update(&mut self) -> Result<&States, Error> {
@@ -125,14 +246,52 @@ where
             self.state(),
             States::WaitingForJob | States::RequestingJob | States::WaitingForFileBlock
         ) {
-            self.state.process_event(Events::RequestJobDocument)
+            self.process_state_event(Events::RequestJobDocument)
         } else {
             Ok(self.state())
         }
     }
 
+    /// Handle a message on the `notify-next` topic, with the job ID of the next
+    /// pending job execution, if any.
+    ///
+    /// The active job is aborted if it is no longer the next pending job
+    /// execution, eg. because it was canceled.
+    pub fn handle_notify_next(&mut self, next_job: Option<&str>) -> Result<&States, Error> {
+        match self.active_job() {
+            Some(job_name) if Some(job_name) != next_job => {
+                self.process_state_event(Events::JobCanceled)
+            }
+            _ => Ok(self.state()),
+        }
+    }
+
+    /// Handle a message on the
+    /// `$aws/things/{thingName}/jobs/{jobId}/update/rejected` topic.
+    ///
+    /// The active job is aborted if the update was rejected because the job
+    /// execution reached a terminal state, eg. because it was canceled.
+    pub fn handle_update_rejected(
+        &mut self,
+        job_name: &str,
+        error: &ErrorResponse,
+    ) -> Result<&States, Error> {
+        let terminal = matches!(
+            error.code,
+            ErrorCode::TerminalStateReached | ErrorCode::InvalidStateTransition
+        );
+
+        if terminal && self.active_job() == Some(job_name) {
+            self.process_state_event(Events::JobCanceled)
+        } else {
+            Ok(self.state())
+        }
+    }
+
+    /// Abort the active job, reporting it as failed. An image in self test is
+    /// rejected, so the bootloader rolls it back on the next reset.
     pub fn abort(&mut self) -> Result<&States, Error> {
-        self.state.process_event(Events::UserAbort)
+        self.process_state_event(Events::UserAbort)
     }
 
     pub fn suspend(&mut self) -> Result<&States, Error> {
@@ -140,15 +299,64 @@ where
         self.state.context_mut().request_timer.cancel().ok();
 
         // Send event to OTA agent task.
-        self.state.process_event(Events::Suspend)
+        self.process_state_event(Events::Suspend)
     }
 
     pub fn resume(&mut self) -> Result<&States, Error> {
         // Send event to OTA agent task
-        self.state.process_event(Events::Resume)
+        self.process_state_event(Events::Resume)
+    }
+
+    /// Attach a status detail to the status updates of this and any later
+    /// job, eg. a bootloader version or the amount of free flash.
+    pub fn set_status_detail(&mut self, key: &str, value: &str) -> Result<(), OtaError> {
+        let key = String::from_str(key).map_err(|_| OtaError::Overflow)?;
+        let value = String::from_str(value).map_err(|_| OtaError::Overflow)?;
+
+        let ctx = self.state.context_mut();
+        if ctx.active_interface.is_some() {
+            ctx.job_status_details
+                .insert(key.clone(), value.clone())
+                .map_err(|_| OtaError::Overflow)?;
+        }
+        ctx.status_details
+            .insert(key, value)
+            .map_err(|_| OtaError::Overflow)?;
+        Ok(())
+    }
+
+    /// Change the byte rate limit of the download at runtime, eg. from a
+    /// shadow field. Unlimited if `0`.
+    pub fn set_max_bytes_per_second(&mut self, max_bytes_per_second: u32) {
+        self.state.context_mut().config.max_bytes_per_second = max_bytes_per_second;
     }
 
     pub fn state(&self) -> &States {
         self.state.state()
     }
+
+    /// Name of the job being processed, if any
+    fn active_job(&self) -> Option<&str> {
+        self.state
+            .context()
+            .active_interface
+            .as_ref()
+            .map(|i| i.file_ctx().job_name.as_str())
+    }
+
+    /// Process `event`, reporting any resulting state change to the observer
+    fn process_state_event(&mut self, event: Events<'_>) -> Result<&States, Error> {
+        let from = *self.state.state();
+        let result = self.state.process_event(event).map(drop);
+
+        let to = *self.state.state();
+        if from != to {
+            self.state
+                .context_mut()
+                .observer
+                .on_event(&OtaObserverEvent::StateChanged { from, to });
+        }
+
+        result.map(|_| self.state.state())
+    }
 }
@@ -0,0 +1,89 @@
+//! Exponential backoff with jitter of the job and file block requests, so a
+//! fleet of devices reconnecting at once doesn't retry in lockstep.
+//!
+//! The wait before retrying a request starts at `request_wait_ms` and doubles
+//! with every unanswered request, up to `max_request_wait_ms`. A random amount
+//! of up to half the wait is then subtracted from it, using the [`Rng`]
+//! configured with
+//! [`OtaAgentBuilder::with_rng`](super::builder::OtaAgentBuilder::with_rng).
+
+use super::config::Config;
+
+/// Source of randomness for the request jitter, eg. a hardware RNG
+/// peripheral.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Disables the jitter, used unless another [`Rng`] is configured.
+pub struct NoRng;
+
+impl Rng for NoRng {
+    fn next_u32(&mut self) -> u32 {
+        0
+    }
+}
+
+/// Wait in milliseconds before retrying a request, after `attempt` requests
+/// went unanswered.
+pub(crate) fn request_wait_ms<R: Rng>(config: &Config, attempt: u8, rng: &mut R) -> u32 {
+    let max_wait_ms = config.max_request_wait_ms.max(config.request_wait_ms);
+    let wait_ms = config
+        .request_wait_ms
+        .checked_mul(1 << attempt.min(31))
+        .map_or(max_wait_ms, |wait_ms| wait_ms.min(max_wait_ms));
+
+    wait_ms - rng.next_u32() % (wait_ms / 2 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedRng(u32);
+
+    impl Rng for FixedRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn fixed_wait() {
+        let config = Config::default();
+
+        for attempt in 0..5 {
+            assert_eq!(request_wait_ms(&config, attempt, &mut NoRng), 8000);
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let config = Config {
+            request_wait_ms: 1000,
+            max_request_wait_ms: 60_000,
+            ..Config::default()
+        };
+
+        let waits: Vec<u32> = (0..8)
+            .map(|attempt| request_wait_ms(&config, attempt, &mut NoRng))
+            .collect();
+        assert_eq!(waits, [1000, 2000, 4000, 8000, 16000, 32000, 60000, 60000]);
+        assert_eq!(request_wait_ms(&config, u8::MAX, &mut NoRng), 60_000);
+    }
+
+    #[test]
+    fn jitter() {
+        let config = Config {
+            request_wait_ms: 1000,
+            max_request_wait_ms: 60_000,
+            ..Config::default()
+        };
+
+        assert_eq!(request_wait_ms(&config, 0, &mut FixedRng(250)), 750);
+        assert_eq!(request_wait_ms(&config, 0, &mut FixedRng(500)), 500);
+        // The jitter never exceeds half the wait
+        assert_eq!(request_wait_ms(&config, 0, &mut FixedRng(501)), 1000);
+        assert_eq!(request_wait_ms(&config, 7, &mut FixedRng(30_000)), 30_000);
+    }
+}
@@ -1,9 +1,18 @@
+use crate::jobs::{
+    StatusDetailsOwned, MAX_STATUS_DETAILS, MAX_STATUS_DETAIL_KEY_LEN, MAX_STATUS_DETAIL_VALUE_LEN,
+    MAX_UPDATE_PAYLOAD_LEN,
+};
 use crate::ota::{
+    backoff::{NoRng, Rng},
     config::Config,
     control_interface::ControlInterface,
     data_interface::DataInterface,
+    health::{HealthCheck, NoHealthCheck},
+    observer::{NoObserver, OtaObserver},
     pal::OtaPal,
+    policy::{AllowAll, UpdatePolicy},
     state::{SmContext, StateMachine},
+    throttle::Throttle,
 };
 
 use super::{agent::OtaAgent, data_interface::NoInterface, pal::ImageState};
@@ -33,14 +42,34 @@ impl<const TIMER_HZ: u32> fugit_timer::Timer<TIMER_HZ> for NoTimer {
     }
 }
 
-pub struct OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32>
-where
+pub struct OtaAgentBuilder<
+    'a,
+    C,
+    DP,
+    DS,
+    T,
+    ST,
+    PAL,
+    const TIMER_HZ: u32,
+    OB = NoObserver,
+    UP = AllowAll,
+    HC = NoHealthCheck,
+    R = NoRng,
+    const K: usize = MAX_STATUS_DETAIL_KEY_LEN,
+    const V: usize = MAX_STATUS_DETAIL_VALUE_LEN,
+    const N: usize = MAX_STATUS_DETAILS,
+    const P: usize = MAX_UPDATE_PAYLOAD_LEN,
+> where
     C: ControlInterface,
     DP: DataInterface,
     DS: DataInterface,
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     control: &'a C,
     data_primary: DP,
@@ -51,6 +80,10 @@ where
     pal: PAL,
     request_timer: T,
     self_test_timer: Option<ST>,
+    observer: OB,
+    policy: UP,
+    health_check: HC,
+    rng: R,
     config: Config,
 }
 
@@ -73,13 +106,33 @@ where
             pal,
             request_timer,
             self_test_timer: None,
+            observer: NoObserver,
+            policy: AllowAll,
+            health_check: NoHealthCheck,
+            rng: NoRng,
             config: Config::default(),
         }
     }
 }
 
-impl<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32>
-    OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ>
+impl<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    > OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
 where
     C: ControlInterface,
     DP: DataInterface,
@@ -87,12 +140,16 @@ where
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
     pub fn data_secondary<D: DataInterface>(
         self,
         interface: D,
-    ) -> OtaAgentBuilder<'a, C, DP, D, T, ST, PAL, TIMER_HZ> {
+    ) -> OtaAgentBuilder<'a, C, DP, D, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P> {
         OtaAgentBuilder {
             control: self.control,
             data_primary: self.data_primary,
@@ -100,6 +157,10 @@ where
             pal: self.pal,
             request_timer: self.request_timer,
             self_test_timer: self.self_test_timer,
+            observer: self.observer,
+            policy: self.policy,
+            health_check: self.health_check,
+            rng: self.rng,
             config: self.config,
         }
     }
@@ -144,6 +205,35 @@ where
         }
     }
 
+    /// Double the wait before retrying an unanswered request, starting at
+    /// `request_wait_ms` and up to `max_request_wait_ms`.
+    ///
+    /// Disabled unless `max_request_wait_ms` exceeds `request_wait_ms`.
+    pub fn max_request_wait_ms(self, max_request_wait_ms: u32) -> Self {
+        Self {
+            config: Config {
+                max_request_wait_ms,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Limit the download to `max_bytes_per_second`, pacing the file block
+    /// requests with a token bucket refilled from the clock of the request
+    /// timer. Up to a second worth of bytes may be requested at once.
+    ///
+    /// Unlimited if `0`, the default.
+    pub fn max_bytes_per_second(self, max_bytes_per_second: u32) -> Self {
+        Self {
+            config: Config {
+                max_bytes_per_second,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
     pub fn status_update_frequency(self, status_update_frequency: u32) -> Self {
         Self {
             config: Config {
@@ -164,11 +254,54 @@ where
         }
     }
 
+    /// Describe the stream before creating the file, failing the job early if
+    /// the file IDs and sizes of the stream do not match the job document.
+    ///
+    /// Only applies to jobs delivered over MQTT.
+    pub fn describe_stream(self) -> Self {
+        Self {
+            config: Config {
+                describe_stream: true,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Wait in `States::ReadyToActivate` once a firmware image has been
+    /// received, until [`OtaAgent::activate`] is called or the time given to
+    /// [`OtaAgent::schedule_activation`] has passed, instead of activating the
+    /// image right away.
+    pub fn manual_activation(self) -> Self {
+        Self {
+            config: Config {
+                manual_activation: true,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Activate jobs holding a file of `file_type`, eg. the image of a
+    /// component routed by a [`RouterPal`](crate::ota::pal::router::RouterPal),
+    /// in addition to jobs holding a firmware image of file type 0. Jobs
+    /// without such a file succeed once their files are received.
+    pub fn image_file_type(self, file_type: u32) -> Self {
+        assert!(file_type < u32::BITS);
+        Self {
+            config: Config {
+                image_file_types: self.config.image_file_types | 1 << file_type,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
     pub fn with_self_test_timeout<NST>(
         self,
         timer: NST,
         timeout_ms: u32,
-    ) -> OtaAgentBuilder<'a, C, DP, DS, T, NST, PAL, TIMER_HZ>
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, NST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
     where
         NST: fugit_timer::Timer<TIMER_HZ>,
     {
@@ -179,6 +312,10 @@ where
             pal: self.pal,
             request_timer: self.request_timer,
             self_test_timer: Some(timer),
+            observer: self.observer,
+            policy: self.policy,
+            health_check: self.health_check,
+            rng: self.rng,
             config: Config {
                 self_test_timeout_ms: timeout_ms,
                 ..self.config
@@ -186,7 +323,135 @@ where
         }
     }
 
-    pub fn build(self) -> OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ> {
+    /// Report progress of the OTA update to `observer`, eg. to drive a
+    /// progress UI or publish metrics.
+    pub fn with_observer<NOB>(
+        self,
+        observer: NOB,
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, NOB, UP, HC, R, K, V, N, P>
+    where
+        NOB: OtaObserver,
+    {
+        OtaAgentBuilder {
+            control: self.control,
+            data_primary: self.data_primary,
+            data_secondary: self.data_secondary,
+            pal: self.pal,
+            request_timer: self.request_timer,
+            self_test_timer: self.self_test_timer,
+            observer,
+            policy: self.policy,
+            health_check: self.health_check,
+            rng: self.rng,
+            config: self.config,
+        }
+    }
+
+    /// Consult `policy` before starting a job and before activating the
+    /// received image.
+    pub fn with_update_policy<NUP>(
+        self,
+        policy: NUP,
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, NUP, HC, R, K, V, N, P>
+    where
+        NUP: UpdatePolicy,
+    {
+        OtaAgentBuilder {
+            control: self.control,
+            data_primary: self.data_primary,
+            data_secondary: self.data_secondary,
+            pal: self.pal,
+            request_timer: self.request_timer,
+            self_test_timer: self.self_test_timer,
+            observer: self.observer,
+            policy,
+            health_check: self.health_check,
+            rng: self.rng,
+            config: self.config,
+        }
+    }
+
+    /// Accept a new image in the self test phase only once `health_check`
+    /// passed within the self test timeout.
+    pub fn with_health_check<NHC>(
+        self,
+        health_check: NHC,
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, NHC, R, K, V, N, P>
+    where
+        NHC: HealthCheck,
+    {
+        OtaAgentBuilder {
+            control: self.control,
+            data_primary: self.data_primary,
+            data_secondary: self.data_secondary,
+            pal: self.pal,
+            request_timer: self.request_timer,
+            self_test_timer: self.self_test_timer,
+            observer: self.observer,
+            policy: self.policy,
+            health_check,
+            rng: self.rng,
+            config: self.config,
+        }
+    }
+
+    /// Randomize the wait before retrying an unanswered request with `rng`,
+    /// see [`backoff`](super::backoff).
+    pub fn with_rng<NR>(
+        self,
+        rng: NR,
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, NR, K, V, N, P>
+    where
+        NR: Rng,
+    {
+        OtaAgentBuilder {
+            control: self.control,
+            data_primary: self.data_primary,
+            data_secondary: self.data_secondary,
+            pal: self.pal,
+            request_timer: self.request_timer,
+            self_test_timer: self.self_test_timer,
+            observer: self.observer,
+            policy: self.policy,
+            health_check: self.health_check,
+            rng,
+            config: self.config,
+        }
+    }
+
+    /// Size the status details of the OTA jobs to hold `NN` entries, with
+    /// keys of at most `NK` bytes and values of at most `NV` bytes, eg. to
+    /// attach more status details with
+    /// [`OtaAgent::set_status_detail`](super::agent::OtaAgent::set_status_detail).
+    ///
+    /// Job status updates are encoded in payloads of at most `NP` bytes, which
+    /// must fit a full map of status details, ie. at least
+    /// [`update_payload_len(NK, NV, NN)`](crate::jobs::update_payload_len).
+    /// Smaller payloads fail to build.
+    pub fn with_status_details<
+        const NK: usize,
+        const NV: usize,
+        const NN: usize,
+        const NP: usize,
+    >(
+        self,
+    ) -> OtaAgentBuilder<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, NK, NV, NN, NP> {
+        OtaAgentBuilder {
+            control: self.control,
+            data_primary: self.data_primary,
+            data_secondary: self.data_secondary,
+            pal: self.pal,
+            request_timer: self.request_timer,
+            self_test_timer: self.self_test_timer,
+            observer: self.observer,
+            policy: self.policy,
+            health_check: self.health_check,
+            rng: self.rng,
+            config: self.config,
+        }
+    }
+
+    pub fn build(self) -> OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P> {
         OtaAgent {
             state: StateMachine::new(SmContext {
                 events: heapless::spsc::Queue::new(),
@@ -196,10 +461,17 @@ where
                 active_interface: None,
                 request_momentum: 0,
                 request_timer: self.request_timer,
+                throttle: Throttle::new(),
                 self_test_timer: self.self_test_timer,
                 pal: self.pal,
+                observer: self.observer,
+                policy: self.policy,
+                health_check: self.health_check,
+                rng: self.rng,
                 config: self.config,
                 image_state: ImageState::Unknown,
+                job_status_details: StatusDetailsOwned::new(),
+                status_details: StatusDetailsOwned::new(),
             }),
         }
     }
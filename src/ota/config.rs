@@ -3,10 +3,16 @@ pub struct Config {
     pub(crate) max_request_momentum: u8,
     pub(crate) activate_delay: u8,
     pub(crate) request_wait_ms: u32,
+    pub(crate) max_request_wait_ms: u32,
+    pub(crate) max_bytes_per_second: u32,
     pub(crate) status_update_frequency: u32,
     pub(crate) allow_downgrade: bool,
     pub(crate) unsubscribe_on_shutdown: bool,
     pub(crate) self_test_timeout_ms: u32,
+    pub(crate) describe_stream: bool,
+    pub(crate) manual_activation: bool,
+    /// File types of the job document holding images to activate, by bit
+    pub(crate) image_file_types: u32,
 }
 
 impl Default for Config {
@@ -16,10 +22,15 @@ impl Default for Config {
             max_request_momentum: 3,
             activate_delay: 5,
             request_wait_ms: 8000,
+            max_request_wait_ms: 0,
+            max_bytes_per_second: 0,
             status_update_frequency: 24,
             allow_downgrade: false,
             unsubscribe_on_shutdown: true,
             self_test_timeout_ms: 16000,
+            describe_stream: false,
+            manual_activation: false,
+            image_file_types: 1,
         }
     }
 }
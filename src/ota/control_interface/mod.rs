@@ -1,4 +1,4 @@
-use crate::jobs::data_types::JobStatus;
+use crate::jobs::{data_types::JobStatus, StatusDetailsOwned};
 
 use super::{
     config::Config,
@@ -12,9 +12,10 @@ pub mod mqtt;
 pub trait ControlInterface {
     fn init(&self) -> Result<(), OtaError>;
     fn request_job(&self) -> Result<(), OtaError>;
-    fn update_job_status(
+    fn update_job_status<const K: usize, const V: usize, const N: usize, const P: usize>(
         &self,
-        file_ctx: &mut FileContext,
+        file_ctx: &FileContext,
+        status_details: &mut StatusDetailsOwned<K, V, N>,
         config: &Config,
         status: JobStatus,
         reason: JobStatusReason,
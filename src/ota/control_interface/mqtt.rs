@@ -5,18 +5,20 @@ use mqttrust::QoS;
 use super::ControlInterface;
 use crate::jobs::data_types::JobStatus;
 use crate::jobs::subscribe::Topic;
-use crate::jobs::Jobs;
+use crate::jobs::{Jobs, StatusDetailsOwned};
 use crate::ota::config::Config;
 use crate::ota::encoding::json::JobStatusReason;
-use crate::ota::encoding::FileContext;
+use crate::ota::encoding::{set_status_detail, FileContext};
 use crate::ota::error::OtaError;
 
 impl<T: mqttrust::Mqtt> ControlInterface for T {
     /// Initialize the control interface by subscribing to the OTA job
-    /// notification topics.
+    /// notification topics, and to rejected job updates to detect canceled
+    /// jobs.
     fn init(&self) -> Result<(), OtaError> {
-        Jobs::subscribe::<1>()
+        Jobs::subscribe::<2>()
             .topic(Topic::NotifyNext, QoS::AtLeastOnce)
+            .topic(Topic::UpdateRejected("+"), QoS::AtLeastOnce)
             .send(self)?;
         Ok(())
     }
@@ -31,20 +33,24 @@ impl<T: mqttrust::Mqtt> ControlInterface for T {
 
     /// Update the job status on the service side with progress or completion
     /// info
-    fn update_job_status(
+    fn update_job_status<const K: usize, const V: usize, const N: usize, const P: usize>(
         &self,
-        file_ctx: &mut FileContext,
+        file_ctx: &FileContext,
+        status_details: &mut StatusDetailsOwned<K, V, N>,
         config: &Config,
         status: JobStatus,
         reason: JobStatusReason,
     ) -> Result<(), OtaError> {
-        file_ctx
-            .status_details
-            .insert(
-                heapless::String::from("self_test"),
-                heapless::String::from(reason.as_str()),
-            )
-            .map_err(|_| OtaError::Overflow)?;
+        set_status_detail(status_details, "self_test", reason.as_str())?;
+
+        if let JobStatusReason::Pal(code) = reason {
+            let mut reason = heapless::String::<10>::new();
+            reason
+                .write_fmt(format_args!("0x{:08x}", code))
+                .map_err(|_| OtaError::Overflow)?;
+
+            set_status_detail(status_details, "reason", &reason)?;
+        }
 
         let mut qos = QoS::AtLeastOnce;
 
@@ -66,15 +72,12 @@ impl<T: mqttrust::Mqtt> ControlInterface for T {
             // active. (Cases where progess counter is lost due to device
             // restarts)
             if status != JobStatus::Succeeded && reason != JobStatusReason::SelfTestActive {
-                let mut progress = heapless::String::new();
+                let mut progress = heapless::String::<21>::new();
                 progress
                     .write_fmt(format_args!("{}/{}", received_blocks, total_blocks))
                     .map_err(|_| OtaError::Overflow)?;
 
-                file_ctx
-                    .status_details
-                    .insert(heapless::String::from("progress"), progress)
-                    .map_err(|_| OtaError::Overflow)?;
+                set_status_detail(status_details, "progress", &progress)?;
             }
 
             // Downgrade progress updates to QOS 0 to avoid overloading MQTT
@@ -85,7 +88,8 @@ impl<T: mqttrust::Mqtt> ControlInterface for T {
         }
 
         Jobs::update(file_ctx.job_name.as_str(), status)
-            .status_details(&file_ctx.status_details)
+            .status_details(status_details)
+            .payload_len::<P>()
             .send(self, qos)?;
 
         Ok(())
@@ -93,8 +97,9 @@ impl<T: mqttrust::Mqtt> ControlInterface for T {
 
     /// Perform any cleanup operations required for control plane
     fn cleanup(&self) -> Result<(), OtaError> {
-        Jobs::unsubscribe::<1>()
+        Jobs::unsubscribe::<2>()
             .topic(Topic::NotifyNext)
+            .topic(Topic::UpdateRejected("+"))
             .send(self)?;
         Ok(())
     }
@@ -56,6 +56,22 @@ pub trait DataInterface {
         payload: &'a mut [u8],
     ) -> Result<FileBlock<'a>, OtaError>;
     fn cleanup(&self, file_ctx: &mut FileContext, config: &Config) -> Result<(), OtaError>;
+
+    /// Request a description of the stream delivering the file. Only called
+    /// for protocols delivering files over streams.
+    fn request_stream_description(&self, _file_ctx: &mut FileContext) -> Result<(), OtaError> {
+        Err(OtaError::InvalidInterface)
+    }
+
+    /// Validate the file ID and size of the file context against a received
+    /// stream description.
+    fn validate_stream_description(
+        &self,
+        _file_ctx: &mut FileContext,
+        _payload: &mut [u8],
+    ) -> Result<(), OtaError> {
+        Err(OtaError::InvalidInterface)
+    }
 }
 
 pub struct NoInterface;
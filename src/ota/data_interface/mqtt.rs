@@ -81,12 +81,13 @@ impl<'a> From<&Topic<'a>> for OtaTopic<'a> {
     }
 }
 
-enum OtaTopic<'a> {
+pub(crate) enum OtaTopic<'a> {
     Data(Encoding, &'a str),
     Description(Encoding, &'a str),
     Rejected(Encoding, &'a str),
 
     Get(Encoding, &'a str),
+    Describe(Encoding, &'a str),
 }
 
 impl<'a> OtaTopic<'a> {
@@ -109,6 +110,10 @@ impl<'a> OtaTopic<'a> {
                 "$aws/things/{}/streams/{}/get/{}",
                 client_id, stream_name, encoding
             )),
+            Self::Describe(encoding, stream_name) => topic_path.write_fmt(format_args!(
+                "$aws/things/{}/streams/{}/describe/{}",
+                client_id, stream_name, encoding
+            )),
         }
         .map_err(|_| OtaError::Overflow)?;
 
@@ -196,9 +201,71 @@ where
                     .format::<256>(self.client_id())?
                     .as_str(),
             ])?;
+
+            if config.describe_stream {
+                self.unsubscribe(&[OtaTopic::Description(
+                    Encoding::Cbor,
+                    file_ctx.stream_name.as_str(),
+                )
+                .format::<256>(self.client_id())?
+                .as_str()])?;
+            }
         }
         Ok(())
     }
+
+    /// Subscribe to the stream description topic, and request the description
+    /// by publishing to the describe stream topic
+    fn request_stream_description(&self, file_ctx: &mut FileContext) -> Result<(), OtaError> {
+        let topic_path = OtaTopic::Description(Encoding::Cbor, file_ctx.stream_name.as_str())
+            .format::<256>(self.client_id())?;
+
+        self.subscribe(&[SubscribeTopic {
+            topic_path: topic_path.as_str(),
+            qos: mqttrust::QoS::AtLeastOnce,
+        }])?;
+
+        let buf = &mut [0u8; 32];
+        let len = cbor::to_slice(&cbor::DescribeStreamRequest { client_token: None }, buf)
+            .map_err(|_| OtaError::Encoding)?;
+
+        self.publish(
+            OtaTopic::Describe(Encoding::Cbor, file_ctx.stream_name.as_str())
+                .format::<{ MAX_STREAM_ID_LEN + MAX_THING_NAME_LEN + 30 }>(self.client_id())?
+                .as_str(),
+            &buf[..len],
+            QoS::AtMostOnce,
+        )?;
+
+        Ok(())
+    }
+
+    /// Decode a cbor encoded stream description, and check that it contains
+    /// the file with the expected size
+    fn validate_stream_description(
+        &self,
+        file_ctx: &mut FileContext,
+        payload: &mut [u8],
+    ) -> Result<(), OtaError> {
+        let description = serde_cbor::de::from_mut_slice::<cbor::DescribeStreamResponse>(payload)
+            .map_err(|_| OtaError::Encoding)?;
+
+        let file = description
+            .files
+            .iter()
+            .find(|f| f.file_id == file_ctx.fileid)
+            .ok_or(OtaError::FileIdMismatch)?;
+
+        if file.file_size != file_ctx.filesize {
+            error!(
+                "Stream file size {:?} does not match job document file size {:?}",
+                file.file_size, file_ctx.filesize
+            );
+            return Err(OtaError::FileSizeMismatch);
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -394,6 +461,60 @@ mod tests {
         );
     }
 
+    #[test]
+    fn request_stream_description_publish() {
+        let mqtt = &MockMqtt::new();
+
+        let mut file_ctx = test_file_ctx(&Config::default());
+
+        mqtt.request_stream_description(&mut file_ctx).unwrap();
+
+        assert_eq!(mqtt.tx.borrow_mut().len(), 2);
+        let bytes = mqtt.tx.borrow_mut().pop_back().unwrap();
+
+        let publish = match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(s)) => s,
+            _ => panic!(),
+        };
+
+        assert_eq!(
+            publish.topic_name,
+            "$aws/things/test_client/streams/test_stream/describe/cbor"
+        );
+        assert_eq!(publish.payload, &[160]);
+    }
+
+    #[test]
+    fn validate_stream_description() {
+        let mqtt = &MockMqtt::new();
+
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = 10;
+
+        // {"s": 1, "d": "", "r": [{"f": 0, "z": 10}]}
+        let description = [
+            0xA3, 0x61, b's', 0x01, 0x61, b'd', 0x60, 0x61, b'r', 0x81, 0xA2, 0x61, b'f', 0x00,
+            0x61, b'z', 0x0A,
+        ];
+
+        assert_eq!(
+            mqtt.validate_stream_description(&mut file_ctx, &mut description.clone()),
+            Ok(())
+        );
+
+        file_ctx.filesize = 11;
+        assert_eq!(
+            mqtt.validate_stream_description(&mut file_ctx, &mut description.clone()),
+            Err(OtaError::FileSizeMismatch)
+        );
+
+        file_ctx.fileid = 1;
+        assert_eq!(
+            mqtt.validate_stream_description(&mut file_ctx, &mut description.clone()),
+            Err(OtaError::FileIdMismatch)
+        );
+    }
+
     #[test]
     fn cleanup_no_unsubscribe() {
         let mqtt = &MockMqtt::new();
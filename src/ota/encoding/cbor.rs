@@ -10,8 +10,8 @@ pub struct DescribeStreamRequest<'a> {
     pub client_token: Option<&'a str>,
 }
 
-#[derive(Serialize)]
-pub struct DescribeStreamResponse<'a> {
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct DescribeStreamResponse<'a, const N: usize = 4> {
     #[serde(rename = "c", skip_serializing_if = "Option::is_none")]
     pub client_token: Option<&'a str>,
     #[serde(rename = "s")]
@@ -19,10 +19,10 @@ pub struct DescribeStreamResponse<'a> {
     #[serde(rename = "d")]
     pub description: &'a str,
     #[serde(rename = "r")]
-    pub files: &'a [StreamFile],
+    pub files: heapless::Vec<StreamFile, N>,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct StreamFile {
     #[serde(rename = "f")]
     pub file_id: u8,
@@ -62,7 +62,7 @@ pub struct GetStreamResponse<'a> {
     pub block_payload: &'a [u8],
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct StreamError<'a> {
     #[serde(rename = "o")]
     pub error_code: &'a str,
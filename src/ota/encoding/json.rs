@@ -1,14 +1,36 @@
 use crate::ota::data_interface::Protocol;
+use crate::ota::encoding::JobDocument;
 use core::str::FromStr;
 use serde::Deserialize;
 
+/// Maximum number of files of an [`OtaJob`].
+pub const MAX_FILES: usize = 4;
+
 /// OTA job document, compatible with FreeRTOS OTA process
 #[derive(Debug, PartialEq, Deserialize)]
 #[serde(rename = "afr_ota")]
 pub struct OtaJob<'a> {
     pub protocols: heapless::Vec<Protocol, 2>,
     pub streamname: &'a str,
-    pub files: heapless::Vec<FileDescription<'a>, 1>,
+    pub files: heapless::Vec<FileDescription<'a>, MAX_FILES>,
+}
+
+impl<'a> JobDocument for OtaJob<'a> {
+    fn protocols(&self) -> &[Protocol] {
+        &self.protocols
+    }
+
+    fn stream_name(&self) -> &str {
+        self.streamname
+    }
+
+    fn file(&self, file_idx: usize) -> Option<FileDescription<'_>> {
+        self.files.get(file_idx).cloned()
+    }
+
+    fn file_count(&self) -> usize {
+        self.files.len()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -56,23 +78,109 @@ pub struct FileDescription<'a> {
     #[serde(rename = "fileType")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_type: Option<u32>,
+
+    #[serde(rename = "enc")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionDescription<'a>>,
+}
+
+/// Maximum length of a wrapped image key.
+pub const MAX_WRAPPED_KEY_LEN: usize = 128;
+
+/// Cipher an encrypted file is encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Cipher {
+    /// AES-128 in GCM mode, with a 12 byte IV and a 16 byte authentication
+    /// tag.
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    /// AES-128 in CTR mode, with a 16 byte initial counter block.
+    #[serde(rename = "aes-128-ctr")]
+    Aes128Ctr,
+}
+
+/// Encryption of a file of the job document, with hex encoded values.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EncryptionDescription<'a> {
+    #[serde(rename = "alg")]
+    pub cipher: Cipher,
+    /// Per-image key, wrapped for the device.
+    #[serde(rename = "key")]
+    pub wrapped_key: &'a str,
+    #[serde(rename = "iv")]
+    pub iv: &'a str,
+    #[serde(rename = "tag")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<&'a str>,
+}
+
+/// Decoded [`EncryptionDescription`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encryption {
+    pub cipher: Cipher,
+    pub wrapped_key: heapless::Vec<u8, MAX_WRAPPED_KEY_LEN>,
+    pub iv: heapless::Vec<u8, 16>,
+    pub tag: Option<[u8; 16]>,
+}
+
+impl<'a> EncryptionDescription<'a> {
+    pub fn decode(&self) -> Option<Encryption> {
+        let iv = decode_hex(self.iv)?;
+        let tag = match self.tag {
+            Some(tag) => Some(decode_hex::<16>(tag)?.into_array().ok()?),
+            None => None,
+        };
+
+        let valid = match self.cipher {
+            Cipher::Aes128Gcm => iv.len() == 12 && tag.is_some(),
+            Cipher::Aes128Ctr => iv.len() == 16,
+        };
+
+        valid.then_some(Encryption {
+            cipher: self.cipher,
+            wrapped_key: decode_hex(self.wrapped_key)?,
+            iv,
+            tag,
+        })
+    }
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Option<heapless::Vec<u8, N>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut bytes = heapless::Vec::new();
+    for pair in s.as_bytes().chunks(2) {
+        if !pair.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let digits = core::str::from_utf8(pair).ok()?;
+        bytes.push(u8::from_str_radix(digits, 16).ok()?).ok()?;
+    }
+    Some(bytes)
 }
 
 impl<'a> FileDescription<'a> {
-    pub fn signature(&self) -> Signature {
-        if let Some(sig) = self.sha1_rsa {
-            return Signature::Sha1Rsa(heapless::String::from(sig));
+    /// Signature of the file, `None` if the file has no signature or if it
+    /// doesn't fit.
+    pub fn signature(&self) -> Option<Signature> {
+        let sig = |sig: &str| heapless::String::from_str(sig).ok();
+
+        if let Some(sha1_rsa) = self.sha1_rsa {
+            return sig(sha1_rsa).map(Signature::Sha1Rsa);
         }
-        if let Some(sig) = self.sha256_rsa {
-            return Signature::Sha256Rsa(heapless::String::from(sig));
+        if let Some(sha256_rsa) = self.sha256_rsa {
+            return sig(sha256_rsa).map(Signature::Sha256Rsa);
         }
-        if let Some(sig) = self.sha1_ecdsa {
-            return Signature::Sha1Ecdsa(heapless::String::from(sig));
+        if let Some(sha1_ecdsa) = self.sha1_ecdsa {
+            return sig(sha1_ecdsa).map(Signature::Sha1Ecdsa);
         }
-        if let Some(sig) = self.sha256_ecdsa {
-            return Signature::Sha256Ecdsa(heapless::String::from(sig));
+        if let Some(sha256_ecdsa) = self.sha256_ecdsa {
+            return sig(sha256_ecdsa).map(Signature::Sha256Ecdsa);
         }
-        unreachable!()
+        None
     }
 }
 
@@ -84,6 +192,7 @@ pub enum JobStatusReason {
     Accepted,       /* Set job state to Succeeded. */
     Rejected,       /* Set job state to Failed. */
     Aborted,        /* Set job state to Failed. */
+    RolledBack,     /* Set job state to Failed. */
     Pal(u32),
 }
 
@@ -96,6 +205,7 @@ impl JobStatusReason {
             JobStatusReason::Accepted => "accepted",
             JobStatusReason::Rejected => "rejected",
             JobStatusReason::Aborted => "aborted",
+            JobStatusReason::RolledBack => "rolled-back",
             JobStatusReason::Pal(_) => "pal err",
         }
     }
@@ -112,6 +222,7 @@ impl FromStr for JobStatusReason {
             "accepted" => JobStatusReason::Accepted,
             "rejected" => JobStatusReason::Rejected,
             "aborted" => JobStatusReason::Aborted,
+            "rolled-back" => JobStatusReason::RolledBack,
             _ => return Err(()),
         })
     }
@@ -132,6 +243,7 @@ mod tests {
             (JobStatusReason::Accepted, "accepted"),
             (JobStatusReason::Rejected, "rejected"),
             (JobStatusReason::Aborted, "aborted"),
+            (JobStatusReason::RolledBack, "rolled-back"),
         ];
 
         for (reason, exp) in reasons {
@@ -6,12 +6,16 @@ use core::ops::{Deref, DerefMut};
 use core::str::FromStr;
 use serde::{Serialize, Serializer};
 
-use crate::jobs::StatusDetailsOwned;
+use crate::jobs::{StatusDetails, StatusDetailsOwned};
 
-use self::json::{JobStatusReason, OtaJob, Signature};
+use self::json::{Encryption, FileDescription, JobStatusReason, Signature};
 
+use super::data_interface::Protocol;
 use super::error::OtaError;
-use super::{config::Config, pal::Version};
+use super::{
+    config::Config,
+    pal::{Version, MAX_VERSION_LEN},
+};
 
 #[derive(Clone, PartialEq)]
 pub struct Bitmap(bitmaps::Bitmap<32>);
@@ -51,6 +55,30 @@ impl Serialize for Bitmap {
     }
 }
 
+/// Job document of an OTA update, mapping the files of the document to the
+/// [`FileDescription`]s a [`FileContext`] is built from.
+///
+/// Implemented by the FreeRTOS compatible [`OtaJob`](json::OtaJob), and by
+/// custom job documents, eg. listing URLs and hashes of target components.
+pub trait JobDocument {
+    /// Protocols the files can be downloaded with, by order of preference.
+    fn protocols(&self) -> &[Protocol];
+
+    /// Name of the stream delivering the files over MQTT, identifying the
+    /// update.
+    fn stream_name(&self) -> &str;
+
+    /// Description of the file with index `file_idx`, if any.
+    fn file(&self, file_idx: usize) -> Option<FileDescription<'_>>;
+
+    /// Number of files of the job, received one after the other.
+    fn file_count(&self) -> usize {
+        (0..)
+            .take_while(|&file_idx| self.file(file_idx).is_some())
+            .count()
+    }
+}
+
 /// A `FileContext` denotes an active context of a single file. An ota job can
 /// contain multiple files, each with their own `FileContext` built from a
 /// corresponding `FileDescription`.
@@ -64,92 +92,158 @@ pub struct FileContext {
     pub auth_scheme: Option<heapless::String<64>>,
     pub signature: Signature,
     pub file_type: Option<u32>,
+    pub encryption: Option<Encryption>,
 
-    pub status_details: StatusDetailsOwned,
     pub block_offset: u32,
     pub blocks_remaining: usize,
     pub request_block_remaining: u32,
     pub job_name: heapless::String<64>,
     pub stream_name: heapless::String<64>,
     pub bitmap: Bitmap,
+    /// Index of the file in the job document.
+    pub file_idx: usize,
+    /// Number of files of the job document.
+    pub file_count: usize,
+    /// Whether the job document holds an image to activate once all of its
+    /// files are received.
+    pub activate: bool,
 }
 
 impl FileContext {
     pub fn new_from(
         job_name: &str,
-        ota_job: &OtaJob,
-        status_details: Option<StatusDetailsOwned>,
+        ota_job: &dyn JobDocument,
         file_idx: usize,
         config: &Config,
-        current_version: Version,
     ) -> Result<Self, OtaError> {
-        let file_desc = ota_job
-            .files
-            .get(file_idx)
-            .ok_or(OtaError::InvalidFile)?
-            .clone();
-
-        // Initialize new `status_details' if not already present
-        let status = if let Some(details) = status_details {
-            details
-        } else {
-            let mut status = StatusDetailsOwned::new();
-            status
-                .insert(
-                    heapless::String::from("updated_by"),
-                    current_version.to_string(),
-                )
-                .map_err(|_| OtaError::Overflow)?;
-            status
-        };
+        let file_desc = ota_job.file(file_idx).ok_or(OtaError::InvalidFile)?;
 
-        let signature = file_desc.signature();
+        let signature = file_desc.signature().ok_or(OtaError::InvalidFile)?;
+        let encryption = match file_desc.encryption {
+            Some(ref encryption) => Some(encryption.decode().ok_or(OtaError::InvalidFile)?),
+            None => None,
+        };
 
         let block_offset = 0;
         let bitmap = Bitmap::new(file_desc.filesize, config.block_size, block_offset);
 
         Ok(FileContext {
-            filepath: heapless::String::from(file_desc.filepath),
+            filepath: bounded(file_desc.filepath)?,
             filesize: file_desc.filesize,
             fileid: file_desc.fileid,
-            certfile: heapless::String::from(file_desc.certfile),
-            update_data_url: file_desc.update_data_url.map(heapless::String::from),
-            auth_scheme: file_desc.auth_scheme.map(heapless::String::from),
+            certfile: bounded(file_desc.certfile)?,
+            update_data_url: file_desc.update_data_url.map(bounded).transpose()?,
+            auth_scheme: file_desc.auth_scheme.map(bounded).transpose()?,
             signature,
             file_type: file_desc.file_type,
+            encryption,
 
-            status_details: status,
-
-            job_name: heapless::String::from(job_name),
+            job_name: bounded(job_name)?,
             block_offset,
             request_block_remaining: bitmap.len() as u32,
             blocks_remaining: (file_desc.filesize + config.block_size - 1) / config.block_size,
-            stream_name: heapless::String::from(ota_job.streamname),
+            stream_name: bounded(ota_job.stream_name())?,
             bitmap,
+            file_idx,
+            file_count: ota_job.file_count(),
+            activate: (0..ota_job.file_count())
+                .filter_map(|file_idx| ota_job.file(file_idx)?.file_type)
+                .any(|file_type| {
+                    file_type < u32::BITS && config.image_file_types & 1 << file_type != 0
+                }),
         })
     }
+}
 
-    pub fn self_test(&self) -> bool {
-        self.status_details
-            .get(&heapless::String::from("self_test"))
-            .and_then(|f| f.parse().ok())
-            .map(|reason: JobStatusReason| {
-                reason == JobStatusReason::SigCheckPassed
-                    || reason == JobStatusReason::SelfTestActive
-            })
-            .unwrap_or(false)
-    }
+/// Copy a field of the job document, failing with [`OtaError::InvalidFile`] if
+/// it doesn't fit.
+pub(crate) fn bounded<const N: usize>(value: &str) -> Result<heapless::String<N>, OtaError> {
+    heapless::String::from_str(value).map_err(|_| OtaError::InvalidFile)
+}
 
-    pub fn updated_by(&self) -> Option<Version> {
-        self.status_details
-            .get(&heapless::String::from("updated_by"))
-            .and_then(|s| Version::from_str(s.as_str()).ok())
+/// Status details of a new job, taken over from the job execution if present,
+/// or recording the version performing the update otherwise. Fails with
+/// [`OtaError::Overflow`] if the version is longer than `V`.
+pub(crate) fn job_status_details<const K: usize, const V: usize, const N: usize>(
+    status_details: Option<&StatusDetails>,
+    current_version: &Version,
+) -> Result<StatusDetailsOwned<K, V, N>, OtaError> {
+    let mut details = StatusDetailsOwned::new();
+    match status_details {
+        Some(status_details) => {
+            for (key, value) in status_details.iter() {
+                set_status_detail(&mut details, key, value)?;
+            }
+        }
+        None => {
+            // Build metadata does not affect precedence, so leave it out to
+            // keep the status details small
+            let updated_by: heapless::String<MAX_VERSION_LEN> = current_version
+                .without_build()
+                .to_string()
+                .ok_or(OtaError::Overflow)?;
+            if updated_by.len() > V {
+                error!(
+                    "Version {} does not fit the status details, size them up in the builder",
+                    updated_by.as_str()
+                );
+                return Err(OtaError::Overflow);
+            }
+            set_status_detail(&mut details, "updated_by", &updated_by)?;
+        }
     }
+    Ok(details)
+}
+
+/// Insert or replace the status detail `key`, failing with
+/// [`OtaError::Overflow`] if it doesn't fit.
+pub fn set_status_detail<const K: usize, const V: usize, const N: usize>(
+    status_details: &mut StatusDetailsOwned<K, V, N>,
+    key: &str,
+    value: &str,
+) -> Result<(), OtaError> {
+    let key = heapless::String::from_str(key).map_err(|_| OtaError::Overflow)?;
+    let value = heapless::String::from_str(value).map_err(|_| OtaError::Overflow)?;
+    status_details
+        .insert(key, value)
+        .map_err(|_| OtaError::Overflow)?;
+    Ok(())
+}
+
+fn status_detail<'d, const K: usize, const V: usize, const N: usize>(
+    status_details: &'d StatusDetailsOwned<K, V, N>,
+    key: &str,
+) -> Option<&'d str> {
+    status_details
+        .iter()
+        .find(|(k, _)| k.as_str() == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Whether the job is in self test, after the device reset into the new
+/// image.
+pub(crate) fn self_test<const K: usize, const V: usize, const N: usize>(
+    status_details: &StatusDetailsOwned<K, V, N>,
+) -> bool {
+    status_detail(status_details, "self_test")
+        .and_then(|f| f.parse().ok())
+        .map(|reason: JobStatusReason| {
+            reason == JobStatusReason::SigCheckPassed || reason == JobStatusReason::SelfTestActive
+        })
+        .unwrap_or(false)
+}
+
+/// Version of the firmware that performed the update.
+pub(crate) fn updated_by<const K: usize, const V: usize, const N: usize>(
+    status_details: &StatusDetailsOwned<K, V, N>,
+) -> Option<Version> {
+    status_detail(status_details, "updated_by").and_then(|s| Version::from_str(s).ok())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ota::test::test_job_doc;
 
     #[test]
     fn bitmap_masking() {
@@ -158,4 +252,67 @@ mod tests {
         let true_indices: Vec<usize> = bitmap.into_iter().collect();
         assert_eq!((0..31).into_iter().collect::<Vec<usize>>(), true_indices);
     }
+
+    #[test]
+    fn activate_image_file_types() {
+        let mut config = Config::default();
+        let mut job_doc = test_job_doc();
+        let mut modem = job_doc.files[0].clone();
+        modem.file_type = Some(1);
+        job_doc.files.push(modem).unwrap();
+
+        // Any file of the job decides, not only the current one
+        let file_ctx = FileContext::new_from("Job-name", &job_doc, 1, &config).unwrap();
+        assert!(file_ctx.activate);
+
+        job_doc.files[0].file_type = Some(2);
+        let file_ctx = FileContext::new_from("Job-name", &job_doc, 0, &config).unwrap();
+        assert!(!file_ctx.activate);
+
+        config.image_file_types |= 1 << 1;
+        let file_ctx = FileContext::new_from("Job-name", &job_doc, 0, &config).unwrap();
+        assert!(file_ctx.activate);
+    }
+
+    #[test]
+    fn invalid_file_description() {
+        let config = Config::default();
+
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].sha1_rsa = None;
+        assert!(matches!(
+            FileContext::new_from("Job-name", &job_doc, 0, &config),
+            Err(OtaError::InvalidFile)
+        ));
+
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filepath =
+            "firmware/0123456789012345678901234567890123456789012345678901234.bin";
+        assert!(matches!(
+            FileContext::new_from("Job-name", &job_doc, 0, &config),
+            Err(OtaError::InvalidFile)
+        ));
+
+        let job_name = "j".repeat(65);
+        assert!(matches!(
+            FileContext::new_from(&job_name, &test_job_doc(), 0, &config),
+            Err(OtaError::InvalidFile)
+        ));
+    }
+
+    #[test]
+    fn updated_by_fits_status_details() {
+        let version: Version = "1.20.300-beta.123+build.7".parse().unwrap();
+
+        assert_eq!(
+            job_status_details::<24, 16, 6>(None, &version),
+            Err(OtaError::Overflow)
+        );
+
+        let details = job_status_details::<24, 32, 6>(None, &version).unwrap();
+        assert_eq!(
+            status_detail(&details, "updated_by"),
+            Some("1.20.300-beta.123")
+        );
+    }
 }
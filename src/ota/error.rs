@@ -1,6 +1,6 @@
 use crate::jobs::JobError;
 
-use super::pal::OtaPalError;
+use super::pal::{OtaPalError, PalErrorCode};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -15,9 +15,21 @@ pub enum OtaError {
     ZeroFileSize,
     Overflow,
     InvalidFile,
+    /// The stream does not contain the file ID of the job document.
+    FileIdMismatch,
+    /// The size of the file in the stream differs from the job document.
+    FileSizeMismatch,
+    /// The update policy deferred the job.
+    UpdateDeferred,
+    /// The update policy rejected the job.
+    UpdateRejected,
+    /// The bootloader rolled back the new image before it completed its self
+    /// test.
+    RolledBack,
     Mqtt(mqttrust::MqttError),
     Encoding,
-    Pal,
+    /// The PAL failed, with the code given by [`OtaPalError::code`].
+    Pal(u32),
     Timer,
 }
 
@@ -33,9 +45,9 @@ impl From<mqttrust::MqttError> for OtaError {
     }
 }
 
-impl<E> From<OtaPalError<E>> for OtaError {
-    fn from(_e: OtaPalError<E>) -> Self {
-        Self::Pal
+impl<E: PalErrorCode> From<OtaPalError<E>> for OtaError {
+    fn from(e: OtaPalError<E>) -> Self {
+        Self::Pal(e.code())
     }
 }
 
@@ -0,0 +1,96 @@
+//! Application health checks completing the self test of a new image, eg.
+//! cloud connectivity, peripherals or sensor sanity.
+//!
+//! The image is only accepted once every check passed within the self test
+//! timeout, and rejected with the reason of the failed check otherwise.
+
+/// Outcome of a [`HealthCheck`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HealthStatus {
+    /// The check hasn't completed yet, and is polled again.
+    Pending,
+    /// The check passed.
+    Passed,
+    /// The check failed, rejecting the image. The reason is reported in the
+    /// `health_check` status detail of the job.
+    Failed(&'static str),
+}
+
+/// Health check of the new image in the self test phase, configured with
+/// [`OtaAgentBuilder::with_health_check`](super::builder::OtaAgentBuilder::with_health_check).
+///
+/// Several checks can be combined in a tuple, passing once all of them passed
+/// and failing as soon as one of them failed.
+pub trait HealthCheck {
+    /// Polled on every call to
+    /// [`OtaAgent::process_event`](super::agent::OtaAgent::process_event)
+    /// while the self test is in progress.
+    fn check(&mut self) -> HealthStatus;
+}
+
+/// Passes immediately, used unless health checks are configured.
+pub struct NoHealthCheck;
+
+impl HealthCheck for NoHealthCheck {
+    fn check(&mut self) -> HealthStatus {
+        HealthStatus::Passed
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($check:ident),+) => {
+        impl<$($check: HealthCheck),+> HealthCheck for ($($check,)+) {
+            #[allow(non_snake_case)]
+            fn check(&mut self) -> HealthStatus {
+                let ($($check,)+) = self;
+                let mut status = HealthStatus::Passed;
+                $(
+                    match $check.check() {
+                        HealthStatus::Failed(reason) => return HealthStatus::Failed(reason),
+                        HealthStatus::Pending => status = HealthStatus::Pending,
+                        HealthStatus::Passed => {}
+                    }
+                )+
+                status
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(HealthStatus);
+
+    impl HealthCheck for Fixed {
+        fn check(&mut self) -> HealthStatus {
+            self.0
+        }
+    }
+
+    #[test]
+    fn combined_checks() {
+        use HealthStatus::*;
+
+        assert_eq!((Fixed(Passed), Fixed(Passed)).check(), Passed);
+        assert_eq!((Fixed(Passed), Fixed(Pending)).check(), Pending);
+        assert_eq!(
+            (
+                Fixed(Pending),
+                Fixed(Failed("sensor")),
+                Fixed(Failed("uart"))
+            )
+                .check(),
+            Failed("sensor")
+        );
+    }
+}
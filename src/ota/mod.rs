@@ -31,14 +31,20 @@
 //! - CBOR deserializer
 
 pub mod agent;
+pub mod backoff;
 pub mod builder;
 pub mod config;
 pub mod control_interface;
 pub mod data_interface;
 pub mod encoding;
 pub mod error;
+pub mod health;
+pub mod observer;
 pub mod pal;
+pub mod pipeline;
+pub mod policy;
 pub mod state;
+pub(crate) mod throttle;
 
 #[cfg(feature = "ota_mqtt_data")]
 pub use data_interface::mqtt::{Encoding, Topic};
@@ -0,0 +1,64 @@
+//! Structured progress events of the OTA agent, for application telemetry
+//! such as a progress UI or custom metrics.
+//!
+//! Unlike [`OtaPal::complete_callback`](super::pal::OtaPal::complete_callback),
+//! the observer cannot influence the update, and is purely informational.
+
+use super::state::States;
+
+/// Progress event emitted by the OTA agent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaObserverEvent<'a> {
+    /// A job document was accepted and an interface was selected to download
+    /// it.
+    JobAccepted { job_name: &'a str },
+    /// The file transfer was initialized and the first blocks are about to be
+    /// requested.
+    FileStarted {
+        file_id: u8,
+        file_size: usize,
+        total_blocks: usize,
+    },
+    /// A new block was written to the file, with `received` out of `total`
+    /// blocks received so far.
+    BlockReceived {
+        block_id: usize,
+        received: usize,
+        total: usize,
+    },
+    /// A block that was already received was received again, and discarded.
+    DuplicateBlock { block_id: usize },
+    /// A request was sent again, after `attempt - 1` requests went
+    /// unanswered.
+    RequestRetry { attempt: u8 },
+    /// Too many requests went unanswered, and the update is aborted.
+    MomentumExhausted,
+    /// The signature of the completed file was checked.
+    SignatureResult { valid: bool },
+    /// The state machine transitioned between two states.
+    StateChanged { from: States, to: States },
+}
+
+/// Receives [`OtaObserverEvent`]s from the OTA agent, configured with
+/// [`OtaAgentBuilder::with_observer`](super::builder::OtaAgentBuilder::with_observer).
+pub trait OtaObserver {
+    fn on_event(&mut self, event: &OtaObserverEvent<'_>);
+}
+
+/// Observer discarding all events, used unless another observer is
+/// configured.
+pub struct NoObserver;
+
+impl OtaObserver for NoObserver {
+    fn on_event(&mut self, _event: &OtaObserverEvent<'_>) {}
+}
+
+impl<F> OtaObserver for F
+where
+    F: FnMut(&OtaObserverEvent<'_>),
+{
+    fn on_event(&mut self, event: &OtaObserverEvent<'_>) {
+        self(event)
+    }
+}
@@ -0,0 +1,426 @@
+//! Dual-bank (A/B) [`OtaPal`] for NOR flash, receiving updates into the bank
+//! that isn't running, for [MCUboot] in the direct-XIP mode with revert
+//! support (`MCUBOOT_DIRECT_XIP` and `MCUBOOT_DIRECT_XIP_REVERT`).
+//!
+//! Each bank holds an image linked to execute in place from it, followed by
+//! the image trailer at the end of the bank, in the same layout as for the
+//! [`McubootPal`](super::mcuboot::McubootPal). On every boot, MCUboot boots
+//! the bank with the highest image version among:
+//!
+//! - confirmed images, with the trailer magic and `image_ok` set,
+//! - test images, with the trailer magic set but neither `image_ok` nor
+//!   `copy_done`, for which MCUboot sets `copy_done` before booting them.
+//!
+//! Any other image, eg. a test image that wasn't confirmed before the next
+//! reset, is erased by MCUboot, reverting to the image of the other bank.
+//! Received images are marked as test images on activation, and confirmed
+//! once they are accepted in the self test phase. The initial image must be
+//! confirmed, eg. signed with `imgtool sign --confirm`.
+//!
+//! [MCUboot]: https://docs.mcuboot.com/design.html#direct-xip
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{
+    mcuboot::{
+        confirm, erase_trailer, read_trailer, read_version, write_magic, Magic, MAX_ALIGN,
+        SWAP_INFO_OFFSET,
+    },
+    ImageReader, ImageState, OtaPal, OtaPalError, PalErrorCode, PalImageState, Version,
+};
+use crate::ota::encoding::FileContext;
+
+/// Flash region of an image bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Slot {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Slot {
+    /// Erase the sectors holding the first `len` bytes of the slot.
+    pub(super) fn erase<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        len: usize,
+    ) -> Result<(), OtaPalError<F::Error>> {
+        let len = (len + F::ERASE_SIZE - 1) / F::ERASE_SIZE * F::ERASE_SIZE;
+        flash.erase(self.offset, self.offset + len as u32)?;
+        Ok(())
+    }
+
+    /// Write `data` at `offset` in the slot, padding the last write with the
+    /// erased value. `offset` must be aligned to the write size of the flash,
+    /// which may not exceed [`MAX_ALIGN`].
+    pub(super) fn write<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, OtaPalError<F::Error>> {
+        if offset + data.len() > self.size as usize {
+            return Err(OtaPalError::FileTooLarge);
+        }
+        if offset % F::WRITE_SIZE != 0 {
+            return Err(OtaPalError::FileWriteFailed);
+        }
+
+        let offset = self.offset + offset as u32;
+        let aligned = data.len() - data.len() % F::WRITE_SIZE;
+        flash.write(offset, &data[..aligned])?;
+
+        let tail = &data[aligned..];
+        if !tail.is_empty() {
+            let mut buf = [0xFF; MAX_ALIGN];
+            buf[..tail.len()].copy_from_slice(tail);
+            flash.write(offset + aligned as u32, &buf[..F::WRITE_SIZE])?;
+        }
+        Ok(data.len())
+    }
+}
+
+/// [`OtaPal`] writing updates to the bank the device isn't running from, and
+/// reporting the version of the image header of the running bank as the
+/// active firmware version.
+///
+/// A received image is only booted if its version is higher than the version
+/// of the running image, and if MCUboot validates its signature.
+pub struct DualBankPal<F> {
+    flash: F,
+    banks: [Slot; 2],
+    running: usize,
+    version: Option<Version>,
+    reset: fn(),
+}
+
+impl<F: NorFlash> DualBankPal<F> {
+    /// Create a PAL for the image `banks` A and B of `flash`, finding the
+    /// running bank as selected by MCUboot. `reset` resets the device.
+    pub fn new(mut flash: F, banks: [Slot; 2], reset: fn()) -> Result<Self, OtaPalError<F::Error>> {
+        assert!(F::WRITE_SIZE <= MAX_ALIGN && F::READ_SIZE <= MAX_ALIGN);
+
+        let mut running = 0;
+        let mut version: Option<Version> = None;
+        for (bank, slot) in banks.iter().enumerate() {
+            // Images not yet tested were not booted
+            let trailer = read_trailer(&mut flash, *slot)?;
+            if trailer.magic != Magic::Good || !(trailer.image_ok || trailer.copy_done) {
+                continue;
+            }
+
+            if let Some(bank_version) = read_version(&mut flash, *slot)? {
+                if version.as_ref().map_or(true, |v| bank_version > *v) {
+                    running = bank;
+                    version = Some(bank_version);
+                }
+            }
+        }
+        if version.is_none() {
+            warn!("No booted MCUboot image in either bank");
+        }
+
+        Ok(Self {
+            flash,
+            banks,
+            running,
+            version,
+            reset,
+        })
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Bank the running image was booted from.
+    pub fn running_bank(&self) -> usize {
+        self.running
+    }
+
+    /// Slot the update is received into.
+    fn target(&self) -> Slot {
+        self.banks[1 - self.running]
+    }
+}
+
+impl<F> OtaPal for DualBankPal<F>
+where
+    F: NorFlash,
+    F::Error: PalErrorCode,
+{
+    type Error = F::Error;
+
+    fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        let slot = self.target();
+        if file.filesize + SWAP_INFO_OFFSET > slot.size as usize {
+            return Err(OtaPalError::FileTooLarge);
+        }
+
+        // MCUboot may not try the previous update while it is erased
+        erase_trailer(&mut self.flash, slot)?;
+        slot.erase(&mut self.flash, file.filesize)
+    }
+
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        let trailer = read_trailer(&mut self.flash, self.banks[self.running])?;
+        Ok(match trailer.magic {
+            Magic::Good if !trailer.image_ok => PalImageState::PendingCommit,
+            Magic::Good | Magic::Unset => PalImageState::Valid,
+            Magic::Bad => PalImageState::Invalid,
+        })
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        let running = self.banks[self.running];
+        let in_test = read_trailer(&mut self.flash, running)?.in_test();
+        match image_state {
+            ImageState::Testing(_) if in_test => Ok(()),
+            ImageState::Testing(_) | ImageState::Unknown => Err(OtaPalError::BadImageState),
+            ImageState::Accepted => confirm(&mut self.flash, running),
+            // A test image is reverted by MCUboot on the next reset
+            ImageState::Rejected(_) | ImageState::Aborted(_) if in_test => Ok(()),
+            // An update not booted yet is canceled, leaving confirmed images
+            ImageState::Rejected(_) | ImageState::Aborted(_) => {
+                let target = self.target();
+                if read_trailer(&mut self.flash, target)?.in_test() {
+                    erase_trailer(&mut self.flash, target)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        // MCUboot tests the received image on the next boot
+        let target = self.target();
+        write_magic(&mut self.flash, target)?;
+        self.reset_device()
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        (self.reset)();
+        Ok(())
+    }
+
+    fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        _file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        self.target()
+            .write(&mut self.flash, block_offset, block_payload)
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        self.version.clone().ok_or(OtaPalError::BadImageState)
+    }
+}
+
+impl<F> ImageReader for DualBankPal<F>
+where
+    F: NorFlash,
+    F::Error: PalErrorCode,
+{
+    fn read_active_image(
+        &mut self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        let slot = self.banks[self.running];
+        if offset + buf.len() > slot.size as usize {
+            return Err(OtaPalError::BadFileHandle);
+        }
+        if offset % F::READ_SIZE != 0 || buf.len() % F::READ_SIZE != 0 {
+            return Err(OtaPalError::Unsupported);
+        }
+        self.flash.read(slot.offset + offset as u32, buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{
+        config::Config,
+        pal::mcuboot::{
+            tests::image_header, write_flag, BOOT_FLAG_SET, COPY_DONE_OFFSET, IMAGE_OK_OFFSET,
+        },
+        state::ImageStateReason,
+        test::{mock::MemFlash, test_file_ctx, DUAL_BANK_SLOTS as BANKS},
+    };
+
+    fn reset() {}
+
+    /// Restart the device, running MCUboot in the direct-XIP revert mode on
+    /// `flash`
+    fn boot(mut flash: MemFlash) -> DualBankPal<MemFlash> {
+        let mut selected: Option<(Slot, Version)> = None;
+        for slot in BANKS {
+            let version = match read_version(&mut flash, slot).unwrap() {
+                Some(version) => version,
+                None => continue,
+            };
+
+            let trailer = read_trailer(&mut flash, slot).unwrap();
+            if trailer.magic != Magic::Good || (!trailer.image_ok && trailer.copy_done) {
+                flash.erase(slot.offset, slot.offset + slot.size).unwrap();
+            } else if selected.as_ref().map_or(true, |(_, v)| version > *v) {
+                selected = Some((slot, version));
+            }
+        }
+
+        if let Some((slot, _)) = selected {
+            if !read_trailer(&mut flash, slot).unwrap().image_ok {
+                write_flag(&mut flash, slot, COPY_DONE_OFFSET, BOOT_FLAG_SET).unwrap();
+            }
+        }
+        DualBankPal::new(flash, BANKS, reset).unwrap()
+    }
+
+    /// Device running the confirmed image `1.0.0` from bank A
+    fn new_pal() -> DualBankPal<MemFlash> {
+        let mut flash = MemFlash::new(0x1000);
+        flash.write(0, &image_header(1, 0, 0, 0)).unwrap();
+        write_magic(&mut flash, BANKS[0]).unwrap();
+        write_flag(&mut flash, BANKS[0], IMAGE_OK_OFFSET, BOOT_FLAG_SET).unwrap();
+        boot(flash)
+    }
+
+    /// Receive and activate the image `1.minor.0`
+    fn update(pal: &mut DualBankPal<MemFlash>, minor: u8) {
+        let header = image_header(1, minor, 0, 0);
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = header.len() + 10;
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, &header).unwrap();
+        pal.write_block(&file_ctx, header.len(), b"abcdefghij")
+            .unwrap();
+        pal.close_file(&file_ctx).unwrap();
+        pal.activate_new_image().unwrap();
+    }
+
+    #[test]
+    fn receive_and_commit() {
+        let mut pal = new_pal();
+        assert_eq!(pal.running_bank(), 0);
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 0, 0)
+        );
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        update(&mut pal, 1);
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        let mut pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 1);
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 1, 0)
+        );
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::PendingCommit
+        );
+
+        let mut buf = [0; 10];
+        pal.read_active_image(32, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcdefghij");
+
+        pal.set_platform_image_state(ImageState::Testing(ImageStateReason::VersionCheck))
+            .unwrap();
+
+        pal.set_platform_image_state(ImageState::Accepted).unwrap();
+        let mut pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 1);
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        // The next update is received into bank A
+        update(&mut pal, 2);
+        let pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 0);
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 2, 0)
+        );
+    }
+
+    #[test]
+    fn roll_back() {
+        let mut pal = new_pal();
+
+        // Rejected before activation
+        update(&mut pal, 1);
+        pal.set_platform_image_state(ImageState::Rejected(ImageStateReason::VersionCheck))
+            .unwrap();
+        let mut pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 0);
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        // Rejected in the self test phase
+        update(&mut pal, 1);
+        let mut pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 1);
+        pal.set_platform_image_state(ImageState::Rejected(ImageStateReason::VersionCheck))
+            .unwrap();
+        let mut pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 0);
+
+        // Not accepted before the next reset
+        update(&mut pal, 1);
+        let mut pal = boot(boot(pal.into_inner()).into_inner());
+        assert_eq!(pal.running_bank(), 0);
+
+        // Older images are not booted
+        update(&mut pal, 0);
+        let pal = boot(pal.into_inner());
+        assert_eq!(pal.running_bank(), 0);
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn file_too_large() {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = 0x800 - SWAP_INFO_OFFSET + 1;
+
+        let mut pal = new_pal();
+        assert!(matches!(
+            pal.create_file_for_rx(&file_ctx),
+            Err(OtaPalError::FileTooLarge)
+        ));
+        assert!(matches!(
+            pal.write_block(&file_ctx, 0x800, b"a"),
+            Err(OtaPalError::FileTooLarge)
+        ));
+    }
+}
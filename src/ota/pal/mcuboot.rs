@@ -0,0 +1,511 @@
+//! [MCUboot] compatible [`OtaPal`], receiving updates into the secondary slot
+//! and requesting the bootloader to swap them in through the image trailer.
+//!
+//! Received images are marked as "test" images on activation, such that
+//! MCUboot reverts them on the next reset unless they are confirmed, which is
+//! done once the image is accepted in the self test phase. The trailer layout
+//! assumes the default `BOOT_MAX_ALIGN` of [`MAX_ALIGN`] bytes.
+//!
+//! [MCUboot]: https://docs.mcuboot.com/design.html
+
+use core::fmt::Write;
+
+use embedded_storage::nor_flash::NorFlash;
+
+use super::{
+    dual_bank::Slot, ImageReader, ImageState, OtaPal, OtaPalError, PalErrorCode, PalImageState,
+    Version,
+};
+use crate::ota::encoding::FileContext;
+
+/// Alignment of the image trailer fields, in bytes.
+pub const MAX_ALIGN: usize = 8;
+
+pub(super) const IMAGE_MAGIC: u32 = 0x96F3_B83D;
+pub(super) const IMAGE_HEADER_LEN: usize = 32;
+
+const BOOT_MAGIC: [u8; 16] = [
+    0x77, 0xC2, 0x95, 0xF3, 0x60, 0xD2, 0xEF, 0x7F, 0x35, 0x52, 0x50, 0x0F, 0x2C, 0xB6, 0x79, 0x80,
+];
+pub(super) const BOOT_FLAG_SET: u8 = 0x01;
+const BOOT_SWAP_TYPE_TEST: u8 = 2;
+/// Swap states recorded per sector in the swap status area
+const BOOT_STATUS_STATE_COUNT: usize = 3;
+
+/// Offsets of the trailer fields, from the end of the slot
+pub(super) const MAGIC_OFFSET: usize = BOOT_MAGIC.len();
+pub(super) const IMAGE_OK_OFFSET: usize = MAGIC_OFFSET + MAX_ALIGN;
+pub(super) const COPY_DONE_OFFSET: usize = IMAGE_OK_OFFSET + MAX_ALIGN;
+pub(super) const SWAP_INFO_OFFSET: usize = COPY_DONE_OFFSET + MAX_ALIGN;
+
+/// Length of the swap trailer at the end of a slot, for MCUboot built with
+/// `MCUBOOT_MAX_IMG_SECTORS` set to `max_img_sectors`. Besides the fields up
+/// to the swap info, it holds the swap size and the swap status area of every
+/// sector.
+pub const fn trailer_len(max_img_sectors: usize) -> usize {
+    max_img_sectors * BOOT_STATUS_STATE_COUNT * MAX_ALIGN + SWAP_INFO_OFFSET + MAX_ALIGN
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Magic {
+    Good,
+    Unset,
+    Bad,
+}
+
+/// Image trailer of a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Trailer {
+    pub magic: Magic,
+    pub image_ok: bool,
+    pub copy_done: bool,
+}
+
+impl Trailer {
+    /// Whether the slot holds a test image not yet confirmed
+    pub fn in_test(&self) -> bool {
+        self.magic == Magic::Good && !self.image_ok
+    }
+}
+
+/// Read the version of the image header at the start of `slot`, `None` if the
+/// slot holds no valid image header.
+pub(super) fn read_version<F: NorFlash>(
+    flash: &mut F,
+    slot: Slot,
+) -> Result<Option<Version>, OtaPalError<F::Error>> {
+    let mut header = [0; IMAGE_HEADER_LEN];
+    flash.read(slot.offset, &mut header)?;
+    Ok(parse_version(&header))
+}
+
+pub(super) fn read_trailer<F: NorFlash>(
+    flash: &mut F,
+    slot: Slot,
+) -> Result<Trailer, OtaPalError<F::Error>> {
+    let end = slot.offset + slot.size;
+
+    let mut magic = [0; BOOT_MAGIC.len()];
+    flash.read(end - MAGIC_OFFSET as u32, &mut magic)?;
+    let mut flag = [0; MAX_ALIGN];
+    flash.read(end - IMAGE_OK_OFFSET as u32, &mut flag)?;
+    let image_ok = flag[0] == BOOT_FLAG_SET;
+    flash.read(end - COPY_DONE_OFFSET as u32, &mut flag)?;
+    let copy_done = flag[0] == BOOT_FLAG_SET;
+
+    let magic = if magic == BOOT_MAGIC {
+        Magic::Good
+    } else if magic.iter().all(|b| *b == 0xFF) {
+        Magic::Unset
+    } else {
+        Magic::Bad
+    };
+
+    Ok(Trailer {
+        magic,
+        image_ok,
+        copy_done,
+    })
+}
+
+/// Write the trailer magic of `slot`, marking its image for a test boot
+pub(super) fn write_magic<F: NorFlash>(
+    flash: &mut F,
+    slot: Slot,
+) -> Result<(), OtaPalError<F::Error>> {
+    slot.write(flash, slot.size as usize - MAGIC_OFFSET, &BOOT_MAGIC)?;
+    Ok(())
+}
+
+/// Write a trailer flag, `offset` bytes from the end of `slot`
+pub(super) fn write_flag<F: NorFlash>(
+    flash: &mut F,
+    slot: Slot,
+    offset: usize,
+    value: u8,
+) -> Result<(), OtaPalError<F::Error>> {
+    slot.write(flash, slot.size as usize - offset, &[value])?;
+    Ok(())
+}
+
+/// Confirm the test image of `slot`, the equivalent of `boot_set_confirmed()`
+pub(super) fn confirm<F: NorFlash>(flash: &mut F, slot: Slot) -> Result<(), OtaPalError<F::Error>> {
+    match read_trailer(flash, slot)? {
+        Trailer {
+            magic: Magic::Bad, ..
+        } => Err(OtaPalError::CommitFailed),
+        trailer if trailer.in_test() => write_flag(flash, slot, IMAGE_OK_OFFSET, BOOT_FLAG_SET),
+        _ => Ok(()),
+    }
+}
+
+/// Erase the sector holding the trailer of `slot`
+pub(super) fn erase_trailer<F: NorFlash>(
+    flash: &mut F,
+    slot: Slot,
+) -> Result<(), OtaPalError<F::Error>> {
+    let end = slot.offset + slot.size;
+    flash.erase(end - F::ERASE_SIZE as u32, end)?;
+    Ok(())
+}
+
+/// Parse the version of an MCUboot image header, `None` if `header` isn't a
+/// valid image header.
+fn parse_version(header: &[u8; IMAGE_HEADER_LEN]) -> Option<Version> {
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if magic != IMAGE_MAGIC {
+        return None;
+    }
+
+    // struct image_version { u8 major; u8 minor; u16 revision; u32 build_num; }
+    let revision = u16::from_le_bytes([header[22], header[23]]);
+    let build_num = u32::from_le_bytes([header[24], header[25], header[26], header[27]]);
+
+    let mut version = Version::new(header[20] as u32, header[21] as u32, revision as u32);
+    if build_num != 0 {
+        version
+            .build
+            .write_fmt(format_args!("{}", build_num))
+            .ok()?;
+    }
+    Some(version)
+}
+
+/// [`OtaPal`] writing updates to the secondary slot of an MCUboot swap
+/// layout on a NOR `flash`, and reporting the version of the image header in
+/// the primary slot as the active firmware version.
+///
+/// Received files must be images signed with `imgtool`, as MCUboot checks
+/// the signature TLV of the image against its own key before swapping it in,
+/// rather than the signature of the job document. An image failing the check
+/// is never swapped in, and the device keeps running the previous image.
+pub struct McubootPal<F> {
+    flash: F,
+    primary: Slot,
+    secondary: Slot,
+    trailer_len: usize,
+    version: Option<Version>,
+    reset: fn(),
+}
+
+impl<F: NorFlash> McubootPal<F> {
+    /// Create a PAL for the `primary` and `secondary` slots of `flash`,
+    /// reading the image header of the primary slot. Images must leave room
+    /// for the swap trailer of MCUboot built with `MCUBOOT_MAX_IMG_SECTORS`
+    /// set to `max_img_sectors`. `reset` resets the device.
+    pub fn new(
+        mut flash: F,
+        primary: Slot,
+        secondary: Slot,
+        max_img_sectors: usize,
+        reset: fn(),
+    ) -> Result<Self, OtaPalError<F::Error>> {
+        assert!(F::WRITE_SIZE <= MAX_ALIGN && F::READ_SIZE <= MAX_ALIGN);
+
+        let version = read_version(&mut flash, primary)?;
+        if version.is_none() {
+            warn!("No MCUboot image header in the primary slot");
+        }
+
+        Ok(Self {
+            flash,
+            primary,
+            secondary,
+            trailer_len: trailer_len(max_img_sectors),
+            version,
+            reset,
+        })
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F> OtaPal for McubootPal<F>
+where
+    F: NorFlash,
+    F::Error: PalErrorCode,
+{
+    type Error = F::Error;
+
+    fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        // Equivalent of `boot_set_pending(0)`, requesting a test swap
+        write_magic(&mut self.flash, self.secondary)?;
+        write_flag(
+            &mut self.flash,
+            self.secondary,
+            SWAP_INFO_OFFSET,
+            BOOT_SWAP_TYPE_TEST,
+        )?;
+        self.reset_device()
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        if file.filesize + self.trailer_len > self.secondary.size as usize {
+            return Err(OtaPalError::FileTooLarge);
+        }
+
+        self.secondary.erase(&mut self.flash, file.filesize)?;
+        // Cancel a pending swap
+        erase_trailer(&mut self.flash, self.secondary)
+    }
+
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        let trailer = read_trailer(&mut self.flash, self.primary)?;
+        Ok(match trailer.magic {
+            Magic::Good if !trailer.image_ok => PalImageState::PendingCommit,
+            Magic::Good | Magic::Unset => PalImageState::Valid,
+            Magic::Bad => PalImageState::Invalid,
+        })
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        match image_state {
+            ImageState::Testing(_) if read_trailer(&mut self.flash, self.primary)?.in_test() => {
+                Ok(())
+            }
+            ImageState::Testing(_) | ImageState::Unknown => Err(OtaPalError::BadImageState),
+            ImageState::Accepted => confirm(&mut self.flash, self.primary),
+            // A test image is reverted by MCUboot on the next reset, while a
+            // swap not yet performed is canceled
+            ImageState::Rejected(_) | ImageState::Aborted(_) => {
+                if read_trailer(&mut self.flash, self.secondary)?.magic != Magic::Unset {
+                    erase_trailer(&mut self.flash, self.secondary)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        (self.reset)();
+        Ok(())
+    }
+
+    fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        _file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        self.secondary
+            .write(&mut self.flash, block_offset, block_payload)
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        self.version.clone().ok_or(OtaPalError::BadImageState)
+    }
+}
+
+impl<F> ImageReader for McubootPal<F>
+where
+    F: NorFlash,
+    F::Error: PalErrorCode,
+{
+    fn read_active_image(
+        &mut self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        if offset + buf.len() > self.primary.size as usize {
+            return Err(OtaPalError::BadFileHandle);
+        }
+        if offset % F::READ_SIZE != 0 || buf.len() % F::READ_SIZE != 0 {
+            return Err(OtaPalError::Unsupported);
+        }
+        self.flash.read(self.primary.offset + offset as u32, buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::ota::{
+        config::Config,
+        state::ImageStateReason,
+        test::{mock::MemFlash, test_file_ctx},
+    };
+
+    const PRIMARY: Slot = Slot {
+        offset: 0,
+        size: 0x1000,
+    };
+    const SECONDARY: Slot = Slot {
+        offset: 0x1000,
+        size: 0x1000,
+    };
+
+    /// Sectors of the slots
+    const MAX_IMG_SECTORS: usize = 4;
+
+    fn reset() {}
+
+    /// Image header of version `major.minor.revision+build_num`
+    pub fn image_header(
+        major: u8,
+        minor: u8,
+        revision: u16,
+        build_num: u32,
+    ) -> [u8; IMAGE_HEADER_LEN] {
+        let mut header = [0; IMAGE_HEADER_LEN];
+        header[0..4].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        header[8..10].copy_from_slice(&(IMAGE_HEADER_LEN as u16).to_le_bytes());
+        header[20] = major;
+        header[21] = minor;
+        header[22..24].copy_from_slice(&revision.to_le_bytes());
+        header[24..28].copy_from_slice(&build_num.to_le_bytes());
+        header
+    }
+
+    /// Perform a test swap, as MCUboot does when booting
+    fn swap(mut flash: MemFlash) -> McubootPal<MemFlash> {
+        let (primary, secondary) = flash.data.split_at_mut(0x1000);
+        if secondary[0x1000 - MAGIC_OFFSET..] == BOOT_MAGIC {
+            primary.copy_from_slice(secondary);
+            primary[0x1000 - IMAGE_OK_OFFSET..0x1000 - MAGIC_OFFSET].fill(0xFF);
+            primary[0x1000 - COPY_DONE_OFFSET] = BOOT_FLAG_SET;
+            secondary.fill(0xFF);
+        }
+        McubootPal::new(flash, PRIMARY, SECONDARY, MAX_IMG_SECTORS, reset).unwrap()
+    }
+
+    #[test]
+    fn header_version() {
+        let mut flash = MemFlash::new(0x2000);
+        let pal = McubootPal::new(flash, PRIMARY, SECONDARY, MAX_IMG_SECTORS, reset).unwrap();
+        assert!(matches!(
+            pal.get_active_firmware_version(),
+            Err(OtaPalError::BadImageState)
+        ));
+
+        flash = pal.into_inner();
+        flash.data[..IMAGE_HEADER_LEN].copy_from_slice(&image_header(1, 2, 3, 4));
+        let pal = McubootPal::new(flash, PRIMARY, SECONDARY, MAX_IMG_SECTORS, reset).unwrap();
+
+        let version = pal.get_active_firmware_version().unwrap();
+        assert_eq!(version.to_string::<16>().unwrap().as_str(), "1.2.3+4");
+    }
+
+    #[test]
+    fn test_and_confirm() {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = IMAGE_HEADER_LEN + 2;
+
+        let mut pal = McubootPal::new(
+            MemFlash::new(0x2000),
+            PRIMARY,
+            SECONDARY,
+            MAX_IMG_SECTORS,
+            reset,
+        )
+        .unwrap();
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, &image_header(1, 2, 3, 4))
+            .unwrap();
+        pal.write_block(&file_ctx, IMAGE_HEADER_LEN, b"ab").unwrap();
+        pal.close_file(&file_ctx).unwrap();
+        pal.activate_new_image().unwrap();
+
+        let flash = pal.into_inner();
+        assert_eq!(flash.data[0x2000 - MAGIC_OFFSET..], BOOT_MAGIC);
+        assert_eq!(flash.data[0x2000 - IMAGE_OK_OFFSET], 0xFF);
+        assert_eq!(flash.data[0x2000 - SWAP_INFO_OFFSET], BOOT_SWAP_TYPE_TEST);
+
+        let mut pal = swap(flash);
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 2, 3)
+        );
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::PendingCommit
+        );
+        pal.set_platform_image_state(ImageState::Testing(ImageStateReason::VersionCheck))
+            .unwrap();
+
+        pal.set_platform_image_state(ImageState::Accepted).unwrap();
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+        let flash = pal.into_inner();
+        assert_eq!(flash.data[0x1000 - IMAGE_OK_OFFSET], BOOT_FLAG_SET);
+    }
+
+    #[test]
+    fn reject_pending_swap() {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = IMAGE_HEADER_LEN;
+
+        let mut pal = McubootPal::new(
+            MemFlash::new(0x2000),
+            PRIMARY,
+            SECONDARY,
+            MAX_IMG_SECTORS,
+            reset,
+        )
+        .unwrap();
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, &image_header(1, 2, 3, 4))
+            .unwrap();
+        pal.activate_new_image().unwrap();
+
+        pal.set_platform_image_state(ImageState::Rejected(ImageStateReason::UpdatePolicy))
+            .unwrap();
+        assert!(matches!(
+            pal.set_platform_image_state(ImageState::Testing(ImageStateReason::VersionCheck)),
+            Err(OtaPalError::BadImageState)
+        ));
+
+        // No swap is performed
+        let pal = swap(pal.into_inner());
+        assert!(pal.get_active_firmware_version().is_err());
+    }
+
+    #[test]
+    fn file_too_large() {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = 0x1000 - trailer_len(MAX_IMG_SECTORS) + 1;
+
+        let mut pal = McubootPal::new(
+            MemFlash::new(0x2000),
+            PRIMARY,
+            SECONDARY,
+            MAX_IMG_SECTORS,
+            reset,
+        )
+        .unwrap();
+        assert!(matches!(
+            pal.create_file_for_rx(&file_ctx),
+            Err(OtaPalError::FileTooLarge)
+        ));
+
+        // The swap status area is reserved as well
+        file_ctx.filesize = 0x1000 - SWAP_INFO_OFFSET;
+        assert!(matches!(
+            pal.create_file_for_rx(&file_ctx),
+            Err(OtaPalError::FileTooLarge)
+        ));
+
+        file_ctx.filesize -= trailer_len(MAX_IMG_SECTORS) - SWAP_INFO_OFFSET;
+        pal.create_file_for_rx(&file_ctx).unwrap();
+    }
+}
@@ -0,0 +1,639 @@
+//! Platform abstraction trait for OTA updates
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use super::encoding::FileContext;
+use super::state::ImageStateReason;
+
+pub mod dual_bank;
+pub mod mcuboot;
+pub mod router;
+#[cfg(feature = "std")]
+pub mod std;
+
+#[derive(Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageState<E> {
+    Unknown,
+    Aborted(ImageStateReason<E>),
+    Rejected(ImageStateReason<E>),
+    Accepted,
+    Testing(ImageStateReason<E>),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaPalError<E> {
+    SignatureCheckFailed,
+    FileWriteFailed,
+    FileTooLarge,
+    FileCloseFailed,
+    BadFileHandle,
+    Unsupported,
+    BadImageState,
+    CommitFailed,
+    VersionCheck,
+    /// A [`pipeline`](super::pipeline) stage failed to decode the received
+    /// file.
+    InvalidPipelineData,
+    /// The key of an encrypted file couldn't be unwrapped, or the file failed
+    /// authentication.
+    DecryptionFailed,
+    Custom(E),
+}
+
+/// Error of a platform, reporting its own code in the job status details when
+/// an [`OtaPal`] fails with [`OtaPalError::Custom`].
+pub trait PalErrorCode {
+    /// Platform specific error code, eg. an `errno` or a flash driver status.
+    fn code(&self) -> u32 {
+        0xFF
+    }
+}
+
+impl PalErrorCode for () {}
+
+impl PalErrorCode for core::convert::Infallible {}
+
+impl PalErrorCode for embedded_storage::nor_flash::NorFlashErrorKind {}
+
+#[cfg(feature = "std")]
+impl PalErrorCode for ::std::io::Error {
+    fn code(&self) -> u32 {
+        self.raw_os_error().map_or(0xFF, |errno| errno as u32)
+    }
+}
+
+impl<E: PalErrorCode> OtaPalError<E> {
+    /// Numeric error code, reported in the job status details on failure.
+    ///
+    /// Codes shared with FreeRTOS OTA match its `OtaPalMainStatus_t` values,
+    /// while errors without a FreeRTOS counterpart use codes from `0xF0`.
+    /// Platform errors report their own [`PalErrorCode::code`].
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::BadFileHandle => 0xE2,
+            Self::SignatureCheckFailed => 0xE3,
+            Self::FileTooLarge => 0xE5,
+            Self::BadImageState => 0xE8,
+            Self::CommitFailed => 0xEB,
+            Self::FileCloseFailed => 0xEE,
+            Self::FileWriteFailed => 0xF0,
+            Self::Unsupported => 0xF1,
+            Self::VersionCheck => 0xF2,
+            Self::InvalidPipelineData => 0xF3,
+            Self::DecryptionFailed => 0xF4,
+            Self::Custom(e) => e.code(),
+        }
+    }
+}
+
+impl<E> From<E> for OtaPalError<E> {
+    fn from(value: E) -> Self {
+        Self::Custom(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PalImageState {
+    /// the new firmware image is in the self test phase
+    PendingCommit,
+    /// the new firmware image is already committed
+    Valid,
+    /// the new firmware image is invalid or non-existent
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OtaEvent {
+    /// OTA update is authenticated and ready to activate.
+    Activate,
+    /// OTA update failed. Unable to use this update.
+    Fail,
+    /// OTA job is now ready for optional user self tests.
+    StartTest,
+
+    SelfTestFailed,
+
+    UpdateComplete,
+}
+
+/// Maximum length of the pre-release part of a [`Version`].
+pub const MAX_PRE_RELEASE_LEN: usize = 31;
+/// Maximum length of a formatted [`Version`] without build metadata, as
+/// stored in the `updated_by` status detail.
+pub const MAX_VERSION_LEN: usize = 3 * 10 + 2 + 1 + MAX_PRE_RELEASE_LEN;
+/// Maximum length of the build metadata part of a [`Version`].
+pub const MAX_BUILD_LEN: usize = 32;
+
+/// A [SemVer 2.0](https://semver.org) version, eg. `1.2.3-rc.1+build.5`.
+///
+/// Versions are compared by precedence, so the build metadata is ignored by
+/// both `Eq` and `Ord`.
+#[derive(Debug, Clone)]
+pub struct Version {
+    major: u32,
+    minor: u32,
+    patch: u32,
+    pre: heapless::String<MAX_PRE_RELEASE_LEN>,
+    build: heapless::String<MAX_BUILD_LEN>,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Version {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "{=u32}.{=u32}.{=u32}",
+            self.major,
+            self.minor,
+            self.patch
+        );
+        if !self.pre.is_empty() {
+            defmt::write!(fmt, "-{=str}", self.pre.as_str());
+        }
+        if !self.build.is_empty() {
+            defmt::write!(fmt, "+{=str}", self.build.as_str());
+        }
+    }
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, build) = match s.split_once('+') {
+            Some((s, build)) => (s, Some(build)),
+            None => (s, None),
+        };
+        let (s, pre) = match s.split_once('-') {
+            Some((s, pre)) => (s, Some(pre)),
+            None => (s, None),
+        };
+
+        let mut iter = s.split('.');
+        let mut version = Self::new(
+            parse_numeric(iter.next().ok_or(())?)?,
+            parse_numeric(iter.next().ok_or(())?)?,
+            parse_numeric(iter.next().ok_or(())?)?,
+        );
+        if iter.next().is_some() {
+            return Err(());
+        }
+
+        if let Some(pre) = pre {
+            for identifier in pre.split('.') {
+                if identifier.bytes().all(|b| b.is_ascii_digit()) {
+                    parse_numeric(identifier)?;
+                } else {
+                    check_identifier(identifier)?;
+                }
+            }
+            version.pre.push_str(pre)?;
+        }
+
+        if let Some(build) = build {
+            build.split('.').try_for_each(check_identifier)?;
+            version.build.push_str(build)?;
+        }
+
+        Ok(version)
+    }
+}
+
+/// Parse a numeric identifier, which may not have leading zeroes.
+fn parse_numeric(s: &str) -> Result<u32, ()> {
+    if s.is_empty() || (s.len() > 1 && s.starts_with('0')) {
+        return Err(());
+    }
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(());
+    }
+    s.parse().map_err(drop)
+}
+
+/// Check that `s` is a non-empty identifier of `[0-9A-Za-z-]`.
+fn check_identifier(s: &str) -> Result<(), ()> {
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: heapless::String::new(),
+            build: heapless::String::new(),
+        }
+    }
+
+    pub fn major(&self) -> u32 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u32 {
+        self.minor
+    }
+
+    pub fn patch(&self) -> u32 {
+        self.patch
+    }
+
+    /// The pre-release identifiers, eg. `rc.1`, or an empty string for a
+    /// release version.
+    pub fn pre_release(&self) -> &str {
+        self.pre.as_str()
+    }
+
+    /// The build metadata, eg. `build.5`, or an empty string.
+    pub fn build(&self) -> &str {
+        self.build.as_str()
+    }
+
+    /// This version without build metadata, which does not affect precedence
+    /// and thus can be left out when storing the version.
+    #[must_use]
+    pub fn without_build(&self) -> Self {
+        Self {
+            build: heapless::String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Format the version, returning `None` if it does not fit in `L` bytes.
+    pub fn to_string<const L: usize>(&self) -> Option<heapless::String<L>> {
+        let mut s = heapless::String::new();
+        s.write_fmt(format_args!("{}", self)).ok()?;
+        Some(s)
+    }
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre)?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::cmp::PartialEq for Version {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == core::cmp::Ordering::Equal
+    }
+}
+
+impl core::cmp::Eq for Version {}
+
+impl core::cmp::PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl core::cmp::Ord for Version {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        use core::cmp::Ordering;
+
+        match (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch)) {
+            Ordering::Equal => {}
+            r => return r,
+        }
+
+        // A pre-release version has lower precedence than the release
+        match (self.pre.is_empty(), other.pre.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            (false, false) => {}
+        }
+
+        let mut lhs = self.pre.split('.');
+        let mut rhs = other.pre.split('.');
+        loop {
+            let (a, b) = match (lhs.next(), rhs.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(a), Some(b)) => (a, b),
+            };
+
+            // Numeric identifiers are compared numerically, and have lower
+            // precedence than alphanumeric identifiers
+            let ordering = match (a.parse::<u32>(), b.parse::<u32>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => a.cmp(b),
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+}
+
+/// Platform abstraction layer for OTA jobs
+pub trait OtaPal {
+    type Error: PalErrorCode;
+
+    /// OTA abort.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to override the behavior of how a job is
+    /// aborted.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Activate the newest MCU image received via OTA.
+    ///
+    /// This function shall do whatever is necessary to activate the newest MCU
+    /// firmware received via OTA. It is typically just a reset of the device.
+    ///
+    /// **note** This function SHOULD not return. If it does, the platform
+    /// doesn't support an automatic reset or an error occurred.
+    ///
+    /// **return**: The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.reset_device()
+    }
+
+    /// OTA create file to store received data.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to override the behavior of how a new file
+    /// is created.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Get the state of the OTA update image.
+    ///
+    /// We read this at OTA_Init time and when the latest OTA job reports itself
+    /// in self test. If the update image is in the "pending commit" state, we
+    /// start a self test timer to assure that we can successfully connect to
+    /// the OTA services and accept the OTA update image within a reasonable
+    /// amount of time (user configurable). If we don't satisfy that
+    /// requirement, we assume there is something wrong with the firmware and
+    /// automatically reset the device, causing it to roll back to the
+    /// previously known working code.
+    ///
+    /// If the update image state is not in "pending commit," the self test
+    /// timer is not started.
+    ///
+    /// **return** An [`PalImageState`].
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>>;
+
+    /// Attempt to set the state of the OTA update image.
+    ///
+    /// Do whatever is required by the platform to Accept/Reject the OTA update
+    /// image (or bundle). Refer to the PAL implementation to determine what
+    /// happens on your platform.
+    ///
+    /// - `state`: [`ImageState`] The desired state of the OTA update image.
+    ///
+    /// **return** The [`OtaPalError`] error code combined with the MCU specific
+    /// error code.
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Reset the device.
+    ///
+    /// This function shall reset the MCU and cause a reboot of the system.
+    ///
+    /// **note** This function SHOULD not return. If it does, the platform
+    /// doesn't support an automatic reset or an error occurred.
+    ///
+    /// **return** The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Authenticate and close the underlying receive file in the specified OTA
+    /// context.
+    ///
+    /// If the signature verification fails, file close should still be
+    /// attempted.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    ///
+    /// **return** The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Write a block of data to the specified file at the given offset.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted.
+    /// - `block_offset`: Byte offset to write to from the beginning of the
+    ///   file.
+    /// - `block_payload`: Byte array of data to write.
+    ///
+    /// **return** The number of bytes written on a success, or a negative error
+    /// code from the platform abstraction layer.
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>>;
+
+    /// OTA update complete.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to notify the main application when the OTA
+    /// update job is complete. Typically, it is used to reset the device after
+    /// a successful update by calling `OtaPal::activate_new_image()` and may
+    /// also be used to kick off user specified self tests during the Self Test
+    /// phase. If the user does not supply a custom callback function, a default
+    /// callback handler is used that automatically calls
+    /// `OtaPal::activate_new_image()` after a successful update.
+    ///
+    /// **note**:
+    ///
+    /// The callback function is called with one of the following arguments:
+    ///
+    /// - `OtaEvent::Activate`      OTA update is authenticated and ready to
+    ///   activate.
+    /// - `OtaEvent::Fail`          OTA update failed. Unable to use this
+    ///   update.
+    /// - `OtaEvent::StartTest`     OTA job is now ready for optional user self
+    ///   tests.
+    ///
+    /// When `OtaEvent::Activate` is received, the job status details have been
+    /// updated with the state as ready for Self Test. After reboot, the new
+    /// firmware will (normally) be notified that it is in the Self Test phase
+    /// via the callback and the application may then optionally run its own
+    /// tests before committing the new image.
+    ///
+    /// If the callback function is called with a result of `OtaEvent::Fail`,
+    /// the OTA update job has failed in some way and should be rejected.
+    ///
+    /// - `event` [`OtaEvent`] An OTA update event from the `OtaEvent` enum.
+    fn complete_callback(&mut self, event: OtaEvent) -> Result<(), OtaPalError<Self::Error>> {
+        match event {
+            OtaEvent::Activate => self.activate_new_image(),
+            OtaEvent::Fail | OtaEvent::UpdateComplete => {
+                // Nothing special to do. The OTA agent handles it
+                Ok(())
+            }
+            OtaEvent::StartTest => {
+                // Nothing special to do. The OTA agent accepts the image once
+                // the configured health checks passed
+                Ok(())
+            }
+            OtaEvent::SelfTestFailed => {
+                // Requires manual activation of previous image as self-test for
+                // new image downloaded failed.*/
+                error!("Self-test failed, shutting down OTA Agent.");
+
+                // Shutdown OTA Agent, if it is required that the unsubscribe operations are not
+                // performed while shutting down please set the second parameter to 0 instead of 1.
+                Ok(())
+            }
+        }
+    }
+
+    ///
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>>;
+
+    /// Whether the block of `file` at `block_offset` can be written now, eg.
+    /// as a [`pipeline`](super::pipeline) stage requires the file to be
+    /// written in order.
+    ///
+    /// Blocks that can't be written are discarded by the OTA agent, and
+    /// requested again.
+    fn accepts_block(&self, _file: &FileContext, _block_offset: usize) -> bool {
+        true
+    }
+
+    /// Notify the PAL of a `file` of the job received before the OTA agent
+    /// was restarted, eg. by a reset, as the job is resumed from its status
+    /// details at a later file, or in the self test phase.
+    fn file_received(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+}
+
+/// Read access to the active firmware image, used as the base image of
+/// [`DeltaPal`](super::pipeline::delta::DeltaPal) updates.
+pub trait ImageReader: OtaPal {
+    /// Read `buf.len()` bytes of the active firmware image, starting at
+    /// `offset`.
+    fn read_active_image(
+        &mut self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), OtaPalError<Self::Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version() {
+        let version = Version::from_str("1.22.333-rc.1+build.5").unwrap();
+        assert_eq!(
+            (version.major(), version.minor(), version.patch()),
+            (1, 22, 333)
+        );
+        assert_eq!(version.pre_release(), "rc.1");
+        assert_eq!(version.build(), "build.5");
+        assert_eq!(
+            version.to_string::<32>().unwrap().as_str(),
+            "1.22.333-rc.1+build.5"
+        );
+        assert_eq!(version.to_string::<8>(), None);
+
+        let longest = Version::from_str(
+            "4294967295.4294967295.4294967295-rc.x234567890123456789012345678+build",
+        )
+        .unwrap();
+        assert_eq!(
+            longest
+                .without_build()
+                .to_string::<MAX_VERSION_LEN>()
+                .unwrap()
+                .len(),
+            MAX_VERSION_LEN
+        );
+
+        assert_eq!(
+            Version::from_str("4294967295.0.0"),
+            Ok(Version::new(u32::MAX, 0, 0))
+        );
+        assert_eq!(
+            Version::from_str("1.0.0-alpha-beta.0+001").map(|v| v.pre_release().len()),
+            Ok(12)
+        );
+
+        for invalid in [
+            "",
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.3-",
+            "1.2.3-rc..1",
+            "1.2.3-rc.01",
+            "1.2.3+",
+            "1.2.3+build!",
+            "4294967296.0.0",
+            "v1.2.3",
+        ] {
+            assert_eq!(Version::from_str(invalid), Err(()), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn version_precedence() {
+        // Example from https://semver.org/#spec-item-11
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "2.0.0",
+            "2.1.0",
+            "2.1.1",
+            "10.0.0",
+        ]
+        .map(|v| Version::from_str(v).unwrap());
+
+        for pair in ordered.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+
+        // Build metadata is ignored
+        assert_eq!(
+            Version::from_str("1.0.0+build.1"),
+            Version::from_str("1.0.0+build.2")
+        );
+    }
+}
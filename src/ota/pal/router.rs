@@ -0,0 +1,337 @@
+//! [`OtaPal`] routing the files of a job to the components of the device, eg.
+//! the main MCU, a BLE co-processor and a cellular modem, each updated by its
+//! own [`OtaPal`].
+//!
+//! Each component activates and self tests its image with its own semantics.
+//! The image state of the job is applied to every component that received a
+//! file, or is pending commit after the reset, so the job only succeeds once
+//! all of them accepted their image.
+
+use super::{ImageState, OtaPal, OtaPalError, PalErrorCode, PalImageState, Version};
+use crate::ota::encoding::FileContext;
+
+/// Components of the device, as a tuple of [`OtaPal`]s sharing an error type.
+///
+/// The first component is the main MCU, which reports the firmware version
+/// and resets the device.
+pub trait Components {
+    type Error: PalErrorCode;
+
+    /// Number of components.
+    const LEN: usize;
+
+    fn component(&mut self, idx: usize) -> Option<&mut dyn OtaPal<Error = Self::Error>>;
+
+    fn component_ref(&self, idx: usize) -> Option<&dyn OtaPal<Error = Self::Error>>;
+
+    fn main(&self) -> &dyn OtaPal<Error = Self::Error>;
+}
+
+macro_rules! impl_components {
+    ($len:literal; $($idx:tt: $component:ident),+) => {
+        impl<E: PalErrorCode, $($component: OtaPal<Error = E>),+> Components for ($($component,)+) {
+            type Error = E;
+
+            const LEN: usize = $len;
+
+            fn component(&mut self, idx: usize) -> Option<&mut dyn OtaPal<Error = E>> {
+                match idx {
+                    $($idx => Some(&mut self.$idx),)+
+                    _ => None,
+                }
+            }
+
+            fn component_ref(&self, idx: usize) -> Option<&dyn OtaPal<Error = E>> {
+                match idx {
+                    $($idx => Some(&self.$idx),)+
+                    _ => None,
+                }
+            }
+
+            fn main(&self) -> &dyn OtaPal<Error = E> {
+                &self.0
+            }
+        }
+    };
+}
+
+impl_components!(1; 0: A);
+impl_components!(2; 0: A, 1: B);
+impl_components!(3; 0: A, 1: B, 2: C);
+impl_components!(4; 0: A, 1: B, 2: C, 3: D);
+
+pub struct RouterPal<C> {
+    components: C,
+    route: fn(&FileContext) -> usize,
+    /// Components that received a file of the current job, by bit
+    targeted: u8,
+}
+
+impl<C: Components> RouterPal<C> {
+    /// Route each file to the component with the index returned by `route`,
+    /// eg. based on [`FileContext::filepath`] or [`FileContext::file_type`].
+    pub fn new(components: C, route: fn(&FileContext) -> usize) -> Self {
+        Self {
+            components,
+            route,
+            targeted: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.components
+    }
+
+    pub fn into_inner(self) -> C {
+        self.components
+    }
+
+    fn routed(
+        &mut self,
+        file: &FileContext,
+    ) -> Result<&mut dyn OtaPal<Error = C::Error>, OtaPalError<C::Error>> {
+        self.components
+            .component((self.route)(file))
+            .ok_or(OtaPalError::BadFileHandle)
+    }
+
+    fn is_targeted(&self, idx: usize) -> bool {
+        self.targeted & (1 << idx) != 0
+    }
+}
+
+impl<C> OtaPal for RouterPal<C>
+where
+    C: Components,
+    C::Error: Clone,
+{
+    type Error = C::Error;
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.routed(file)?.abort(file)
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        let idx = (self.route)(file);
+        self.routed(file)?.create_file_for_rx(file)?;
+        self.targeted |= 1 << idx;
+        Ok(())
+    }
+
+    /// Target the component of a file received before the restart, as the
+    /// targeted components are not persisted.
+    fn file_received(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        let idx = (self.route)(file);
+        self.routed(file)?.file_received(file)?;
+        self.targeted |= 1 << idx;
+        Ok(())
+    }
+
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        for idx in 1..C::LEN {
+            if let Some(component) = self.components.component(idx) {
+                if component.get_platform_image_state()? == PalImageState::PendingCommit {
+                    return Ok(PalImageState::PendingCommit);
+                }
+            }
+        }
+        self.components
+            .component(0)
+            .ok_or(OtaPalError::BadFileHandle)?
+            .get_platform_image_state()
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        for idx in 0..C::LEN {
+            let targeted = self.is_targeted(idx);
+            if let Some(component) = self.components.component(idx) {
+                if targeted || component.get_platform_image_state()? == PalImageState::PendingCommit
+                {
+                    component.set_platform_image_state(image_state.clone())?;
+                }
+            }
+        }
+
+        if !matches!(image_state, ImageState::Testing(_)) {
+            self.targeted = 0;
+        }
+        Ok(())
+    }
+
+    /// Activate the images of the components that received a file, and reset
+    /// the device through the main MCU, last.
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        for idx in 1..C::LEN {
+            if self.is_targeted(idx) {
+                if let Some(component) = self.components.component(idx) {
+                    component.activate_new_image()?;
+                }
+            }
+        }
+
+        let main = self
+            .components
+            .component(0)
+            .ok_or(OtaPalError::BadFileHandle)?;
+        if self.targeted & 1 != 0 {
+            main.activate_new_image()
+        } else {
+            main.reset_device()
+        }
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.components
+            .component(0)
+            .ok_or(OtaPalError::BadFileHandle)?
+            .reset_device()
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.routed(file)?.close_file(file)
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        self.routed(file)?
+            .write_block(file, block_offset, block_payload)
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        self.components.main().get_active_firmware_version()
+    }
+
+    fn accepts_block(&self, file: &FileContext, block_offset: usize) -> bool {
+        // Files without a component fail on write
+        self.components
+            .component_ref((self.route)(file))
+            .map_or(true, |component| {
+                component.accepts_block(file, block_offset)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{
+        config::Config,
+        pipeline::reorder::ReorderPal,
+        state::ImageStateReason,
+        test::{mock::MemPal, test_file_ctx},
+    };
+
+    fn route(file: &FileContext) -> usize {
+        file.file_type.unwrap_or_default() as usize
+    }
+
+    fn file(file_type: u32) -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.file_type = Some(file_type);
+        file_ctx
+    }
+
+    #[test]
+    fn route_files() {
+        let mut pal = RouterPal::new((MemPal::default(), MemPal::default()), route);
+
+        let (mcu, modem) = (file(0), file(1));
+        pal.create_file_for_rx(&modem).unwrap();
+        pal.write_block(&modem, 0, b"modem").unwrap();
+        pal.close_file(&modem).unwrap();
+        assert!(matches!(
+            pal.create_file_for_rx(&file(2)),
+            Err(OtaPalError::BadFileHandle)
+        ));
+
+        pal.activate_new_image().unwrap();
+
+        let (mcu_pal, modem_pal) = pal.into_inner();
+        assert!(mcu_pal.image.is_empty());
+        assert!(!mcu_pal.activated);
+        assert_eq!(modem_pal.image, b"modem");
+        assert!(modem_pal.activated);
+
+        let mut pal = RouterPal::new((mcu_pal, modem_pal), route);
+        pal.create_file_for_rx(&mcu).unwrap();
+        pal.activate_new_image().unwrap();
+        assert!(pal.into_inner().0.activated);
+    }
+
+    #[test]
+    fn accept_pending_components() {
+        // After the reset, only the modem is pending commit
+        let modem = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
+        let mut pal = RouterPal::new((MemPal::default(), MemPal::default(), modem), route);
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::PendingCommit
+        );
+
+        pal.set_platform_image_state(ImageState::Accepted).unwrap();
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        let (mcu, ble, modem) = pal.into_inner();
+        assert_eq!(mcu.accepted, None);
+        assert_eq!(ble.accepted, None);
+        assert_eq!(modem.accepted, Some(true));
+    }
+
+    #[test]
+    fn resume_targeted_components() {
+        let mut pal = RouterPal::new((MemPal::default(), MemPal::default()), route);
+
+        // The MCU image was received before the restart
+        pal.file_received(&file(0)).unwrap();
+        pal.create_file_for_rx(&file(1)).unwrap();
+        pal.activate_new_image().unwrap();
+
+        let (mcu, modem) = pal.into_inner();
+        assert!(mcu.activated);
+        assert!(modem.activated);
+    }
+
+    #[test]
+    fn ordered_component() {
+        let modem: ReorderPal<_, 1, 4> = ReorderPal::new(MemPal::default());
+        let mut pal = RouterPal::new((MemPal::default(), modem), route);
+
+        let modem = file(1);
+        pal.create_file_for_rx(&modem).unwrap();
+        assert!(pal.accepts_block(&modem, 4));
+        pal.write_block(&modem, 4, b"efgh").unwrap();
+
+        // The reorder window of the modem is full
+        assert!(!pal.accepts_block(&modem, 8));
+        assert!(pal.accepts_block(&file(0), 8));
+
+        pal.write_block(&modem, 0, b"abcd").unwrap();
+        assert_eq!(pal.inner().1.inner().image, b"abcdefgh");
+    }
+
+    #[test]
+    fn reject_targeted_components() {
+        let mut pal = RouterPal::new((MemPal::default(), MemPal::default()), route);
+        pal.create_file_for_rx(&file(1)).unwrap();
+
+        pal.set_platform_image_state(ImageState::Rejected(ImageStateReason::FailedIngest))
+            .unwrap();
+
+        let (mcu, modem) = pal.into_inner();
+        assert_eq!(mcu.accepted, None);
+        assert_eq!(modem.accepted, Some(false));
+    }
+}
@@ -0,0 +1,450 @@
+//! File backed [`OtaPal`] for hosted platforms, eg. Linux gateways.
+//!
+//! Files are received into a staging file next to their target, which is
+//! atomically renamed over the target once the file is closed and its
+//! signature verified. The state of
+//! the new image is persisted in a sidecar file, such that it survives the
+//! restart activating the new image.
+
+use ::std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use super::{ImageState, OtaPal, OtaPalError, PalImageState, Version};
+use crate::ota::encoding::FileContext;
+
+/// Extension appended to the target path while a file is being received.
+const STAGING_EXTENSION: &str = "part";
+
+/// Default name of the image state sidecar file.
+const STATE_FILE: &str = ".ota_image_state";
+
+/// How [`FilePal`] restarts the device, activating the new image.
+pub enum Restart {
+    /// Exit the process with the given status, for a supervisor such as
+    /// systemd to start it again from the new image.
+    Exit(i32),
+    /// Replace the process by executing the new image, or the current
+    /// executable if no image was received, with the arguments of the current
+    /// process.
+    #[cfg(unix)]
+    Exec,
+    /// Custom restart hook, given the path of the new image if one was
+    /// received.
+    Hook(RestartHook),
+}
+
+/// Restart hook of [`Restart::Hook`].
+pub type RestartHook = Box<dyn FnMut(Option<&Path>) -> io::Result<()> + Send>;
+
+/// Signature check of a received file, given the path of the staging file and
+/// the file description holding the signature and the signer certificate.
+/// Returns whether the signature is valid.
+pub type Verifier = Box<dyn FnMut(&Path, &FileContext) -> bool + Send>;
+
+/// [`OtaPal`] writing received files relative to a root directory.
+///
+/// Staging files are executable on unix, as the received file may be the
+/// image executed by [`Restart::Exec`]. A file replaces its target only once
+/// the [`Verifier`] accepted it, and files with a path leaving the root
+/// directory are rejected.
+pub struct FilePal {
+    root: PathBuf,
+    state_path: PathBuf,
+    version: Version,
+    verifier: Verifier,
+    restart: Restart,
+    staging: Option<(File, PathBuf)>,
+    image: Option<PathBuf>,
+}
+
+impl FilePal {
+    /// Create a PAL writing files to their `filepath` relative to `root`, for
+    /// a device running firmware `version`. Received files are checked by
+    /// `verifier` before they replace their target.
+    pub fn new(root: impl Into<PathBuf>, version: Version, verifier: Verifier) -> Self {
+        let root = root.into();
+        Self {
+            state_path: root.join(STATE_FILE),
+            root,
+            version,
+            verifier,
+            restart: Restart::Exit(0),
+            staging: None,
+            image: None,
+        }
+    }
+
+    /// Persist the image state in `path`, rather than in
+    /// `.ota_image_state` in the root directory.
+    pub fn with_state_path(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            state_path: path.into(),
+            ..self
+        }
+    }
+
+    /// Restart the device using `restart`. Defaults to [`Restart::Exit`]
+    /// with status 0.
+    pub fn with_restart(self, restart: Restart) -> Self {
+        Self { restart, ..self }
+    }
+
+    /// Path the file is written to once it is closed. The `filepath` of the
+    /// file must be a relative path within the root directory.
+    pub fn target_path(&self, file: &FileContext) -> Result<PathBuf, OtaPalError<io::Error>> {
+        let path = Path::new(file.filepath.as_str());
+        let mut components = path.components().filter(|c| *c != Component::CurDir);
+        let within_root = components.all(|c| matches!(c, Component::Normal(_)));
+        if !within_root || path.file_name().is_none() {
+            return Err(OtaPalError::BadFileHandle);
+        }
+        Ok(self.root.join(path))
+    }
+
+    fn staging_path(target: &Path) -> PathBuf {
+        let mut path = target.as_os_str().to_owned();
+        path.push(".");
+        path.push(STAGING_EXTENSION);
+        PathBuf::from(path)
+    }
+
+    /// Write the image state to the sidecar file, replacing it atomically.
+    fn write_state(&self, state: PalImageState) -> io::Result<()> {
+        let state = match state {
+            PalImageState::PendingCommit => "PendingCommit",
+            PalImageState::Valid => "Valid",
+            PalImageState::Invalid => "Invalid",
+        };
+
+        let tmp = Self::staging_path(&self.state_path);
+        let mut file = File::create(&tmp)?;
+        file.write_all(state.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.state_path)
+    }
+}
+
+impl OtaPal for FilePal {
+    type Error = io::Error;
+
+    fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        if let Some((file, path)) = self.staging.take() {
+            drop(file);
+            fs::remove_file(path).map_err(|_| OtaPalError::FileCloseFailed)?;
+        }
+        Ok(())
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        // The new image is in the self test phase once restarted
+        self.write_state(PalImageState::PendingCommit)
+            .map_err(|_| OtaPalError::CommitFailed)?;
+        self.reset_device()
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        let path = Self::staging_path(&self.target_path(file)?);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|_| OtaPalError::BadFileHandle)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        ::std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o755);
+        let staging = options
+            .open(&path)
+            .map_err(|_| OtaPalError::BadFileHandle)?;
+        self.staging = Some((staging, path));
+        Ok(())
+    }
+
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        match fs::read_to_string(&self.state_path) {
+            Ok(state) => match state.trim() {
+                "PendingCommit" => Ok(PalImageState::PendingCommit),
+                "Valid" => Ok(PalImageState::Valid),
+                "Invalid" => Ok(PalImageState::Invalid),
+                _ => Err(OtaPalError::BadImageState),
+            },
+            // No update was received yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PalImageState::Valid),
+            Err(e) => Err(OtaPalError::Custom(e)),
+        }
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        let state = match image_state {
+            ImageState::Testing(_) => PalImageState::PendingCommit,
+            ImageState::Accepted => PalImageState::Valid,
+            ImageState::Rejected(_) | ImageState::Aborted(_) => PalImageState::Invalid,
+            ImageState::Unknown => return Err(OtaPalError::BadImageState),
+        };
+
+        self.write_state(state)
+            .map_err(|_| OtaPalError::CommitFailed)
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        match self.restart {
+            Restart::Exit(status) => ::std::process::exit(status),
+            #[cfg(unix)]
+            Restart::Exec => {
+                use ::std::os::unix::process::CommandExt;
+
+                let image = match self.image {
+                    Some(ref image) => image.clone(),
+                    None => ::std::env::current_exe()?,
+                };
+                // Only returns on failure
+                Err(::std::process::Command::new(image)
+                    .args(::std::env::args_os().skip(1))
+                    .exec()
+                    .into())
+            }
+            Restart::Hook(ref mut hook) => Ok(hook(self.image.as_deref())?),
+        }
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        let (staging, path) = self.staging.take().ok_or(OtaPalError::BadFileHandle)?;
+
+        let len = staging
+            .metadata()
+            .map_err(|_| OtaPalError::FileCloseFailed)?
+            .len();
+        if len != file.filesize as u64 {
+            error!(
+                "Closing file of {} bytes, expected {}",
+                len as usize, file.filesize
+            );
+            drop(staging);
+            fs::remove_file(path).ok();
+            return Err(OtaPalError::FileCloseFailed);
+        }
+
+        staging
+            .sync_all()
+            .map_err(|_| OtaPalError::FileCloseFailed)?;
+        drop(staging);
+
+        if !(self.verifier)(&path, file) {
+            error!("Signature check of {} failed", file.filepath.as_str());
+            fs::remove_file(path).ok();
+            return Err(OtaPalError::SignatureCheckFailed);
+        }
+
+        let target = self.target_path(file)?;
+        fs::rename(path, &target).map_err(|_| OtaPalError::FileCloseFailed)?;
+        self.image = Some(target);
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        _file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        let (staging, _) = self.staging.as_mut().ok_or(OtaPalError::BadFileHandle)?;
+
+        staging
+            .seek(SeekFrom::Start(block_offset as u64))
+            .and_then(|_| staging.write_all(block_payload))
+            .map_err(|_| OtaPalError::FileWriteFailed)?;
+        Ok(block_payload.len())
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        Ok(self.version.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, state::ImageStateReason, test::test_file_ctx};
+
+    /// Empty directory unique to `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("rustot-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    /// Verifier accepting every file
+    fn verified() -> Verifier {
+        Box::new(|_, _| true)
+    }
+
+    fn file_ctx(filepath: &str, filesize: usize) -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filepath = heapless::String::from(filepath);
+        file_ctx.filesize = filesize;
+        file_ctx
+    }
+
+    #[test]
+    fn receive_file() {
+        let dir = test_dir("receive_file");
+        let file_ctx = file_ctx("bin/firmware", 8);
+        let mut pal = FilePal::new(&dir, Version::new(1, 2, 3), verified());
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 4, b"efgh").unwrap();
+        pal.write_block(&file_ctx, 0, b"abcd").unwrap();
+
+        // The target is only replaced once the file is closed
+        assert!(dir.join("bin/firmware.part").exists());
+        assert!(!dir.join("bin/firmware").exists());
+
+        pal.close_file(&file_ctx).unwrap();
+        assert_eq!(fs::read(dir.join("bin/firmware")).unwrap(), b"abcdefgh");
+        assert!(!dir.join("bin/firmware.part").exists());
+        #[cfg(unix)]
+        {
+            use ::std::os::unix::fs::PermissionsExt;
+
+            let metadata = fs::metadata(dir.join("bin/firmware")).unwrap();
+            assert_ne!(metadata.permissions().mode() & 0o100, 0);
+        }
+
+        assert_eq!(
+            pal.get_active_firmware_version().unwrap(),
+            Version::new(1, 2, 3)
+        );
+
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let mut pal = pal.with_restart(Restart::Hook(Box::new(move |image| {
+            tx.send(image.map(Path::to_path_buf)).unwrap();
+            Ok(())
+        })));
+        pal.activate_new_image().unwrap();
+        assert_eq!(rx.recv().unwrap(), Some(dir.join("bin/firmware")));
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::PendingCommit
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn abort_and_incomplete_file() {
+        let dir = test_dir("abort");
+        let file_ctx = file_ctx("firmware", 8);
+        let mut pal = FilePal::new(&dir, Version::default(), verified());
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, b"abcd").unwrap();
+        pal.abort(&file_ctx).unwrap();
+        assert!(!dir.join("firmware.part").exists());
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, b"abcd").unwrap();
+        assert!(matches!(
+            pal.close_file(&file_ctx),
+            Err(OtaPalError::FileCloseFailed)
+        ));
+        assert!(!dir.join("firmware").exists());
+
+        assert!(matches!(
+            pal.write_block(&file_ctx, 0, b"abcd"),
+            Err(OtaPalError::BadFileHandle)
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_paths_outside_root() {
+        let dir = test_dir("paths");
+        let mut pal = FilePal::new(&dir, Version::default(), verified());
+
+        for filepath in ["/etc/passwd", "../firmware", "bin/../../firmware", "", "."] {
+            assert!(matches!(
+                pal.create_file_for_rx(&file_ctx(filepath, 8)),
+                Err(OtaPalError::BadFileHandle)
+            ));
+        }
+        assert_eq!(
+            pal.target_path(&file_ctx("./bin/firmware", 8)).unwrap(),
+            dir.join("bin/firmware")
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn reject_invalid_signature() {
+        let dir = test_dir("signature");
+        let file_ctx = file_ctx("firmware", 4);
+        let verifier: Verifier = Box::new(|path, file| {
+            assert!(path.ends_with("firmware.part"));
+            fs::read(path).unwrap() == file.filepath.as_bytes()
+        });
+        let mut pal = FilePal::new(&dir, Version::default(), verifier);
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, b"fake").unwrap();
+        assert!(matches!(
+            pal.close_file(&file_ctx),
+            Err(OtaPalError::SignatureCheckFailed)
+        ));
+        assert!(!dir.join("firmware").exists());
+        assert!(!dir.join("firmware.part").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn persist_image_state() {
+        let dir = test_dir("image_state");
+        fs::create_dir_all(&dir).unwrap();
+        let mut pal = FilePal::new(&dir, Version::default(), verified());
+
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        pal.set_platform_image_state(ImageState::Testing(ImageStateReason::SignatureCheckPassed))
+            .unwrap();
+
+        // A new instance, eg. after restarting, reads the persisted state
+        let mut pal = FilePal::new(&dir, Version::default(), verified());
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::PendingCommit
+        );
+
+        pal.set_platform_image_state(ImageState::Rejected(ImageStateReason::UserAbort))
+            .unwrap();
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Invalid
+        );
+
+        pal.set_platform_image_state(ImageState::Accepted).unwrap();
+        assert_eq!(
+            pal.get_platform_image_state().unwrap(),
+            PalImageState::Valid
+        );
+
+        let mut pal = pal.with_state_path(dir.join("state"));
+        pal.set_platform_image_state(ImageState::Testing(ImageStateReason::SignatureCheckPassed))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("state")).unwrap(),
+            "PendingCommit"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
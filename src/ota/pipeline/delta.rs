@@ -0,0 +1,413 @@
+//! Delta updates, reconstructing the new image from the active image and a
+//! bsdiff style patch streamed as the OTA file.
+//!
+//! The patch is uncompressed and little endian, made of a header followed by
+//! records applied in order until the new image is complete:
+//!
+//! ```text
+//! header: magic "DLT1", new image size (u32)
+//! record: diff length (u32), extra length (u32), seek (i32),
+//!         diff bytes, extra bytes
+//! ```
+//!
+//! Each diff byte is added (wrapping) to the next byte read from the active
+//! image, extra bytes are copied as is, and the read position in the active
+//! image is then moved by `seek` bytes.
+
+use super::Output;
+use crate::ota::{
+    encoding::FileContext,
+    pal::{ImageReader, OtaPal, OtaPalError},
+};
+
+const MAGIC: &[u8; 4] = b"DLT1";
+const HEADER_LEN: usize = 8;
+const RECORD_LEN: usize = 12;
+
+/// Number of bytes of the active image read at once.
+const CHUNK_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatchState {
+    Header,
+    Record,
+    Diff(usize),
+    Extra(usize),
+    Done,
+}
+
+/// Streaming patcher, applying a delta patch received in consecutive chunks.
+pub struct Patcher {
+    state: PatchState,
+    buf: [u8; RECORD_LEN],
+    buf_len: usize,
+    max_image_size: usize,
+    image_size: usize,
+    output: Output,
+    base_pos: usize,
+    extra_len: usize,
+    seek: i32,
+}
+
+impl Patcher {
+    /// Create a patcher for new images of up to `max_image_size` bytes.
+    pub fn new(max_image_size: usize) -> Self {
+        Self {
+            state: PatchState::Header,
+            buf: [0; RECORD_LEN],
+            buf_len: 0,
+            max_image_size,
+            image_size: 0,
+            output: Output::new(),
+            base_pos: 0,
+            extra_len: 0,
+            seek: 0,
+        }
+    }
+
+    /// Size of the new image, once the header has been received.
+    pub fn image_size(&self) -> Option<usize> {
+        (self.state != PatchState::Header).then_some(self.image_size)
+    }
+
+    /// Whether the new image has been completely written.
+    pub fn is_complete(&self) -> bool {
+        self.state == PatchState::Done
+    }
+
+    /// Apply the next `patch` chunk, reading the active image from and writing
+    /// the new image to `pal`.
+    pub fn apply<P: ImageReader>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        mut patch: &[u8],
+    ) -> Result<(), OtaPalError<P::Error>> {
+        while !patch.is_empty() {
+            match self.state {
+                PatchState::Header | PatchState::Record => {
+                    let len = if self.state == PatchState::Header {
+                        HEADER_LEN
+                    } else {
+                        RECORD_LEN
+                    };
+                    let n = (len - self.buf_len).min(patch.len());
+                    self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&patch[..n]);
+                    self.buf_len += n;
+                    patch = &patch[n..];
+
+                    if self.buf_len == len {
+                        self.buf_len = 0;
+                        self.parse()?;
+                    }
+                }
+                PatchState::Diff(remaining) => {
+                    let n = remaining.min(patch.len()).min(CHUNK_LEN);
+                    let mut chunk = [0; CHUNK_LEN];
+                    pal.read_active_image(self.base_pos, &mut chunk[..n])?;
+                    for (byte, diff) in chunk[..n].iter_mut().zip(&patch[..n]) {
+                        *byte = byte.wrapping_add(*diff);
+                    }
+                    self.write(pal, file, &chunk[..n])?;
+                    self.base_pos += n;
+                    patch = &patch[n..];
+                    self.state = PatchState::Diff(remaining - n);
+                }
+                PatchState::Extra(remaining) => {
+                    let n = remaining.min(patch.len());
+                    self.write(pal, file, &patch[..n])?;
+                    patch = &patch[n..];
+                    self.state = PatchState::Extra(remaining - n);
+                }
+                PatchState::Done => return Err(OtaPalError::InvalidPipelineData),
+            }
+
+            self.settle()?;
+        }
+
+        if self.is_complete() {
+            self.output.flush(pal, file)?;
+        }
+        Ok(())
+    }
+
+    /// Parse the header or record collected in `buf`
+    fn parse<E>(&mut self) -> Result<(), OtaPalError<E>> {
+        let word = |i: usize| {
+            u32::from_le_bytes([
+                self.buf[i],
+                self.buf[i + 1],
+                self.buf[i + 2],
+                self.buf[i + 3],
+            ])
+        };
+
+        if self.state == PatchState::Header {
+            if &self.buf[..4] != MAGIC {
+                return Err(OtaPalError::InvalidPipelineData);
+            }
+            self.image_size = word(4) as usize;
+            if self.image_size > self.max_image_size {
+                return Err(OtaPalError::FileTooLarge);
+            }
+            self.state = PatchState::Record;
+        } else {
+            self.extra_len = word(4) as usize;
+            self.seek = word(8) as i32;
+            self.state = PatchState::Diff(word(0) as usize);
+        }
+        Ok(())
+    }
+
+    /// Move past completed sections of the current record
+    fn settle<E>(&mut self) -> Result<(), OtaPalError<E>> {
+        loop {
+            self.state = match self.state {
+                PatchState::Record if self.output.len() == self.image_size => PatchState::Done,
+                PatchState::Diff(0) => PatchState::Extra(self.extra_len),
+                PatchState::Extra(0) => {
+                    self.base_pos = self
+                        .base_pos
+                        .checked_add_signed(self.seek as isize)
+                        .ok_or(OtaPalError::InvalidPipelineData)?;
+                    PatchState::Record
+                }
+                _ => return Ok(()),
+            };
+        }
+    }
+
+    fn write<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        data: &[u8],
+    ) -> Result<(), OtaPalError<P::Error>> {
+        if self.output.len() + data.len() > self.image_size {
+            return Err(OtaPalError::InvalidPipelineData);
+        }
+        self.output.write(pal, file, data)
+    }
+}
+
+/// Pipeline stage applying delta patches against the active image, read from
+/// the wrapped PAL through [`ImageReader`].
+///
+/// The patch is applied in order, as each record moves on from the read
+/// position the previous record left in the active image, and is rejected
+/// when the file is closed before the new image is complete.
+pub struct DeltaPal<P> {
+    pal: P,
+    file_type: u32,
+    max_image_size: usize,
+    patcher: Option<Patcher>,
+    patch_pos: usize,
+}
+
+impl<P: ImageReader> DeltaPal<P> {
+    /// Apply the patches of files with `file_type`, to new images of up to
+    /// `max_image_size` bytes, eg. the size of the target slot.
+    pub fn new(pal: P, file_type: u32, max_image_size: usize) -> Self {
+        Self {
+            pal,
+            file_type,
+            max_image_size,
+            patcher: None,
+            patch_pos: 0,
+        }
+    }
+
+    wrapped_pal!(P);
+
+    fn is_delta(&self, file: &FileContext) -> bool {
+        file.file_type == Some(self.file_type)
+    }
+}
+
+impl<P: ImageReader> OtaPal for DeltaPal<P> {
+    type Error = P::Error;
+
+    forward_image_state!();
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.patcher = None;
+        self.pal.abort(file)
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.patch_pos = 0;
+        if !self.is_delta(file) {
+            self.patcher = None;
+            return self.pal.create_file_for_rx(file);
+        }
+
+        self.patcher = Some(Patcher::new(self.max_image_size));
+        let mut file = file.clone();
+        file.filesize = self.max_image_size;
+        self.pal.create_file_for_rx(&file)
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        match self.patcher.take() {
+            Some(patcher) => {
+                if !patcher.is_complete() {
+                    return Err(OtaPalError::InvalidPipelineData);
+                }
+
+                let mut file = file.clone();
+                file.filesize = patcher.image_size;
+                self.pal.close_file(&file)
+            }
+            None => self.pal.close_file(file),
+        }
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        match self.patcher {
+            Some(ref mut patcher) => {
+                if block_offset != self.patch_pos {
+                    return Err(OtaPalError::InvalidPipelineData);
+                }
+                patcher.apply(&mut self.pal, file, block_payload)?;
+                self.patch_pos += block_payload.len();
+                Ok(block_payload.len())
+            }
+            None => self.pal.write_block(file, block_offset, block_payload),
+        }
+    }
+
+    fn accepts_block(&self, file: &FileContext, block_offset: usize) -> bool {
+        match self.patcher {
+            Some(_) => block_offset == self.patch_pos,
+            None => self.pal.accepts_block(file, block_offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::pipeline::tests::{bank_b_image, check_stage};
+    use crate::ota::test::{dual_bank_pal, mock::MemPal, stage_file_ctx};
+
+    fn record(patch: &mut Vec<u8>, diff: &[u8], extra: &[u8], seek: i32) {
+        patch.extend_from_slice(&(diff.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&(extra.len() as u32).to_le_bytes());
+        patch.extend_from_slice(&seek.to_le_bytes());
+        patch.extend_from_slice(diff);
+        patch.extend_from_slice(extra);
+    }
+
+    /// Patch turning `b"hello world!"` into `b"jello brave world hello"`
+    fn test_patch() -> Vec<u8> {
+        let mut patch = b"DLT1".to_vec();
+        patch.extend_from_slice(&23u32.to_le_bytes());
+        // "h" + 2 = "j", copy "ello ", insert "brave "
+        record(&mut patch, &[2, 0, 0, 0, 0, 0], b"brave ", 0);
+        // Copy "world", insert " " and seek back to the start
+        record(&mut patch, &[0; 5], b" ", -11);
+        // Copy "hello"
+        record(&mut patch, &[0; 5], &[], 0);
+        patch
+    }
+
+    #[test]
+    fn apply_patch_in_chunks() {
+        let file_ctx = stage_file_ctx(None);
+        let patch = test_patch();
+
+        for chunk_len in 1..=patch.len() {
+            let mut pal = MemPal::running(b"hello world!");
+            let mut patcher = Patcher::new(1024);
+            for chunk in patch.chunks(chunk_len) {
+                patcher.apply(&mut pal, &file_ctx, chunk).unwrap();
+            }
+
+            assert!(patcher.is_complete());
+            assert_eq!(patcher.image_size(), Some(23));
+            assert_eq!(pal.image, b"jello brave world hello");
+        }
+    }
+
+    #[test]
+    fn invalid_patch() {
+        let file_ctx = stage_file_ctx(None);
+        let mut pal = MemPal::running(b"hello world!");
+
+        let mut patch = test_patch();
+        patch[0] = b'X';
+        assert!(matches!(
+            Patcher::new(1024).apply(&mut pal, &file_ctx, &patch),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+
+        // Trailing data after the new image is complete
+        let mut patch = test_patch();
+        patch.push(0);
+        assert!(matches!(
+            Patcher::new(1024).apply(&mut pal, &file_ctx, &patch),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+
+        // New image larger than the target
+        let mut patch = b"DLT1".to_vec();
+        patch.extend_from_slice(&1025u32.to_le_bytes());
+        assert!(matches!(
+            Patcher::new(1024).apply(&mut pal, &file_ctx, &patch),
+            Err(OtaPalError::FileTooLarge)
+        ));
+
+        // Records writing past the size of the new image
+        let mut patch = b"DLT1".to_vec();
+        patch.extend_from_slice(&2u32.to_le_bytes());
+        record(&mut patch, &[0; 3], &[], 0);
+        assert!(matches!(
+            Patcher::new(1024).apply(&mut pal, &file_ctx, &patch),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+    }
+
+    #[test]
+    fn delta_pal() {
+        check_stage(
+            DeltaPal::new(MemPal::running(b"hello world!"), 7, 1024),
+            DeltaPal::inner,
+            7,
+            &test_patch(),
+            10,
+            b"jello brave world hello",
+        );
+    }
+
+    #[test]
+    fn delta_pal_on_flash() {
+        let mut pal = DeltaPal::new(dual_bank_pal(b"hello world!"), 7, 0x700);
+
+        // New image spanning several erase sectors, written in unaligned records
+        let mut patch = b"DLT1".to_vec();
+        patch.extend_from_slice(&1508u32.to_le_bytes());
+        record(&mut patch, &[2], &[], 0);
+        for _ in 0..301 {
+            record(&mut patch, &[0; 5], &[], -5);
+        }
+        record(&mut patch, &[0], b"!", 0);
+
+        let mut file_ctx = stage_file_ctx(Some(7));
+        file_ctx.filesize = patch.len();
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        for (i, chunk) in patch.chunks(100).enumerate() {
+            pal.write_block(&file_ctx, i * 100, chunk).unwrap();
+        }
+        pal.close_file(&file_ctx).unwrap();
+
+        let flash = pal.into_inner().into_inner();
+        let image = bank_b_image(&flash, 1508);
+        assert_eq!(&image[..7], b"jello e");
+        assert_eq!(&image[1501..], b"ello e!");
+    }
+}
@@ -0,0 +1,375 @@
+//! Encrypted updates, decrypting files encrypted with a per-image key as
+//! described by the `enc` object of the file in the job document:
+//!
+//! ```json
+//! "enc": { "alg": "aes-128-gcm", "key": "<hex>", "iv": "<hex>", "tag": "<hex>" }
+//! ```
+//!
+//! The per-image key is wrapped for the device, and unwrapped by a
+//! [`KeyUnwrap`] implementation, eg. backed by a secure element.
+
+use aes::{
+    cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher, StreamCipherSeek},
+    Aes128,
+};
+use ghash::{universal_hash::UniversalHash, GHash};
+use subtle::ConstantTimeEq;
+
+use crate::ota::{
+    encoding::{
+        json::{Cipher, Encryption},
+        FileContext,
+    },
+    pal::{OtaPal, OtaPalError},
+};
+
+/// Number of bytes decrypted at once.
+const CHUNK_LEN: usize = 64;
+
+/// Unwraps the per-image key of an encrypted file.
+pub trait KeyUnwrap {
+    type Error;
+
+    /// Unwrap `wrapped_key` into the AES-128 `key`.
+    fn unwrap_key(&mut self, wrapped_key: &[u8], key: &mut [u8; 16]) -> Result<(), Self::Error>;
+}
+
+enum Decryptor {
+    Ctr(ctr::Ctr128BE<Aes128>),
+    Gcm {
+        ctr: ctr::Ctr32BE<Aes128>,
+        ghash: GHash,
+        /// Ciphertext not yet hashed, short of a complete block
+        partial: [u8; 16],
+        partial_len: usize,
+        /// `E(K, J0)`, masking the GHASH output
+        tag_mask: [u8; 16],
+        tag: [u8; 16],
+        len: usize,
+    },
+}
+
+impl Decryptor {
+    fn new(encryption: &Encryption, key: &[u8; 16]) -> Option<Self> {
+        match encryption.cipher {
+            Cipher::Aes128Ctr => {
+                let iv: [u8; 16] = encryption.iv.as_slice().try_into().ok()?;
+                Some(Self::Ctr(ctr::Ctr128BE::new(key.into(), &iv.into())))
+            }
+            Cipher::Aes128Gcm => {
+                // Only 96 bit IVs are supported
+                let iv: [u8; 12] = encryption.iv.as_slice().try_into().ok()?;
+                let aes = Aes128::new(key.into());
+
+                let mut h = [0; 16];
+                aes.encrypt_block((&mut h).into());
+
+                // With a 96 bit IV, `J0 = IV || 1`, and the keystream starts
+                // with counter 2
+                let mut j0 = [0; 16];
+                j0[..12].copy_from_slice(&iv);
+                j0[15] = 1;
+                let mut tag_mask = j0;
+                aes.encrypt_block((&mut tag_mask).into());
+                j0[15] = 2;
+
+                Some(Self::Gcm {
+                    ctr: ctr::Ctr32BE::new(key.into(), &j0.into()),
+                    ghash: GHash::new(&h.into()),
+                    partial: [0; 16],
+                    partial_len: 0,
+                    tag_mask,
+                    tag: encryption.tag?,
+                    len: 0,
+                })
+            }
+        }
+    }
+
+    /// Decrypt `chunk` at `offset` in place
+    fn decrypt(&mut self, offset: usize, chunk: &mut [u8]) {
+        match self {
+            Self::Ctr(ctr) => {
+                ctr.seek(offset as u64);
+                ctr.apply_keystream(chunk);
+            }
+            Self::Gcm {
+                ctr,
+                ghash,
+                partial,
+                partial_len,
+                len,
+                ..
+            } => {
+                // The ciphertext is authenticated in order
+                let mut data: &[u8] = chunk;
+                while !data.is_empty() {
+                    let n = (16 - *partial_len).min(data.len());
+                    partial[*partial_len..*partial_len + n].copy_from_slice(&data[..n]);
+                    *partial_len += n;
+                    data = &data[n..];
+
+                    if *partial_len == 16 {
+                        ghash.update(&[(*partial).into()]);
+                        *partial_len = 0;
+                    }
+                }
+                *len += chunk.len();
+
+                ctr.seek(offset as u64);
+                ctr.apply_keystream(chunk);
+            }
+        }
+    }
+
+    /// Check the authentication tag, once the whole file is decrypted
+    fn verify(self) -> bool {
+        match self {
+            Self::Ctr(_) => true,
+            Self::Gcm {
+                mut ghash,
+                partial,
+                partial_len,
+                tag_mask,
+                tag,
+                len,
+                ..
+            } => {
+                ghash.update_padded(&partial[..partial_len]);
+
+                // No additional authenticated data
+                let mut lengths = [0; 16];
+                lengths[8..].copy_from_slice(&(len as u64 * 8).to_be_bytes());
+                ghash.update(&[lengths.into()]);
+
+                let mut computed: [u8; 16] = ghash.finalize().into();
+                for (byte, mask) in computed.iter_mut().zip(tag_mask) {
+                    *byte ^= mask;
+                }
+                computed.ct_eq(&tag).into()
+            }
+        }
+    }
+}
+
+/// Pipeline stage decrypting files with an `enc` object in the job document.
+/// Other files are written unchanged.
+///
+/// AES-CTR encrypted blocks are decrypted in any order, while AES-GCM
+/// encrypted files must be written in order, eg. by wrapping this stage in a
+/// [`ReorderPal`](super::reorder::ReorderPal), and their authentication tag is
+/// verified when the file is closed.
+pub struct EncryptionPal<P, K> {
+    pal: P,
+    keys: K,
+    decryptor: Option<Decryptor>,
+    next_offset: usize,
+}
+
+impl<P: OtaPal, K: KeyUnwrap> EncryptionPal<P, K> {
+    pub fn new(pal: P, keys: K) -> Self {
+        Self {
+            pal,
+            keys,
+            decryptor: None,
+            next_offset: 0,
+        }
+    }
+
+    wrapped_pal!(P);
+}
+
+impl<P: OtaPal, K: KeyUnwrap> OtaPal for EncryptionPal<P, K> {
+    type Error = P::Error;
+
+    forward_image_state!();
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.decryptor = None;
+        self.pal.abort(file)
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.decryptor = match file.encryption {
+            Some(ref encryption) => {
+                let mut key = [0; 16];
+                let unwrapped = self.keys.unwrap_key(&encryption.wrapped_key, &mut key);
+                let decryptor = unwrapped
+                    .ok()
+                    .and_then(|_| Decryptor::new(encryption, &key));
+                key.fill(0);
+
+                Some(decryptor.ok_or(OtaPalError::DecryptionFailed)?)
+            }
+            None => None,
+        };
+        self.next_offset = 0;
+        self.pal.create_file_for_rx(file)
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        if !self.decryptor.take().map_or(true, Decryptor::verify) {
+            // The image must not be committed, eg. by a signature check
+            self.pal.abort(file)?;
+            return Err(OtaPalError::DecryptionFailed);
+        }
+        self.pal.close_file(file)
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        let decryptor = match self.decryptor {
+            Some(ref mut decryptor) => decryptor,
+            None => return self.pal.write_block(file, block_offset, block_payload),
+        };
+
+        if !matches!(decryptor, Decryptor::Ctr(_)) && block_offset != self.next_offset {
+            return Err(OtaPalError::InvalidPipelineData);
+        }
+
+        let mut chunk = [0; CHUNK_LEN];
+        for (i, data) in block_payload.chunks(CHUNK_LEN).enumerate() {
+            let offset = block_offset + i * CHUNK_LEN;
+            let chunk = &mut chunk[..data.len()];
+            chunk.copy_from_slice(data);
+            decryptor.decrypt(offset, chunk);
+            self.pal.write_block(file, offset, chunk)?;
+        }
+        self.next_offset = block_offset + block_payload.len();
+
+        Ok(block_payload.len())
+    }
+
+    fn accepts_block(&self, file: &FileContext, block_offset: usize) -> bool {
+        match self.decryptor {
+            Some(Decryptor::Gcm { .. }) => block_offset == self.next_offset,
+            _ => self.pal.accepts_block(file, block_offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{
+        encoding::json::EncryptionDescription,
+        test::{mock::MemPal, stage_file_ctx},
+    };
+
+    // AES-GCM test case 3 of "The Galois/Counter Mode of Operation (GCM)"
+    const KEY: &str = "feffe9928665731c6d6a8f9467308308";
+    const IV: &str = "cafebabefacedbaddecaf888";
+    const PLAINTEXT: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+    const CIPHERTEXT: &str = "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985";
+    const TAG: &str = "4d5c2af327cd64a62cf35abd2ba6fab4";
+
+    /// Keys wrapped with the identity
+    struct PlainKeys;
+
+    impl KeyUnwrap for PlainKeys {
+        type Error = ();
+
+        fn unwrap_key(&mut self, wrapped_key: &[u8], key: &mut [u8; 16]) -> Result<(), ()> {
+            key.copy_from_slice(wrapped_key.get(..16).ok_or(())?);
+            Ok(())
+        }
+    }
+
+    fn file_ctx(cipher: Cipher, iv: &str, tag: Option<&str>) -> FileContext {
+        let mut file_ctx = stage_file_ctx(None);
+        file_ctx.encryption = EncryptionDescription {
+            cipher,
+            wrapped_key: KEY,
+            iv,
+            tag,
+        }
+        .decode();
+        assert!(file_ctx.encryption.is_some());
+        file_ctx
+    }
+
+    #[test]
+    fn decrypt_gcm() {
+        let file_ctx = file_ctx(Cipher::Aes128Gcm, IV, Some(TAG));
+        let ciphertext = hex::decode(CIPHERTEXT).unwrap();
+
+        let mut pal = EncryptionPal::new(MemPal::default(), PlainKeys);
+        pal.create_file_for_rx(&file_ctx).unwrap();
+
+        assert!(!pal.accepts_block(&file_ctx, 20));
+        pal.write_block(&file_ctx, 0, &ciphertext[..20]).unwrap();
+        assert!(pal.accepts_block(&file_ctx, 20));
+        pal.write_block(&file_ctx, 20, &ciphertext[20..]).unwrap();
+        pal.close_file(&file_ctx).unwrap();
+
+        assert_eq!(pal.inner().image, hex::decode(PLAINTEXT).unwrap());
+    }
+
+    #[test]
+    fn decrypt_gcm_tampered() {
+        let file_ctx = file_ctx(Cipher::Aes128Gcm, IV, Some(TAG));
+        let mut ciphertext = hex::decode(CIPHERTEXT).unwrap();
+        ciphertext[10] ^= 1;
+
+        let mut pal = EncryptionPal::new(MemPal::default(), PlainKeys);
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 0, &ciphertext).unwrap();
+        assert!(matches!(
+            pal.close_file(&file_ctx),
+            Err(OtaPalError::DecryptionFailed)
+        ));
+
+        // The file is aborted rather than closed
+        assert!(pal.inner().closed_size.is_none());
+        assert!(pal.inner().image.is_empty());
+    }
+
+    #[test]
+    fn decrypt_ctr_out_of_order() {
+        // The GCM keystream starts with the counter block `IV || 2`
+        let file_ctx = file_ctx(Cipher::Aes128Ctr, "cafebabefacedbaddecaf88800000002", None);
+        let ciphertext = hex::decode(CIPHERTEXT).unwrap();
+
+        let mut pal = EncryptionPal::new(MemPal::default(), PlainKeys);
+        pal.create_file_for_rx(&file_ctx).unwrap();
+
+        assert!(pal.accepts_block(&file_ctx, 20));
+        pal.write_block(&file_ctx, 20, &ciphertext[20..]).unwrap();
+        pal.write_block(&file_ctx, 0, &ciphertext[..20]).unwrap();
+        pal.close_file(&file_ctx).unwrap();
+
+        assert_eq!(pal.inner().image, hex::decode(PLAINTEXT).unwrap());
+    }
+
+    #[test]
+    fn invalid_key() {
+        let mut file_ctx = file_ctx(Cipher::Aes128Gcm, IV, Some(TAG));
+        if let Some(ref mut encryption) = file_ctx.encryption {
+            encryption.wrapped_key.truncate(8);
+        }
+
+        let mut pal = EncryptionPal::new(MemPal::default(), PlainKeys);
+        assert!(matches!(
+            pal.create_file_for_rx(&file_ctx),
+            Err(OtaPalError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn invalid_gcm_iv() {
+        let mut file_ctx = file_ctx(Cipher::Aes128Gcm, IV, Some(TAG));
+        if let Some(ref mut encryption) = file_ctx.encryption {
+            encryption.iv.extend_from_slice(&[0; 4]).unwrap();
+        }
+
+        let mut pal = EncryptionPal::new(MemPal::default(), PlainKeys);
+        assert!(matches!(
+            pal.create_file_for_rx(&file_ctx),
+            Err(OtaPalError::DecryptionFailed)
+        ));
+    }
+}
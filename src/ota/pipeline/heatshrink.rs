@@ -0,0 +1,361 @@
+//! Compressed updates, decompressing [heatshrink] compressed files on the fly.
+//!
+//! The `WINDOW` size in bytes and `LOOKAHEAD_BITS` of the decoder must match
+//! the `-w` (base 2 logarithm of `WINDOW`) and `-l` options the file was
+//! compressed with.
+//!
+//! [heatshrink]: https://github.com/atomicobject/heatshrink
+
+use super::Output;
+use crate::ota::{
+    encoding::FileContext,
+    pal::{OtaPal, OtaPalError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecodeState {
+    Tag,
+    Literal,
+    Index,
+    Count,
+}
+
+/// Streaming heatshrink decoder, decompressing a file received in consecutive
+/// chunks.
+pub struct Decoder<const WINDOW: usize, const LOOKAHEAD_BITS: u8> {
+    state: DecodeState,
+    bits: usize,
+    bit_count: u8,
+    offset: usize,
+    window: [u8; WINDOW],
+    max_output_len: usize,
+    output: Output,
+}
+
+impl<const WINDOW: usize, const LOOKAHEAD_BITS: u8> Decoder<WINDOW, LOOKAHEAD_BITS> {
+    /// Create a decoder for files decompressing to up to `max_output_len`
+    /// bytes.
+    pub fn new(max_output_len: usize) -> Self {
+        assert!(WINDOW.is_power_of_two() && (16..=32768).contains(&WINDOW));
+        assert!(LOOKAHEAD_BITS >= 3 && (1 << LOOKAHEAD_BITS) < WINDOW);
+
+        Self {
+            state: DecodeState::Tag,
+            bits: 0,
+            bit_count: 0,
+            offset: 0,
+            window: [0; WINDOW],
+            max_output_len,
+            output: Output::new(),
+        }
+    }
+
+    /// Number of decompressed bytes so far.
+    pub fn output_len(&self) -> usize {
+        self.output.len()
+    }
+
+    /// Decompress the next `input` chunk, writing the decompressed data to
+    /// `pal`. The data is written in aligned blocks, the remainder being
+    /// written by [`Decoder::finish`].
+    pub fn decode<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        input: &[u8],
+    ) -> Result<(), OtaPalError<P::Error>> {
+        for byte in input {
+            for i in (0..8).rev() {
+                self.bits = (self.bits << 1) | ((byte >> i) & 1) as usize;
+                self.bit_count += 1;
+
+                if self.bit_count == self.needed_bits() {
+                    let value = self.bits;
+                    self.bits = 0;
+                    self.bit_count = 0;
+                    self.step(pal, file, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the remainder of the decompressed data, once the whole file is
+    /// decoded.
+    pub fn finish<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+    ) -> Result<(), OtaPalError<P::Error>> {
+        self.output.flush(pal, file)
+    }
+
+    fn needed_bits(&self) -> u8 {
+        match self.state {
+            DecodeState::Tag => 1,
+            DecodeState::Literal => 8,
+            DecodeState::Index => WINDOW.trailing_zeros() as u8,
+            DecodeState::Count => LOOKAHEAD_BITS,
+        }
+    }
+
+    fn step<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        value: usize,
+    ) -> Result<(), OtaPalError<P::Error>> {
+        self.state = match self.state {
+            DecodeState::Tag if value == 1 => DecodeState::Literal,
+            DecodeState::Tag => DecodeState::Index,
+            DecodeState::Literal => {
+                self.emit(pal, file, value as u8)?;
+                DecodeState::Tag
+            }
+            DecodeState::Index => {
+                self.offset = value + 1;
+                DecodeState::Count
+            }
+            DecodeState::Count => {
+                for _ in 0..=value {
+                    let byte = self.window[self.output.len().wrapping_sub(self.offset) % WINDOW];
+                    self.emit(pal, file, byte)?;
+                }
+                DecodeState::Tag
+            }
+        };
+        Ok(())
+    }
+
+    fn emit<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        byte: u8,
+    ) -> Result<(), OtaPalError<P::Error>> {
+        if self.output.len() == self.max_output_len {
+            return Err(OtaPalError::FileTooLarge);
+        }
+        self.window[self.output.len() % WINDOW] = byte;
+        self.output.write(pal, file, &[byte])
+    }
+}
+
+/// Pipeline stage decompressing heatshrink compressed files on the fly,
+/// without holding more than the `WINDOW` of decompressed data.
+///
+/// The file is written in order, as each back-reference points into the data
+/// decompressed before it.
+pub struct HeatshrinkPal<P, const WINDOW: usize = 256, const LOOKAHEAD_BITS: u8 = 4> {
+    pal: P,
+    file_type: u32,
+    max_image_size: usize,
+    decoder: Option<Decoder<WINDOW, LOOKAHEAD_BITS>>,
+    input_pos: usize,
+}
+
+impl<P: OtaPal, const WINDOW: usize, const LOOKAHEAD_BITS: u8>
+    HeatshrinkPal<P, WINDOW, LOOKAHEAD_BITS>
+{
+    /// Decompress files with `file_type`, to images of up to
+    /// `max_image_size` bytes.
+    pub fn new(pal: P, file_type: u32, max_image_size: usize) -> Self {
+        Self {
+            pal,
+            file_type,
+            max_image_size,
+            decoder: None,
+            input_pos: 0,
+        }
+    }
+
+    wrapped_pal!(P);
+
+    fn is_compressed(&self, file: &FileContext) -> bool {
+        file.file_type == Some(self.file_type)
+    }
+}
+
+impl<P: OtaPal, const WINDOW: usize, const LOOKAHEAD_BITS: u8> OtaPal
+    for HeatshrinkPal<P, WINDOW, LOOKAHEAD_BITS>
+{
+    type Error = P::Error;
+
+    forward_image_state!();
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.decoder = None;
+        self.pal.abort(file)
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.input_pos = 0;
+        if !self.is_compressed(file) {
+            self.decoder = None;
+            return self.pal.create_file_for_rx(file);
+        }
+
+        self.decoder = Some(Decoder::new(self.max_image_size));
+        let mut file = file.clone();
+        file.filesize = self.max_image_size;
+        self.pal.create_file_for_rx(&file)
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        match self.decoder.take() {
+            Some(mut decoder) => {
+                decoder.finish(&mut self.pal, file)?;
+                let mut file = file.clone();
+                file.filesize = decoder.output_len();
+                self.pal.close_file(&file)
+            }
+            None => self.pal.close_file(file),
+        }
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        match self.decoder {
+            Some(ref mut decoder) => {
+                if block_offset != self.input_pos {
+                    return Err(OtaPalError::InvalidPipelineData);
+                }
+                decoder.decode(&mut self.pal, file, block_payload)?;
+                self.input_pos += block_payload.len();
+                Ok(block_payload.len())
+            }
+            None => self.pal.write_block(file, block_offset, block_payload),
+        }
+    }
+
+    fn accepts_block(&self, file: &FileContext, block_offset: usize) -> bool {
+        match self.decoder {
+            Some(_) => block_offset == self.input_pos,
+            None => self.pal.accepts_block(file, block_offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::pipeline::tests::{bank_b_image, check_stage};
+    use crate::ota::test::{dual_bank_pal, mock::MemPal, stage_file_ctx};
+
+    /// Bit writer producing heatshrink compressed data
+    #[derive(Default)]
+    struct Encoder {
+        data: Vec<u8>,
+        bit_count: usize,
+    }
+
+    impl Encoder {
+        fn push(&mut self, value: usize, bits: u8) {
+            for i in (0..bits).rev() {
+                if self.bit_count % 8 == 0 {
+                    self.data.push(0);
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.data.last_mut().unwrap() |= bit << (7 - self.bit_count % 8);
+                self.bit_count += 1;
+            }
+        }
+
+        fn literal(&mut self, byte: u8) {
+            self.push(1, 1);
+            self.push(byte as usize, 8);
+        }
+
+        fn backref(&mut self, offset: usize, count: usize) {
+            self.push(0, 1);
+            self.push(offset - 1, 8);
+            self.push(count - 1, 4);
+        }
+    }
+
+    /// Compressed `b"abcabcabcabc-abc-a"`
+    fn test_data() -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.literal(b'a');
+        encoder.literal(b'b');
+        encoder.literal(b'c');
+        encoder.backref(3, 9);
+        encoder.literal(b'-');
+        encoder.backref(4, 5);
+        encoder.data
+    }
+
+    #[test]
+    fn decode_in_chunks() {
+        let file_ctx = stage_file_ctx(None);
+        let data = test_data();
+
+        for chunk_len in 1..=data.len() {
+            let mut pal = MemPal::default();
+            let mut decoder = Decoder::<256, 4>::new(1024);
+            for chunk in data.chunks(chunk_len) {
+                decoder.decode(&mut pal, &file_ctx, chunk).unwrap();
+            }
+            decoder.finish(&mut pal, &file_ctx).unwrap();
+
+            assert_eq!(decoder.output_len(), 18);
+            assert_eq!(pal.image, b"abcabcabcabc-abc-a");
+        }
+    }
+
+    #[test]
+    fn heatshrink_pal() {
+        check_stage(
+            HeatshrinkPal::<_>::new(MemPal::default(), 5, 1024),
+            HeatshrinkPal::inner,
+            5,
+            &test_data(),
+            4,
+            b"abcabcabcabc-abc-a",
+        );
+    }
+
+    #[test]
+    fn heatshrink_pal_on_flash() {
+        let mut pal: HeatshrinkPal<_> = HeatshrinkPal::new(dual_bank_pal(&[]), 5, 0x700);
+
+        // Decompressing to several erase sectors, written in unaligned runs
+        let mut encoder = Encoder::default();
+        encoder.literal(b'a');
+        for _ in 0..100 {
+            encoder.backref(1, 15);
+        }
+        encoder.literal(b'b');
+
+        let mut file_ctx = stage_file_ctx(Some(5));
+        file_ctx.filesize = encoder.data.len();
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        for (i, chunk) in encoder.data.chunks(7).enumerate() {
+            pal.write_block(&file_ctx, i * 7, chunk).unwrap();
+        }
+        pal.close_file(&file_ctx).unwrap();
+
+        let flash = pal.into_inner().into_inner();
+        let image = bank_b_image(&flash, 1502);
+        assert!(image[..1501].iter().all(|b| *b == b'a'));
+        assert_eq!(image[1501], b'b');
+    }
+
+    #[test]
+    fn image_too_large() {
+        let mut file_ctx = stage_file_ctx(Some(5));
+        let mut pal: HeatshrinkPal<_> = HeatshrinkPal::new(MemPal::default(), 5, 16);
+        pal.create_file_for_rx(&file_ctx).unwrap();
+
+        let data = test_data();
+        file_ctx.filesize = data.len();
+        assert!(matches!(
+            pal.write_block(&file_ctx, 0, &data),
+            Err(OtaPalError::FileTooLarge)
+        ));
+    }
+}
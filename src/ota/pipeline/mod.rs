@@ -0,0 +1,215 @@
+//! Stages processing the received file before it is written to the target
+//! slot, eg. to apply a delta update or decompress the file.
+//!
+//! Each stage wraps an [`OtaPal`](super::pal::OtaPal), and is itself an
+//! `OtaPal` handing the processed data on to the wrapped PAL, so stages can be
+//! chained between the OTA agent and the platform PAL. Stages requiring the
+//! file to be written in order can be wrapped in a
+//! [`ReorderPal`](reorder::ReorderPal), to hold blocks received out of order.
+//!
+//! Stages transforming the file into an image of another size, ie.
+//! [`DeltaPal`](delta::DeltaPal) and
+//! [`HeatshrinkPal`](heatshrink::HeatshrinkPal), only process the files of the
+//! job document with the `fileType` they are configured with, and write other
+//! files unchanged. As the image size is only known once the file is
+//! complete, the wrapped PAL is given the maximum image size the stage is
+//! configured with when the file is created, and the image size when the file
+//! is closed, such that the signature is verified against the image. The
+//! image is written in blocks of [`WRITE_LEN`] bytes, keeping every write but
+//! the last aligned to the write size of the flash.
+
+/// Accessors of the PAL wrapped by a stage, held in its `pal` field.
+macro_rules! wrapped_pal {
+    ($pal:ident) => {
+        /// The wrapped PAL.
+        pub fn inner(&self) -> &$pal {
+            &self.pal
+        }
+
+        /// The wrapped PAL, mutably.
+        pub fn inner_mut(&mut self) -> &mut $pal {
+            &mut self.pal
+        }
+
+        /// Unwrap the stage, returning the wrapped PAL.
+        pub fn into_inner(self) -> $pal {
+            self.pal
+        }
+    };
+}
+
+/// [`OtaPal`] methods handed on to the wrapped PAL unchanged, by a stage
+/// only processing the received file.
+macro_rules! forward_image_state {
+    () => {
+        fn activate_new_image(&mut self) -> Result<(), $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.activate_new_image()
+        }
+
+        fn get_platform_image_state(
+            &mut self,
+        ) -> Result<$crate::ota::pal::PalImageState, $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.get_platform_image_state()
+        }
+
+        fn set_platform_image_state(
+            &mut self,
+            image_state: $crate::ota::pal::ImageState<Self::Error>,
+        ) -> Result<(), $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.set_platform_image_state(image_state)
+        }
+
+        fn reset_device(&mut self) -> Result<(), $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.reset_device()
+        }
+
+        fn complete_callback(
+            &mut self,
+            event: $crate::ota::pal::OtaEvent,
+        ) -> Result<(), $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.complete_callback(event)
+        }
+
+        fn get_active_firmware_version(
+            &self,
+        ) -> Result<$crate::ota::pal::Version, $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.get_active_firmware_version()
+        }
+
+        fn file_received(
+            &mut self,
+            file: &$crate::ota::encoding::FileContext,
+        ) -> Result<(), $crate::ota::pal::OtaPalError<Self::Error>> {
+            self.pal.file_received(file)
+        }
+    };
+}
+
+pub mod delta;
+#[cfg(feature = "ota_encryption")]
+pub mod encryption;
+pub mod heatshrink;
+pub mod reorder;
+
+use crate::ota::{
+    encoding::FileContext,
+    pal::{OtaPal, OtaPalError},
+};
+
+/// Length of the writes of the image to the wrapped PAL, a multiple of the
+/// write size of any flash.
+pub const WRITE_LEN: usize = 256;
+
+/// Output of a stage, written to the wrapped PAL in blocks of [`WRITE_LEN`]
+/// bytes, and the remainder once the output is complete.
+pub(crate) struct Output {
+    buf: [u8; WRITE_LEN],
+    buf_len: usize,
+    len: usize,
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; WRITE_LEN],
+            buf_len: 0,
+            len: 0,
+        }
+    }
+
+    /// Number of bytes output so far, written or not.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn write<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+        mut data: &[u8],
+    ) -> Result<(), OtaPalError<P::Error>> {
+        while !data.is_empty() {
+            let n = (WRITE_LEN - self.buf_len).min(data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            self.len += n;
+            data = &data[n..];
+
+            if self.buf_len == WRITE_LEN {
+                self.flush(pal, file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the remainder of the output. Nothing may be written afterwards.
+    pub fn flush<P: OtaPal>(
+        &mut self,
+        pal: &mut P,
+        file: &FileContext,
+    ) -> Result<(), OtaPalError<P::Error>> {
+        if self.buf_len > 0 {
+            pal.write_block(file, self.len - self.buf_len, &self.buf[..self.buf_len])?;
+            self.buf_len = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ota::test::mock::{MemFlash, MemPal};
+    use crate::ota::test::DUAL_BANK_SLOTS;
+    use embedded_storage::nor_flash::NorFlash;
+
+    /// Check that `pal`, a stage processing the files of `file_type` into
+    /// `image`, writes other files unchanged, and only accepts the blocks of
+    /// `data` in order, split at `split`.
+    pub fn check_stage<S: OtaPal<Error = <MemPal as OtaPal>::Error>>(
+        mut pal: S,
+        inner: fn(&S) -> &MemPal,
+        file_type: u32,
+        data: &[u8],
+        split: usize,
+        image: &[u8],
+    ) {
+        let mut file_ctx = crate::ota::test::stage_file_ctx(None);
+
+        // Other files are written unchanged
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        assert!(pal.accepts_block(&file_ctx, 256));
+        pal.write_block(&file_ctx, 0, b"full image").unwrap();
+        pal.close_file(&file_ctx).unwrap();
+        assert_eq!(inner(&pal).image, b"full image");
+        assert_eq!(inner(&pal).closed_size, Some(file_ctx.filesize));
+
+        file_ctx.file_type = Some(file_type);
+        pal.create_file_for_rx(&file_ctx).unwrap();
+
+        let (first, second) = data.split_at(split);
+        assert!(!pal.accepts_block(&file_ctx, split));
+        assert!(pal.accepts_block(&file_ctx, 0));
+        assert!(matches!(
+            pal.write_block(&file_ctx, split, second),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+        pal.write_block(&file_ctx, 0, first).unwrap();
+        pal.write_block(&file_ctx, split, second).unwrap();
+        pal.close_file(&file_ctx).unwrap();
+
+        assert_eq!(inner(&pal).image, image);
+        assert_eq!(inner(&pal).closed_size, Some(image.len()));
+    }
+
+    /// The image of `len` bytes written to bank B of `flash`, checking that
+    /// the rest of the bank is left erased.
+    pub fn bank_b_image(flash: &MemFlash, len: usize) -> &[u8] {
+        let slot = &DUAL_BANK_SLOTS[1];
+        let bank = &flash.data[slot.offset as usize..][..slot.size as usize];
+        let written =
+            (len + MemFlash::WRITE_SIZE - 1) / MemFlash::WRITE_SIZE * MemFlash::WRITE_SIZE;
+        assert!(bank[written..].iter().all(|b| *b == 0xFF));
+        &bank[..len]
+    }
+}
@@ -0,0 +1,156 @@
+//! In-order reassembly of blocks received out of order, for PALs and pipeline
+//! stages requiring the file to be written sequentially.
+
+use heapless::Vec;
+
+use crate::ota::{
+    encoding::FileContext,
+    pal::{OtaPal, OtaPalError},
+};
+
+/// Pipeline stage holding up to `BLOCKS` blocks of at most `BLOCK_SIZE` bytes
+/// received ahead of the next block to write, and writing them to the wrapped
+/// PAL in order.
+///
+/// Blocks further ahead than the reorder window, or received while the window
+/// is full, are not accepted and are requested again by the OTA agent.
+/// `BLOCK_SIZE` must match the block size the agent is configured with.
+pub struct ReorderPal<P, const BLOCKS: usize, const BLOCK_SIZE: usize> {
+    pal: P,
+    next_offset: usize,
+    blocks: Vec<(usize, Vec<u8, BLOCK_SIZE>), BLOCKS>,
+}
+
+impl<P: OtaPal, const BLOCKS: usize, const BLOCK_SIZE: usize> ReorderPal<P, BLOCKS, BLOCK_SIZE> {
+    pub fn new(pal: P) -> Self {
+        Self {
+            pal,
+            next_offset: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    wrapped_pal!(P);
+
+    fn reset(&mut self) {
+        self.next_offset = 0;
+        self.blocks.clear();
+    }
+
+    /// Write the held blocks following the last written block
+    fn flush(&mut self, file: &FileContext) -> Result<(), OtaPalError<P::Error>> {
+        while let Some(i) = self
+            .blocks
+            .iter()
+            .position(|(offset, _)| *offset == self.next_offset)
+        {
+            let (offset, block) = self.blocks.swap_remove(i);
+            self.pal.write_block(file, offset, &block)?;
+            self.next_offset += block.len();
+        }
+        Ok(())
+    }
+}
+
+impl<P: OtaPal, const BLOCKS: usize, const BLOCK_SIZE: usize> OtaPal
+    for ReorderPal<P, BLOCKS, BLOCK_SIZE>
+{
+    type Error = P::Error;
+
+    forward_image_state!();
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.reset();
+        self.pal.abort(file)
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.reset();
+        self.pal.create_file_for_rx(file)
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        // Every block must have been written by now
+        if !self.blocks.is_empty() {
+            self.reset();
+            return Err(OtaPalError::InvalidPipelineData);
+        }
+        self.pal.close_file(file)
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        if block_offset == self.next_offset {
+            self.pal.write_block(file, block_offset, block_payload)?;
+            self.next_offset += block_payload.len();
+            self.flush(file)?;
+        } else {
+            if !self.accepts_block(file, block_offset) {
+                return Err(OtaPalError::InvalidPipelineData);
+            }
+            let block = Vec::from_slice(block_payload).map_err(|_| OtaPalError::FileTooLarge)?;
+            self.blocks
+                .push((block_offset, block))
+                .map_err(|_| OtaPalError::InvalidPipelineData)?;
+        }
+        Ok(block_payload.len())
+    }
+
+    fn accepts_block(&self, _file: &FileContext, block_offset: usize) -> bool {
+        block_offset == self.next_offset
+            || (block_offset > self.next_offset
+                && block_offset - self.next_offset <= BLOCKS * BLOCK_SIZE
+                && !self.blocks.is_full())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::test::{mock::MemPal, stage_file_ctx};
+
+    #[test]
+    fn reorder_blocks() {
+        let file_ctx = stage_file_ctx(None);
+        let mut pal: ReorderPal<_, 2, 4> = ReorderPal::new(MemPal::default());
+        pal.create_file_for_rx(&file_ctx).unwrap();
+
+        // Outside of the reorder window
+        assert!(!pal.accepts_block(&file_ctx, 12));
+
+        assert!(pal.accepts_block(&file_ctx, 8));
+        pal.write_block(&file_ctx, 8, b"ij").unwrap();
+        pal.write_block(&file_ctx, 4, b"efgh").unwrap();
+        assert!(pal.inner().image.is_empty());
+
+        // The reorder window is full
+        assert!(!pal.accepts_block(&file_ctx, 12));
+        assert!(matches!(
+            pal.write_block(&file_ctx, 12, b"kl"),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+
+        assert!(pal.accepts_block(&file_ctx, 0));
+        pal.write_block(&file_ctx, 0, b"abcd").unwrap();
+        assert_eq!(pal.inner().image, b"abcdefghij");
+
+        pal.close_file(&file_ctx).unwrap();
+    }
+
+    #[test]
+    fn close_with_missing_blocks() {
+        let file_ctx = stage_file_ctx(None);
+        let mut pal: ReorderPal<_, 2, 4> = ReorderPal::new(MemPal::default());
+
+        pal.create_file_for_rx(&file_ctx).unwrap();
+        pal.write_block(&file_ctx, 4, b"efgh").unwrap();
+        assert!(matches!(
+            pal.close_file(&file_ctx),
+            Err(OtaPalError::InvalidPipelineData)
+        ));
+    }
+}
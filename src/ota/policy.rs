@@ -0,0 +1,46 @@
+//! Application policy deciding whether, and when, an OTA update may proceed,
+//! eg. only within a maintenance window or above a minimum battery level.
+
+use super::encoding::{json::JobStatusReason, FileContext};
+
+/// Decision of an [`UpdatePolicy`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PolicyDecision {
+    /// Proceed with the update.
+    Allow,
+    /// Postpone the update without failing it. The job is left untouched on
+    /// the server, and is evaluated again when it is received next time.
+    Defer,
+    /// Reject the update, failing the job with the given reason.
+    Reject(JobStatusReason),
+}
+
+/// Consulted by the OTA agent before starting a job and before activating the
+/// received image, configured with
+/// [`OtaAgentBuilder::with_update_policy`](super::builder::OtaAgentBuilder::with_update_policy).
+pub trait UpdatePolicy {
+    /// Called when a new job document is received, before the file is
+    /// created.
+    ///
+    /// A deferred job is not started, and the agent keeps waiting for a job.
+    /// Call [`OtaAgent::check_for_update`](super::agent::OtaAgent::check_for_update)
+    /// to receive the job again once the condition is expected to be met.
+    fn check_job(&mut self, _file_ctx: &FileContext) -> PolicyDecision {
+        PolicyDecision::Allow
+    }
+
+    /// Called before activating a received image.
+    ///
+    /// A deferred activation waits in `States::ReadyToActivate`, and is
+    /// checked again when the request timer fires in
+    /// [`OtaAgent::timer_callback`](super::agent::OtaAgent::timer_callback), or
+    /// on [`OtaAgent::activate`](super::agent::OtaAgent::activate).
+    fn check_activate(&mut self, _file_ctx: &FileContext) -> PolicyDecision {
+        PolicyDecision::Allow
+    }
+}
+
+/// Policy allowing every update, used unless another policy is configured.
+pub struct AllowAll;
+
+impl UpdatePolicy for AllowAll {}
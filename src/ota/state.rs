@@ -1,15 +1,23 @@
+use core::fmt::Write;
+
 use smlang::statemachine;
 
+use super::backoff::{self, Rng};
 use super::config::Config;
 use super::control_interface::ControlInterface;
 use super::data_interface::{DataInterface, Protocol};
 use super::encoding::json::JobStatusReason;
-use super::encoding::json::OtaJob;
-use super::encoding::FileContext;
+use super::encoding::{
+    bounded, job_status_details, self_test, set_status_detail, updated_by, FileContext, JobDocument,
+};
+use super::health::{HealthCheck, HealthStatus};
+use super::observer::{OtaObserver, OtaObserverEvent};
 use super::pal::OtaPal;
 use super::pal::OtaPalError;
+use super::policy::{PolicyDecision, UpdatePolicy};
+use super::throttle::Throttle;
 
-use crate::jobs::{data_types::JobStatus, StatusDetails};
+use crate::jobs::{data_types::JobStatus, StatusDetails, StatusDetailsOwned};
 use crate::ota::encoding::Bitmap;
 use crate::ota::pal::OtaEvent;
 
@@ -20,6 +28,7 @@ use super::{
     pal::{ImageState, PalImageState},
 };
 
+#[derive(Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ImageStateReason<E> {
     NewerJob,
@@ -29,7 +38,16 @@ pub enum ImageStateReason<E> {
     SignatureCheckPassed,
     InvalidDataProtocol,
     UserAbort,
+    /// The job was canceled on the service side.
+    Canceled,
     VersionCheck,
+    StreamMismatch,
+    UpdatePolicy,
+    /// A health check failed, or didn't pass within the self test timeout.
+    HealthCheck,
+    /// The bootloader reverted to the previous image before the self test
+    /// completed.
+    RolledBack,
     Pal(OtaPalError<E>),
 }
 
@@ -49,10 +67,9 @@ impl RestartReason {
     }
 }
 
-#[derive(PartialEq)]
 pub struct JobEventData<'a> {
     pub job_name: &'a str,
-    pub ota_document: &'a OtaJob<'a>,
+    pub ota_document: &'a dyn JobDocument,
     pub status_details: Option<&'a StatusDetails<'a>>,
 }
 
@@ -66,19 +83,33 @@ statemachine! {
         RequestingJob + ReplacementJob(JobEventData<'a>) [process_job_handler] = CreatingFile,
         WaitingForJob + RequestJobDocument [request_job_handler] = WaitingForJob,
         WaitingForJob + ReceivedJobDocument(JobEventData<'a>) [process_job_handler] = CreatingFile,
-        CreatingFile + StartSelfTest [in_self_test_handler] = WaitingForJob,
+        CreatingFile + StartSelfTest [in_self_test_handler] = SelfTesting,
+        SelfTesting + CheckHealth [check_health_handler] = SelfTesting,
+        SelfTesting + SelfTestComplete = WaitingForJob,
+        SelfTesting + SelfTestTimeout [self_test_timeout_handler] = Restarting,
+        CreatingFile + DescribeStream [describe_stream_handler] = WaitingForStreamDescription,
         CreatingFile + CreateFile [init_file_handler] = RequestingFileBlock,
         CreatingFile + RequestTimer [init_file_handler] = RequestingFileBlock,
-        CreatingFile | WaitingForJob | Restarting + Restart(RestartReason) [restart_handler] = Restarting,
+        WaitingForStreamDescription + RequestTimer [describe_stream_handler] = WaitingForStreamDescription,
+        WaitingForStreamDescription + ReceivedStreamDescription(&'a mut [u8]) [stream_description_handler] = CreatingFile,
+        WaitingForStreamDescription + CloseFile [release_interface_handler] = WaitingForJob,
+        CreatingFile | WaitingForJob | SelfTesting | Restarting + Restart(RestartReason) [restart_handler] = Restarting,
         RequestingFileBlock | WaitingForFileBlock + RequestFileBlock [request_data_handler] = WaitingForFileBlock,
         RequestingFileBlock | WaitingForFileBlock + RequestTimer [request_data_handler] = WaitingForFileBlock,
         WaitingForFileBlock + ReceivedFileBlock(&'a mut [u8]) [process_data_handler]  = WaitingForFileBlock,
         WaitingForFileBlock + ReceivedJobDocument(JobEventData<'a>) [job_notification_handler] = RequestingJob,
         WaitingForFileBlock + CloseFile [close_file_handler] = WaitingForJob,
-        Suspended | RequestingJob | WaitingForJob | CreatingFile | RequestingFileBlock | WaitingForFileBlock + Resume [resume_job_handler] = RequestingJob,
-        Ready | RequestingJob | WaitingForJob | CreatingFile | RequestingFileBlock | WaitingForFileBlock + Suspend = Suspended,
-        Ready | RequestingJob | WaitingForJob | CreatingFile | RequestingFileBlock | WaitingForFileBlock + UserAbort [user_abort_handler] = WaitingForJob,
-        Ready | RequestingJob | WaitingForJob | CreatingFile | RequestingFileBlock | WaitingForFileBlock + Shutdown [shutdown_handler] = Ready,
+        WaitingForFileBlock + Restart(RestartReason) [restart_handler] = Restarting,
+        WaitingForFileBlock + AwaitActivation = ReadyToActivate,
+        ReadyToActivate | Restarting + ScheduleActivation(u32) [schedule_activation_handler] = ReadyToActivate,
+        ReadyToActivate + Activate [activate_handler] = Restarting,
+        ReadyToActivate + RequestTimer [activate_handler] = Restarting,
+        Restarting + CloseFile [close_file_handler] = WaitingForJob,
+        Suspended | RequestingJob | WaitingForJob | CreatingFile | WaitingForStreamDescription | RequestingFileBlock | WaitingForFileBlock | SelfTesting + Resume [resume_job_handler] = RequestingJob,
+        Ready | RequestingJob | WaitingForJob | CreatingFile | WaitingForStreamDescription | RequestingFileBlock | WaitingForFileBlock | SelfTesting + Suspend = Suspended,
+        RequestingJob | CreatingFile | WaitingForStreamDescription | RequestingFileBlock | WaitingForFileBlock | ReadyToActivate | SelfTesting + JobCanceled [job_canceled_handler] = WaitingForJob,
+        Ready | RequestingJob | WaitingForJob | CreatingFile | WaitingForStreamDescription | RequestingFileBlock | WaitingForFileBlock | ReadyToActivate | SelfTesting + UserAbort [user_abort_handler] = WaitingForJob,
+        Ready | RequestingJob | WaitingForJob | CreatingFile | WaitingForStreamDescription | RequestingFileBlock | WaitingForFileBlock | ReadyToActivate | SelfTesting + Shutdown [shutdown_handler] = Ready,
     }
 }
 
@@ -92,6 +123,53 @@ impl defmt::Format for Error {
     }
 }
 
+// smlang only implements `PartialEq` for the generated `States`, but copying
+// and formatting them is needed to report state changes to the observer.
+macro_rules! impl_states {
+    ($($state:ident),*) => {
+        impl Clone for States {
+            fn clone(&self) -> Self {
+                match self {
+                    $(States::$state => States::$state),*
+                }
+            }
+        }
+
+        impl Copy for States {}
+
+        impl core::fmt::Debug for States {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                match self {
+                    $(States::$state => f.write_str(stringify!($state))),*
+                }
+            }
+        }
+
+        #[cfg(feature = "defmt")]
+        impl defmt::Format for States {
+            fn format(&self, fmt: defmt::Formatter) {
+                match self {
+                    $(States::$state => defmt::write!(fmt, "{=str}", stringify!($state))),*
+                }
+            }
+        }
+    };
+}
+
+impl_states!(
+    Ready,
+    RequestingJob,
+    WaitingForJob,
+    CreatingFile,
+    SelfTesting,
+    WaitingForStreamDescription,
+    RequestingFileBlock,
+    WaitingForFileBlock,
+    ReadyToActivate,
+    Restarting,
+    Suspended
+);
+
 pub(crate) enum Interface {
     Primary(FileContext),
     #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
@@ -128,14 +206,35 @@ macro_rules! data_interface {
 }
 
 // Context of current OTA Job, keeping state
-pub(crate) struct SmContext<'a, C, DP, DS, T, ST, PAL, const L: usize, const TIMER_HZ: u32>
-where
+pub(crate) struct SmContext<
+    'a,
+    C,
+    DP,
+    DS,
+    T,
+    ST,
+    PAL,
+    const L: usize,
+    const TIMER_HZ: u32,
+    OB,
+    UP,
+    HC,
+    R,
+    const K: usize,
+    const V: usize,
+    const N: usize,
+    const P: usize,
+> where
     C: ControlInterface,
     DP: DataInterface,
     DS: DataInterface,
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     pub(crate) events: heapless::spsc::Queue<Events<'a>, L>,
     pub(crate) control: &'a C,
@@ -148,13 +247,39 @@ where
     pub(crate) pal: PAL,
     pub(crate) request_momentum: u8,
     pub(crate) request_timer: T,
+    pub(crate) throttle: Throttle<TIMER_HZ>,
     pub(crate) self_test_timer: Option<ST>,
+    pub(crate) observer: OB,
+    pub(crate) policy: UP,
+    pub(crate) health_check: HC,
+    pub(crate) rng: R,
     pub(crate) config: Config,
     pub(crate) image_state: ImageState<PAL::Error>,
+    /// Status details of the active job
+    pub(crate) job_status_details: StatusDetailsOwned<K, V, N>,
+    /// Application status details, attached to every job status update
+    pub(crate) status_details: StatusDetailsOwned<K, V, N>,
 }
 
-impl<'a, C, DP, DS, T, ST, PAL, const L: usize, const TIMER_HZ: u32>
-    SmContext<'a, C, DP, DS, T, ST, PAL, L, TIMER_HZ>
+impl<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const L: usize,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    > SmContext<'a, C, DP, DS, T, ST, PAL, L, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
 where
     C: ControlInterface,
     DP: DataInterface,
@@ -162,19 +287,28 @@ where
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     /// Called to update the filecontext structure from the job
     fn get_file_context_from_job(
         &mut self,
         job_name: &str,
-        ota_document: &OtaJob,
+        ota_document: &dyn JobDocument,
         status_details: Option<StatusDetails>,
     ) -> Result<FileContext, OtaError> {
-        let file_idx = 0;
+        // The files of a job are received one after the other, resuming at the
+        // file recorded in the status details
+        let file_idx = status_details
+            .as_ref()
+            .and_then(|s| s.get(&"file"))
+            .and_then(|file_idx| file_idx.parse().ok())
+            .unwrap_or(0);
 
         if ota_document
-            .files
-            .get(file_idx)
+            .file(file_idx)
             .map(|f| f.filesize)
             .unwrap_or_default()
             == 0
@@ -186,7 +320,7 @@ where
         // reported now
         let cur_file_ctx = self.active_interface.as_mut().map(|i| i.mut_file_ctx());
         let file_ctx = if let Some(file_ctx) = cur_file_ctx {
-            if file_ctx.stream_name != ota_document.streamname {
+            if file_ctx.stream_name != ota_document.stream_name() {
                 info!("New job document received, aborting current job");
 
                 // Abort the current job
@@ -205,23 +339,18 @@ where
                 Ok(FileContext::new_from(
                     job_name,
                     ota_document,
-                    status_details.map(|s| {
-                        s.iter()
-                            .map(|(&k, &v)| (heapless::String::from(k), heapless::String::from(v)))
-                            .collect()
-                    }),
                     file_idx,
                     &self.config,
-                    self.pal.get_active_firmware_version()?,
                 )?)
             } else {
                 // The same job is being reported so update the url
                 info!("New job document ID is identical to the current job: Updating the URL based on the new job document");
                 file_ctx.update_data_url = ota_document
-                    .files
-                    .get(0)
-                    .map(|f| f.update_data_url.map(heapless::String::from))
-                    .ok_or(OtaError::InvalidFile)?;
+                    .file(file_idx)
+                    .ok_or(OtaError::InvalidFile)?
+                    .update_data_url
+                    .map(bounded)
+                    .transpose()?;
 
                 Err(file_ctx.clone())
             }
@@ -229,17 +358,35 @@ where
             Ok(FileContext::new_from(
                 job_name,
                 ota_document,
-                status_details.map(|s| {
-                    s.iter()
-                        .map(|(&k, &v)| (heapless::String::from(k), heapless::String::from(v)))
-                        .collect()
-                }),
                 file_idx,
                 &self.config,
-                self.pal.get_active_firmware_version()?,
             )?)
         };
 
+        // Attach the application status details to a new job
+        if file_ctx.is_ok() {
+            self.job_status_details = job_status_details(
+                status_details.as_ref(),
+                &self.pal.get_active_firmware_version()?,
+            )?;
+            for (key, value) in self.status_details.iter() {
+                self.job_status_details
+                    .insert(key.clone(), value.clone())
+                    .map_err(|_| OtaError::Overflow)?;
+            }
+
+            // Files received before a restart, all of them once activated
+            let received = if self_test(&self.job_status_details) {
+                ota_document.file_count()
+            } else {
+                file_idx
+            };
+            for file_idx in 0..received {
+                let file = FileContext::new_from(job_name, ota_document, file_idx, &self.config)?;
+                self.pal.file_received(&file)?;
+            }
+        }
+
         // If the job is in self test mode, don't start an OTA update but
         // instead do the following:
         //
@@ -249,13 +396,35 @@ where
         //
         // If it's the same or newer, reject the job since either the firmware
         // was not accepted during self test or an incorrect image was sent by
-        // the OTA operator.
-        let mut file_ctx = match file_ctx {
-            Ok(mut file_ctx) if file_ctx.self_test() => {
+        // the OTA operator. If the platform isn't in self test either, the
+        // bootloader rolled back the update, and the job is failed as such.
+        let file_ctx = match file_ctx {
+            Ok(mut file_ctx) if self_test(&self.job_status_details) => {
                 self.handle_self_test_job(&mut file_ctx)?;
                 return Ok(file_ctx);
             }
             Ok(file_ctx) => {
+                match self.policy.check_job(&file_ctx) {
+                    PolicyDecision::Allow => {}
+                    PolicyDecision::Defer => {
+                        info!("Job deferred by the update policy");
+                        self.active_interface = None;
+                        return Err(OtaError::UpdateDeferred);
+                    }
+                    PolicyDecision::Reject(reason) => {
+                        warn!("Job rejected by the update policy");
+                        self.active_interface = None;
+                        self.control.update_job_status::<K, V, N, P>(
+                            &file_ctx,
+                            &mut self.job_status_details,
+                            &self.config,
+                            JobStatus::Rejected,
+                            reason,
+                        )?;
+                        return Err(OtaError::UpdateRejected);
+                    }
+                }
+
                 info!("Job document was accepted. Attempting to begin the update");
                 file_ctx
             }
@@ -266,20 +435,26 @@ where
             }
         };
 
+        // When describing the stream, the file is created once the stream
+        // description has been validated
+        if self.describes_stream(ota_document.protocols()) {
+            return Ok(file_ctx);
+        }
+
         // Create/Open the OTA file on the file system
         if let Err(e) = self.pal.create_file_for_rx(&file_ctx) {
+            let code = e.code();
             self.image_state = Self::set_image_state_with_reason(
                 self.control,
                 &mut self.pal,
                 &self.config,
-                &mut file_ctx,
+                &file_ctx,
+                &mut self.job_status_details,
                 ImageState::Aborted(ImageStateReason::Pal(e)),
             )?;
 
             self.ota_close()?;
-            // FIXME:
-            return Err(OtaError::Pal);
-            // return Err(e.into());
+            return Err(OtaError::Pal(code));
         }
 
         Ok(file_ctx)
@@ -305,6 +480,33 @@ where
         }
     }
 
+    /// Check if the stream should be described before creating the file, which
+    /// is only possible when the file is delivered over MQTT.
+    fn describes_stream(&self, protocols: &[Protocol]) -> bool {
+        let protocol = if protocols.contains(&DP::PROTOCOL) {
+            DP::PROTOCOL
+        } else {
+            DS::PROTOCOL
+        };
+
+        self.config.describe_stream && protocol == Protocol::Mqtt
+    }
+
+    /// Report a request being sent again, after previous requests went
+    /// unanswered
+    fn notify_retry(&mut self) {
+        if self.request_momentum > 0 {
+            self.observer.on_event(&OtaObserverEvent::RequestRetry {
+                attempt: self.request_momentum.saturating_add(1),
+            });
+        }
+    }
+
+    /// Wait before retrying a request, backing off with the request momentum
+    fn request_wait_ms(&mut self) -> u32 {
+        backoff::request_wait_ms(&self.config, self.request_momentum, &mut self.rng)
+    }
+
     /// Check if the current image is `PendingCommit` and thus is in selftest
     fn platform_in_selftest(&mut self) -> bool {
         // Get the platform state from the OTA pal layer
@@ -318,12 +520,33 @@ where
         info!("In self test mode");
 
         let active_version = self.pal.get_active_firmware_version().unwrap_or_default();
+        let updates_self = file_ctx.fileid == 0 && file_ctx.file_type == Some(0);
+
+        // The firmware that performed the update is running again while the
+        // platform isn't in self test, so the bootloader reverted the new
+        // image, eg. after it crashed before completing its self test.
+        if updates_self
+            && !self.platform_in_selftest()
+            && updated_by(&self.job_status_details)
+                .map_or(false, |updated_by| active_version <= updated_by)
+        {
+            error!("The new image was rolled back before completing its self test");
+            self.image_state = Self::set_image_state_with_reason(
+                self.control,
+                &mut self.pal,
+                &self.config,
+                file_ctx,
+                &mut self.job_status_details,
+                ImageState::Rejected(ImageStateReason::RolledBack),
+            )?;
+            self.active_interface = None;
+            return Err(OtaError::RolledBack);
+        }
 
-        let version_check = if file_ctx.fileid == 0 && file_ctx.file_type == Some(0) {
+        let version_check = if updates_self {
             // Only check for versions if the target is self & always allow
             // updates if updated_by is not present.
-            file_ctx
-                .updated_by()
+            updated_by(&self.job_status_details)
                 .map_or(true, |updated_by| active_version > updated_by)
         } else {
             true
@@ -343,6 +566,7 @@ where
                 &mut self.pal,
                 &self.config,
                 file_ctx,
+                &mut self.job_status_details,
                 ImageState::Testing(ImageStateReason::VersionCheck),
             )?;
 
@@ -353,6 +577,7 @@ where
                 &mut self.pal,
                 &self.config,
                 file_ctx,
+                &mut self.job_status_details,
                 ImageState::Rejected(ImageStateReason::VersionCheck),
             )?;
 
@@ -371,7 +596,8 @@ where
         control: &C,
         _pal: &mut PAL,
         config: &Config,
-        file_ctx: &mut FileContext,
+        file_ctx: &FileContext,
+        status_details: &mut StatusDetailsOwned<K, V, N>,
         image_state: ImageState<PAL::Error>,
     ) -> Result<ImageState<PAL::Error>, OtaError> {
         // debug!("set_image_state_with_reason {:?}", image_state);
@@ -403,8 +629,9 @@ where
             ImageState::Testing(_) => {
                 // We discovered we're ready for test mode, put job status
                 // in self_test active
-                control.update_job_status(
+                control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    status_details,
                     config,
                     JobStatus::InProgress,
                     JobStatusReason::SelfTestActive,
@@ -413,19 +640,41 @@ where
             ImageState::Accepted => {
                 // Now that we have accepted the firmware update, we can
                 // complete the job
-                control.update_job_status(
+                control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    status_details,
                     config,
                     JobStatus::Succeeded,
                     JobStatusReason::Accepted,
                 )?;
             }
+            ImageState::Rejected(ImageStateReason::Pal(ref e))
+            | ImageState::Aborted(ImageStateReason::Pal(ref e)) => {
+                // Report the PAL error code with the failed job
+                control.update_job_status::<K, V, N, P>(
+                    file_ctx,
+                    status_details,
+                    config,
+                    JobStatus::Failed,
+                    JobStatusReason::Pal(e.code()),
+                )?;
+            }
+            ImageState::Rejected(ImageStateReason::RolledBack) => {
+                control.update_job_status::<K, V, N, P>(
+                    file_ctx,
+                    status_details,
+                    config,
+                    JobStatus::Failed,
+                    JobStatusReason::RolledBack,
+                )?;
+            }
             ImageState::Rejected(_) => {
                 // The firmware update was rejected, complete the job as
                 // FAILED (Job service will not allow us to set REJECTED
                 // after the job has been started already).
-                control.update_job_status(
+                control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    status_details,
                     config,
                     JobStatus::Failed,
                     JobStatusReason::Rejected,
@@ -435,8 +684,9 @@ where
                 // The firmware update was aborted, complete the job as
                 // FAILED (Job service will not allow us to set REJECTED
                 // after the job has been started already).
-                control.update_job_status(
+                control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    status_details,
                     config,
                     JobStatus::Failed,
                     JobStatusReason::Aborted,
@@ -446,6 +696,173 @@ where
         Ok(image_state)
     }
 
+    /// Close the completed file, and request the job document again to
+    /// receive the next file of the job
+    fn next_file(&mut self) -> Result<(), OtaError> {
+        let file_ctx = self
+            .active_interface
+            .as_mut()
+            .ok_or(OtaError::InvalidInterface)?
+            .mut_file_ctx();
+
+        info!(
+            "File {} of {} received",
+            file_ctx.file_idx + 1,
+            file_ctx.file_count
+        );
+
+        let mut next = heapless::String::<10>::new();
+        next.write_fmt(format_args!("{}", file_ctx.file_idx + 1))
+            .map_err(|_| OtaError::Overflow)?;
+        set_status_detail(&mut self.job_status_details, "file", &next)?;
+
+        self.control.update_job_status::<K, V, N, P>(
+            file_ctx,
+            &mut self.job_status_details,
+            &self.config,
+            JobStatus::InProgress,
+            JobStatusReason::Receiving,
+        )?;
+
+        self.events
+            .enqueue(Events::CloseFile)
+            .map_err(|_| OtaError::SignalEventFailed)?;
+        self.events
+            .enqueue(Events::RequestJobDocument)
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
+    fn accept_self_test(&mut self) -> Result<(), OtaError> {
+        info!("Health checks passed, accepting the new image");
+        let file_ctx = self
+            .active_interface
+            .as_mut()
+            .ok_or(OtaError::InvalidInterface)?
+            .mut_file_ctx();
+
+        self.pal.set_platform_image_state(ImageState::Accepted)?;
+        self.image_state = ImageState::Accepted;
+        self.control.update_job_status::<K, V, N, P>(
+            file_ctx,
+            &mut self.job_status_details,
+            &self.config,
+            JobStatus::Succeeded,
+            JobStatusReason::Accepted,
+        )?;
+
+        // The job succeeded, so it is no longer active
+        self.active_interface = None;
+
+        // Stop the self test timer as it is no longer required
+        if let Some(ref mut self_test_timer) = self.self_test_timer {
+            self_test_timer.cancel().map_err(|_| OtaError::Timer)?;
+        }
+
+        self.events
+            .enqueue(Events::SelfTestComplete)
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
+    /// Reject the image in self test, reporting `reason` in the job status
+    /// details, and reset the device to roll back
+    fn reject_self_test(&mut self, reason: &str) -> Result<(), OtaError> {
+        error!("Health check failed: {}, rejecting the new image", reason);
+
+        // The image may not keep running untested, so it is rejected and the
+        // device reset even if reporting fails
+        let rejected = self
+            .pal
+            .set_platform_image_state(ImageState::Rejected(ImageStateReason::HealthCheck))
+            .map_err(OtaError::from);
+        let canceled = self
+            .self_test_timer
+            .as_mut()
+            .map_or(Ok(()), |timer| timer.cancel().map_err(|_| OtaError::Timer));
+        self.events
+            .enqueue(Events::Restart(RestartReason::Restart(0)))
+            .map_err(|_| OtaError::SignalEventFailed)?;
+
+        let mut detail = heapless::String::<V>::new();
+        for c in reason.chars() {
+            if detail.push(c).is_err() {
+                break;
+            }
+        }
+        if set_status_detail(&mut self.job_status_details, "health_check", &detail).is_err() {
+            warn!("No room left to report the failed health check");
+        }
+
+        let file_ctx = self
+            .active_interface
+            .as_ref()
+            .ok_or(OtaError::InvalidInterface)?
+            .file_ctx();
+        let reported = Self::set_image_state_with_reason(
+            self.control,
+            &mut self.pal,
+            &self.config,
+            file_ctx,
+            &mut self.job_status_details,
+            ImageState::Rejected(ImageStateReason::HealthCheck),
+        );
+        self.image_state = ImageState::Rejected(ImageStateReason::HealthCheck);
+        self.pal.complete_callback(OtaEvent::SelfTestFailed)?;
+
+        rejected.and(canceled).and(reported.map(drop))
+    }
+
+    /// Reject the image in self test when its job ends before the health
+    /// checks completed, returning whether there was one
+    fn end_self_test(&mut self, reason: ImageStateReason<PAL::Error>) -> Result<bool, OtaError> {
+        if !matches!(self.image_state, ImageState::Testing(_)) {
+            return Ok(false);
+        }
+
+        warn!("Self test ended early, rejecting the new image");
+        self.pal
+            .set_platform_image_state(ImageState::Rejected(reason))?;
+        self.pal.complete_callback(OtaEvent::SelfTestFailed)?;
+
+        if let Some(ref mut self_test_timer) = self.self_test_timer {
+            self_test_timer.cancel().map_err(|_| OtaError::Timer)?;
+        }
+        Ok(true)
+    }
+
+    /// Release the data interface of the active job, leaving its file as is
+    fn release_interface(&mut self) -> Result<(), OtaError> {
+        data_interface!(self.cleanup, &self.config)?;
+        self.active_interface = None;
+        Ok(())
+    }
+
+    /// Abort the active job on user request, reporting it as failed. An image
+    /// in self test is rejected.
+    fn abort_job(&mut self) -> Result<(), OtaError> {
+        let in_self_test = self.end_self_test(ImageStateReason::UserAbort)?;
+
+        let file_ctx = self
+            .active_interface
+            .as_ref()
+            .ok_or(OtaError::InvalidInterface)?
+            .file_ctx();
+        self.image_state = Self::set_image_state_with_reason(
+            self.control,
+            &mut self.pal,
+            &self.config,
+            file_ctx,
+            &mut self.job_status_details,
+            ImageState::Aborted(ImageStateReason::UserAbort),
+        )?;
+
+        if in_self_test {
+            // The file was closed before the reset into self test
+            self.release_interface()
+        } else {
+            self.ota_close()
+        }
+    }
+
     pub fn ota_close(&mut self) -> Result<(), OtaError> {
         // Cleanup related to selected protocol.
         data_interface!(self.cleanup, &self.config)?;
@@ -483,20 +900,28 @@ where
                     block.block_id, file_ctx.blocks_remaining
                 );
 
+                self.observer.on_event(&OtaObserverEvent::DuplicateBlock {
+                    block_id: block.block_id,
+                });
+
                 // Just return same progress as before
                 return Ok(false);
             }
 
+            // Leave blocks the PAL can't write yet to be requested again
+            let block_offset = block.block_id * self.config.block_size;
+            if !self.pal.accepts_block(file_ctx, block_offset) {
+                debug!("Block {} can't be written yet, discarding", block.block_id);
+                return Ok(false);
+            }
+
             info!(
                 "Received block {}. {:?} blocks remaining.",
                 block.block_id, file_ctx.blocks_remaining
             );
 
-            self.pal.write_block(
-                file_ctx,
-                block.block_id * self.config.block_size,
-                block.block_payload,
-            )?;
+            self.pal
+                .write_block(file_ctx, block_offset, block.block_payload)?;
 
             file_ctx
                 .bitmap
@@ -504,13 +929,26 @@ where
 
             file_ctx.blocks_remaining -= 1;
 
+            let total = (file_ctx.filesize + self.config.block_size - 1) / self.config.block_size;
+            self.observer.on_event(&OtaObserverEvent::BlockReceived {
+                block_id: block.block_id,
+                received: total - file_ctx.blocks_remaining,
+                total,
+            });
+
             if file_ctx.blocks_remaining == 0 {
                 info!("Received final expected block of file.");
 
                 // Stop the request timer
                 self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
 
-                self.pal.close_file(file_ctx)?;
+                let result = self.pal.close_file(file_ctx);
+                if matches!(result, Ok(()) | Err(OtaPalError::SignatureCheckFailed)) {
+                    self.observer.on_event(&OtaObserverEvent::SignatureResult {
+                        valid: result.is_ok(),
+                    });
+                }
+                result?;
 
                 // Return true to indicate end of file.
                 Ok(true)
@@ -537,8 +975,26 @@ where
     }
 }
 
-impl<'a, C, DP, DS, T, ST, PAL, const L: usize, const TIMER_HZ: u32> StateMachineContext
-    for SmContext<'a, C, DP, DS, T, ST, PAL, L, TIMER_HZ>
+impl<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const L: usize,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    > StateMachineContext
+    for SmContext<'a, C, DP, DS, T, ST, PAL, L, TIMER_HZ, OB, UP, HC, R, K, V, N, P>
 where
     C: ControlInterface,
     DP: DataInterface,
@@ -546,13 +1002,59 @@ where
     T: fugit_timer::Timer<TIMER_HZ>,
     ST: fugit_timer::Timer<TIMER_HZ>,
     PAL: OtaPal,
+    OB: OtaObserver,
+    UP: UpdatePolicy,
+    HC: HealthCheck,
+    R: Rng,
 {
     fn restart_handler(&mut self, reason: &RestartReason) -> Result<(), OtaError> {
         debug!("restart_handler");
         match reason {
             RestartReason::Activate(cnt) if *cnt > self.config.activate_delay => {
-                info!("Application callback! OtaEvent::Activate");
-                self.pal.complete_callback(OtaEvent::Activate)?;
+                let file_ctx = self
+                    .active_interface
+                    .as_mut()
+                    .ok_or(OtaError::InvalidInterface)?
+                    .mut_file_ctx();
+
+                match self.policy.check_activate(file_ctx) {
+                    PolicyDecision::Allow => {
+                        self.ota_close()?;
+
+                        info!("Application callback! OtaEvent::Activate");
+                        self.pal.complete_callback(OtaEvent::Activate)?;
+                    }
+                    PolicyDecision::Defer => {
+                        // Wait for activation, checking again once the request
+                        // timer fires
+                        let wait_ms = self.request_wait_ms();
+                        self.events
+                            .enqueue(Events::ScheduleActivation(wait_ms))
+                            .map_err(|_| OtaError::SignalEventFailed)?;
+                    }
+                    PolicyDecision::Reject(job_reason) => {
+                        warn!("Activation rejected by the update policy");
+
+                        self.pal.set_platform_image_state(ImageState::Rejected(
+                            ImageStateReason::UpdatePolicy,
+                        ))?;
+
+                        self.control.update_job_status::<K, V, N, P>(
+                            file_ctx,
+                            &mut self.job_status_details,
+                            &self.config,
+                            JobStatus::Failed,
+                            job_reason,
+                        )?;
+
+                        // Send event to close file.
+                        self.events
+                            .enqueue(Events::CloseFile)
+                            .map_err(|_| OtaError::SignalEventFailed)?;
+
+                        self.pal.complete_callback(OtaEvent::Fail)?;
+                    }
+                }
             }
             RestartReason::Restart(cnt) if *cnt > self.config.activate_delay => {
                 self.pal.reset_device()?;
@@ -603,12 +1105,14 @@ where
     /// Initiate a request for a job
     fn request_job_handler(&mut self) -> Result<(), OtaError> {
         debug!("request_job_handler");
+        self.notify_retry();
         match self.control.request_job() {
             Err(e) => {
                 if self.request_momentum < self.config.max_request_momentum {
                     // Start request timer
+                    let wait_ms = self.request_wait_ms();
                     self.request_timer
-                        .start(self.config.request_wait_ms.millis())
+                        .start(wait_ms.millis())
                         .map_err(|_| OtaError::Timer)?;
 
                     self.request_momentum += 1;
@@ -625,6 +1129,7 @@ where
                     // Too many requests have been sent without a response or
                     // too many failures when trying to publish the request
                     // message. Abort.
+                    self.observer.on_event(&OtaObserverEvent::MomentumExhausted);
                     Err(OtaError::MomentumAbort)
                 }
             }
@@ -642,12 +1147,14 @@ where
     /// Initialize and handle file transfer
     fn init_file_handler(&mut self) -> Result<(), OtaError> {
         debug!("init_file_handler");
+        self.notify_retry();
         match data_interface!(self.init_file_transfer) {
             Err(e) => {
                 if self.request_momentum < self.config.max_request_momentum {
                     // Start request timer
+                    let wait_ms = self.request_wait_ms();
                     self.request_timer
-                        .start(self.config.request_wait_ms.millis())
+                        .start(wait_ms.millis())
                         .map_err(|_| OtaError::Timer)?;
 
                     self.request_momentum += 1;
@@ -665,6 +1172,7 @@ where
                     // too many failures when trying to publish the request
                     // message. Abort.
 
+                    self.observer.on_event(&OtaObserverEvent::MomentumExhausted);
                     Err(OtaError::MomentumAbort)
                 }
             }
@@ -676,6 +1184,18 @@ where
 
                 info!("Initialized file handler! Requesting file blocks");
 
+                let file_ctx = self
+                    .active_interface
+                    .as_ref()
+                    .ok_or(OtaError::InvalidInterface)?
+                    .file_ctx();
+                self.observer.on_event(&OtaObserverEvent::FileStarted {
+                    file_id: file_ctx.fileid,
+                    file_size: file_ctx.filesize,
+                    total_blocks: (file_ctx.filesize + self.config.block_size - 1)
+                        / self.config.block_size,
+                });
+
                 self.events
                     .enqueue(Events::RequestFileBlock)
                     .map_err(|_| OtaError::SignalEventFailed)?;
@@ -685,6 +1205,129 @@ where
         }
     }
 
+    /// Request a description of the stream, to validate the job document
+    /// before creating the file
+    fn describe_stream_handler(&mut self) -> Result<(), OtaError> {
+        debug!("describe_stream_handler");
+        if self.request_momentum <= self.config.max_request_momentum {
+            self.notify_retry();
+
+            // Start request timer
+            let wait_ms = self.request_wait_ms();
+            self.request_timer
+                .start(wait_ms.millis())
+                .map_err(|_| OtaError::Timer)?;
+
+            self.request_momentum += 1;
+
+            data_interface!(self.request_stream_description)
+        } else {
+            // Stop request timer
+            self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
+
+            let file_ctx = self
+                .active_interface
+                .as_mut()
+                .ok_or(OtaError::InvalidInterface)?
+                .mut_file_ctx();
+
+            self.image_state = Self::set_image_state_with_reason(
+                self.control,
+                &mut self.pal,
+                &self.config,
+                file_ctx,
+                &mut self.job_status_details,
+                ImageState::Aborted(ImageStateReason::MomentumAbort),
+            )?;
+
+            // Send shutdown event to the OTA Agent task
+            self.events
+                .enqueue(Events::Shutdown)
+                .map_err(|_| OtaError::SignalEventFailed)?;
+
+            // Reset the request momentum
+            self.request_momentum = 0;
+
+            self.observer.on_event(&OtaObserverEvent::MomentumExhausted);
+            Err(OtaError::MomentumAbort)
+        }
+    }
+
+    /// Validate the stream description against the job document, and create
+    /// the file
+    fn stream_description_handler(&mut self, payload: &mut [u8]) -> Result<(), OtaError> {
+        debug!("stream_description_handler");
+        match data_interface!(self.validate_stream_description, payload) {
+            Err(e) if e.is_retryable() => {
+                warn!("Failed to decode stream description {:?}", e);
+                return Err(e);
+            }
+            Err(e) => {
+                // Stop the request timer
+                self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
+                self.request_momentum = 0;
+
+                let file_ctx = self
+                    .active_interface
+                    .as_mut()
+                    .ok_or(OtaError::InvalidInterface)?
+                    .mut_file_ctx();
+
+                error!("Stream description does not match the job document, rejecting job");
+
+                self.image_state = Self::set_image_state_with_reason(
+                    self.control,
+                    &mut self.pal,
+                    &self.config,
+                    file_ctx,
+                    &mut self.job_status_details,
+                    ImageState::Rejected(ImageStateReason::StreamMismatch),
+                )?;
+
+                // Send event to close file.
+                self.events
+                    .enqueue(Events::CloseFile)
+                    .map_err(|_| OtaError::SignalEventFailed)?;
+
+                return Err(e);
+            }
+            Ok(()) => {}
+        }
+
+        // Stop the request timer
+        self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
+        self.request_momentum = 0;
+
+        let file_ctx = self
+            .active_interface
+            .as_mut()
+            .ok_or(OtaError::InvalidInterface)?
+            .mut_file_ctx();
+
+        // Create/Open the OTA file on the file system
+        if let Err(e) = self.pal.create_file_for_rx(file_ctx) {
+            let code = e.code();
+            self.image_state = Self::set_image_state_with_reason(
+                self.control,
+                &mut self.pal,
+                &self.config,
+                file_ctx,
+                &mut self.job_status_details,
+                ImageState::Aborted(ImageStateReason::Pal(e)),
+            )?;
+
+            self.events
+                .enqueue(Events::CloseFile)
+                .map_err(|_| OtaError::SignalEventFailed)?;
+
+            return Err(OtaError::Pal(code));
+        }
+
+        self.events
+            .enqueue(Events::CreateFile)
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
     /// Handle self test
     fn in_self_test_handler(&mut self) -> Result<(), OtaError> {
         info!("Beginning self-test");
@@ -699,29 +1342,13 @@ where
             .mut_file_ctx();
 
         if in_self_test {
-            self.pal.complete_callback(OtaEvent::StartTest)?;
             info!("Application callback! OtaEvent::StartTest");
+            self.pal.complete_callback(OtaEvent::StartTest)?;
 
-            self.image_state = ImageState::Accepted;
-            self.control.update_job_status(
-                file_ctx,
-                &self.config,
-                JobStatus::Succeeded,
-                JobStatusReason::Accepted,
-            )?;
-
-            file_ctx
-                .status_details
-                .insert(
-                    heapless::String::from("self_test"),
-                    heapless::String::from(JobStatusReason::Accepted.as_str()),
-                )
-                .map_err(|_| OtaError::Overflow)?;
-
-            // Stop the self test timer as it is no longer required
-            if let Some(ref mut self_test_timer) = self.self_test_timer {
-                self_test_timer.cancel().map_err(|_| OtaError::Timer)?;
-            }
+            // The image is accepted once the health checks passed
+            self.events
+                .enqueue(Events::CheckHealth)
+                .map_err(|_| OtaError::SignalEventFailed)?;
         } else {
             // The job is in self test but the platform image state is not so it
             // could be an attack on the platform image state. Reject the update
@@ -733,6 +1360,7 @@ where
                 &mut self.pal,
                 &self.config,
                 file_ctx,
+                &mut self.job_status_details,
                 ImageState::Rejected(ImageStateReason::ImageStateMismatch),
             )?;
 
@@ -743,6 +1371,27 @@ where
         Ok(())
     }
 
+    /// Poll the health checks of the image in self test
+    fn check_health_handler(&mut self) -> Result<(), OtaError> {
+        match self.health_check.check() {
+            HealthStatus::Pending => self
+                .events
+                .enqueue(Events::CheckHealth)
+                .map_err(|_| OtaError::SignalEventFailed),
+            HealthStatus::Passed => self.accept_self_test(),
+            HealthStatus::Failed(reason) => self.reject_self_test(reason),
+        }
+    }
+
+    /// Handle the health checks not passing within the self test timeout
+    fn self_test_timeout_handler(&mut self) -> Result<(), OtaError> {
+        error!(
+            "Self test failed to complete within {} ms",
+            self.config.self_test_timeout_ms
+        );
+        self.reject_self_test("timeout")
+    }
+
     /// Update file context from job document
     fn process_job_handler(&mut self, data: &JobEventData<'_>) -> Result<(), OtaError> {
         let JobEventData {
@@ -753,16 +1402,18 @@ where
 
         let file_ctx = self.get_file_context_from_job(
             job_name,
-            ota_document,
+            *ota_document,
             status_details.map(Clone::clone),
         )?;
 
-        match self.select_interface(file_ctx, &ota_document.protocols) {
+        match self.select_interface(file_ctx, ota_document.protocols()) {
             Ok(interface) => {
                 info!("Setting OTA data interface");
                 self.active_interface = Some(interface);
+                self.observer
+                    .on_event(&OtaObserverEvent::JobAccepted { job_name });
             }
-            Err(mut file_ctx) => {
+            Err(file_ctx) => {
                 // Failed to set the data interface so abort the OTA. If there
                 // is a valid job id, then a job status update will be sent.
 
@@ -772,20 +1423,15 @@ where
                     self.control,
                     &mut self.pal,
                     &self.config,
-                    &mut file_ctx,
+                    &file_ctx,
+                    &mut self.job_status_details,
                     ImageState::Aborted(ImageStateReason::InvalidDataProtocol),
                 )?;
                 return Err(OtaError::InvalidInterface);
             }
         }
 
-        if self
-            .active_interface
-            .as_mut()
-            .ok_or(OtaError::InvalidInterface)?
-            .file_ctx()
-            .self_test()
-        {
+        if self_test(&self.job_status_details) {
             // If the OTA job is in the self_test state, alert the application layer.
             if matches!(self.image_state, ImageState::Testing(_)) {
                 self.events
@@ -798,9 +1444,15 @@ where
             }
         } else {
             if !self.platform_in_selftest() {
-                // Received a valid context so send event to request file blocks
+                // Received a valid context so send event to request file
+                // blocks, optionally validating the stream first
+                let event = if self.describes_stream(ota_document.protocols()) {
+                    Events::DescribeStream
+                } else {
+                    Events::CreateFile
+                };
                 self.events
-                    .enqueue(Events::CreateFile)
+                    .enqueue(event)
                     .map_err(|_| OtaError::SignalEventFailed)?;
             } else {
                 // Received a job that is not in self-test but platform is, so
@@ -823,12 +1475,32 @@ where
             .ok_or(OtaError::InvalidInterface)?
             .mut_file_ctx();
         if file_ctx.blocks_remaining > 0 {
+            // Pace the requests to the byte rate limit, if any
+            if self.config.max_bytes_per_second > 0 {
+                let bytes = file_ctx.bitmap.len() * self.config.block_size;
+                let now = self.request_timer.now();
+                if let Some(wait) =
+                    self.throttle
+                        .acquire(self.config.max_bytes_per_second, now, bytes as u32)
+                {
+                    debug!("Throttling the file block request");
+                    self.request_timer
+                        .start(wait)
+                        .map_err(|_| OtaError::Timer)?;
+                    return Ok(());
+                }
+            }
+
             // Start the request timer
+            let wait_ms =
+                backoff::request_wait_ms(&self.config, self.request_momentum, &mut self.rng);
             self.request_timer
-                .start(self.config.request_wait_ms.millis())
+                .start(wait_ms.millis())
                 .map_err(|_| OtaError::Timer)?;
 
             if self.request_momentum <= self.config.max_request_momentum {
+                self.notify_retry();
+
                 // Each request increases the momentum until a response is
                 // received. Too much momentum is interpreted as a failure to
                 // communicate and will cause us to abort the OTA.
@@ -846,6 +1518,7 @@ where
                     &mut self.pal,
                     &self.config,
                     file_ctx,
+                    &mut self.job_status_details,
                     ImageState::Aborted(ImageStateReason::MomentumAbort),
                 )?;
 
@@ -860,6 +1533,7 @@ where
                 // Too many requests have been sent without a response or too
                 // many failures when trying to publish the request message.
                 // Abort.
+                self.observer.on_event(&OtaObserverEvent::MomentumExhausted);
                 Err(OtaError::MomentumAbort)
             }
         } else {
@@ -904,8 +1578,14 @@ where
                     .ok_or(OtaError::InvalidInterface)?
                     .mut_file_ctx();
 
-                // File is completed! Update progress accordingly.
-                let (status, reason, event) = if let Some(0) = file_ctx.file_type {
+                if file_ctx.file_idx + 1 < file_ctx.file_count {
+                    return self.next_file();
+                }
+
+                // File is completed! Update progress accordingly. The images
+                // of a job with several files are activated together, each
+                // by the PAL of its component.
+                let (status, reason, event) = if file_ctx.activate {
                     (
                         JobStatus::InProgress,
                         JobStatusReason::SigCheckPassed,
@@ -919,24 +1599,39 @@ where
                     )
                 };
 
-                self.control
-                    .update_job_status(file_ctx, &self.config, status, reason)?;
-
-                // Send event to close file.
-                self.events
-                    .enqueue(Events::CloseFile)
-                    .map_err(|_| OtaError::SignalEventFailed)?;
+                self.control.update_job_status::<K, V, N, P>(
+                    file_ctx,
+                    &mut self.job_status_details,
+                    &self.config,
+                    status,
+                    reason,
+                )?;
 
                 // TODO: Last file block processed, increment the statistics
                 // otaAgent.statistics.otaPacketsProcessed++;
 
                 match event {
+                    OtaEvent::Activate if self.config.manual_activation => {
+                        info!("Image ready, waiting for activation");
+                        self.events
+                            .enqueue(Events::AwaitActivation)
+                            .map_err(|_| OtaError::SignalEventFailed)?;
+                    }
                     OtaEvent::Activate => {
+                        // The file is closed once the update policy allows
+                        // activating it
                         self.events
                             .enqueue(Events::Restart(RestartReason::Activate(0)))
                             .map_err(|_| OtaError::SignalEventFailed)?;
                     }
-                    event => self.pal.complete_callback(event)?,
+                    event => {
+                        // Send event to close file.
+                        self.events
+                            .enqueue(Events::CloseFile)
+                            .map_err(|_| OtaError::SignalEventFailed)?;
+
+                        self.pal.complete_callback(event)?
+                    }
                 };
             }
             Ok(false) => {
@@ -954,8 +1649,9 @@ where
 
                 // We're actively receiving a file so update the job status as
                 // needed
-                self.control.update_job_status(
+                self.control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    &mut self.job_status_details,
                     &self.config,
                     JobStatus::InProgress,
                     JobStatusReason::Receiving,
@@ -965,8 +1661,9 @@ where
                     file_ctx.request_block_remaining -= 1;
                 } else {
                     // Start the request timer.
+                    let wait_ms = self.request_wait_ms();
                     self.request_timer
-                        .start(self.config.request_wait_ms.millis())
+                        .start(wait_ms.millis())
                         .map_err(|_| OtaError::Timer)?;
 
                     self.events
@@ -992,12 +1689,16 @@ where
                     ImageStateReason::FailedIngest,
                 ))?;
 
-                // TODO: Pal reason
-                self.control.update_job_status(
+                let reason = match e {
+                    OtaError::Pal(code) => JobStatusReason::Pal(code),
+                    _ => JobStatusReason::Rejected,
+                };
+                self.control.update_job_status::<K, V, N, P>(
                     file_ctx,
+                    &mut self.job_status_details,
                     &self.config,
                     JobStatus::Failed,
-                    JobStatusReason::Pal(0),
+                    reason,
                 )?;
 
                 // Stop the request timer.
@@ -1019,40 +1720,70 @@ where
         Ok(())
     }
 
+    /// Activate the received image after `delay_ms`
+    fn schedule_activation_handler(&mut self, delay_ms: &u32) -> Result<(), OtaError> {
+        info!("Activation scheduled in {} ms", delay_ms);
+        self.request_timer
+            .start(delay_ms.millis())
+            .map_err(|_| OtaError::Timer)
+    }
+
+    /// Activate the received image, waiting for activation
+    fn activate_handler(&mut self) -> Result<(), OtaError> {
+        debug!("activate_handler");
+        // Stop any scheduled activation
+        self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
+
+        self.events
+            .enqueue(Events::Restart(RestartReason::Activate(0)))
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
     /// Close file opened for download
     fn close_file_handler(&mut self) -> Result<(), OtaError> {
         self.ota_close()
     }
 
+    /// Release a job that ended before its file was created
+    fn release_interface_handler(&mut self) -> Result<(), OtaError> {
+        self.release_interface()
+    }
+
+    /// Abort the active job after it was canceled on the service side. The job
+    /// status is left untouched, as the service rejects any further updates.
+    fn job_canceled_handler(&mut self) -> Result<(), OtaError> {
+        warn!("OTA job canceled!");
+        if self.active_interface.is_none() {
+            return Err(OtaError::NoActiveJob);
+        }
+
+        // Stop the request timer
+        self.request_timer.cancel().map_err(|_| OtaError::Timer)?;
+
+        if self.end_self_test(ImageStateReason::Canceled)? {
+            self.image_state = ImageState::Aborted(ImageStateReason::Canceled);
+            return self.release_interface();
+        }
+
+        self.pal
+            .set_platform_image_state(ImageState::Aborted(ImageStateReason::Canceled))?;
+        self.ota_close()
+    }
+
     /// Handle user interrupt to abort task
     fn user_abort_handler(&mut self) -> Result<(), OtaError> {
         warn!("User abort OTA!");
-        if let Some(ref mut interface) = self.active_interface {
-            self.image_state = Self::set_image_state_with_reason(
-                self.control,
-                &mut self.pal,
-                &self.config,
-                interface.mut_file_ctx(),
-                ImageState::Aborted(ImageStateReason::UserAbort),
-            )?;
-            self.ota_close()
-        } else {
-            Err(OtaError::NoActiveJob)
+        if self.active_interface.is_none() {
+            return Err(OtaError::NoActiveJob);
         }
+        self.abort_job()
     }
 
     /// Handle user interrupt to abort task
     fn shutdown_handler(&mut self) -> Result<(), OtaError> {
         warn!("Shutting down OTA!");
-        if let Some(ref mut interface) = self.active_interface {
-            self.image_state = Self::set_image_state_with_reason(
-                self.control,
-                &mut self.pal,
-                &self.config,
-                interface.mut_file_ctx(),
-                ImageState::Aborted(ImageStateReason::UserAbort),
-            )?;
-            self.ota_close()?;
+        if self.active_interface.is_some() {
+            self.abort_job()?;
         }
         Ok(())
     }
@@ -1065,7 +1796,7 @@ mod tests {
             agent::OtaAgent,
             pal::Version,
             test::{
-                mock::{MockPal, MockTimer},
+                mock::{MemPal, MockTimer},
                 test_job_doc,
             },
         },
@@ -1085,24 +1816,62 @@ mod tests {
 
         let request_timer = MockTimer::new();
         let self_test_timer = MockTimer::new();
-        let pal = MockPal {};
+        let pal = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
 
         let mut agent = OtaAgent::builder(&mqtt, &mqtt, request_timer, pal)
             .with_self_test_timeout(self_test_timer, 32000)
             .build();
 
         let ota_job = test_job_doc();
-        let mut file_ctx = FileContext::new_from(
-            "Job-name",
-            &ota_job,
-            None,
-            0,
-            &Config::default(),
-            Version::new(0, 1, 0),
-        )
-        .unwrap();
+        let mut file_ctx =
+            FileContext::new_from("Job-name", &ota_job, 0, &Config::default()).unwrap();
+        let updated_by: Version = Version::new(0, 1, 0);
 
         let context = agent.state.context_mut();
+        context.job_status_details = job_status_details(None, &updated_by).unwrap();
+
+        assert_eq!(context.handle_self_test_job(&mut file_ctx), Ok(()));
+
+        assert!(
+            matches!(context.image_state, ImageState::Testing(_)),
+            "Unexpected image state"
+        );
+    }
+
+    #[test]
+    fn version_check_pre_release() {
+        let mqtt = MockMqtt::new();
+
+        let request_timer = MockTimer::new();
+        let self_test_timer = MockTimer::new();
+        let pal = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
+
+        let mut agent = OtaAgent::builder(&mqtt, &mqtt, request_timer, pal)
+            .with_self_test_timeout(self_test_timer, 32000)
+            .build();
+
+        // The release `1.0.0` is newer than its release candidate
+        let ota_job = test_job_doc();
+        let mut file_ctx =
+            FileContext::new_from("Job-name", &ota_job, 0, &Config::default()).unwrap();
+        let updated_by: Version = "1.0.0-rc.1+build.7".parse().unwrap();
+
+        let context = agent.state.context_mut();
+        context.job_status_details = job_status_details(None, &updated_by).unwrap();
+
+        assert_eq!(
+            context
+                .job_status_details
+                .get(&heapless::String::from("updated_by"))
+                .map(|v| v.as_str()),
+            Some("1.0.0-rc.1")
+        );
 
         assert_eq!(context.handle_self_test_job(&mut file_ctx), Ok(()));
 
@@ -1118,24 +1887,22 @@ mod tests {
 
         let request_timer = MockTimer::new();
         let self_test_timer = MockTimer::new();
-        let pal = MockPal {};
+        let pal = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
 
         let mut agent = OtaAgent::builder(&mqtt, &mqtt, request_timer, pal)
             .with_self_test_timeout(self_test_timer, 32000)
             .build();
 
         let ota_job = test_job_doc();
-        let mut file_ctx = FileContext::new_from(
-            "Job-name",
-            &ota_job,
-            None,
-            0,
-            &Config::default(),
-            Version::new(1, 1, 0),
-        )
-        .unwrap();
+        let mut file_ctx =
+            FileContext::new_from("Job-name", &ota_job, 0, &Config::default()).unwrap();
+        let updated_by: Version = Version::new(1, 1, 0);
 
         let context = agent.state.context_mut();
+        context.job_status_details = job_status_details(None, &updated_by).unwrap();
 
         assert_eq!(context.handle_self_test_job(&mut file_ctx), Ok(()));
 
@@ -1145,13 +1912,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn version_check_rolled_back() {
+        let mqtt = MockMqtt::new();
+
+        let mut agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MemPal::default())
+            .with_self_test_timeout(MockTimer::new(), 32000)
+            .build();
+
+        // The bootloader reverted to the firmware that performed the update
+        let ota_job = test_job_doc();
+        let mut file_ctx =
+            FileContext::new_from("Job-name", &ota_job, 0, &Config::default()).unwrap();
+        let updated_by: Version = Version::new(1, 0, 0);
+
+        let context = agent.state.context_mut();
+        context.job_status_details = job_status_details(None, &updated_by).unwrap();
+
+        assert_eq!(
+            context.handle_self_test_job(&mut file_ctx),
+            Err(OtaError::RolledBack)
+        );
+
+        assert!(
+            matches!(
+                context.image_state,
+                ImageState::Rejected(ImageStateReason::RolledBack)
+            ),
+            "Unexpected image state"
+        );
+    }
+
     #[test]
     fn version_check_allow_donwgrade() {
         let mqtt = MockMqtt::new();
 
         let request_timer = MockTimer::new();
         let self_test_timer = MockTimer::new();
-        let pal = MockPal {};
+        let pal = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
 
         let mut agent = OtaAgent::builder(&mqtt, &mqtt, request_timer, pal)
             .with_self_test_timeout(self_test_timer, 32000)
@@ -1159,17 +1960,12 @@ mod tests {
             .build();
 
         let ota_job = test_job_doc();
-        let mut file_ctx = FileContext::new_from(
-            "Job-name",
-            &ota_job,
-            None,
-            0,
-            &Config::default(),
-            Version::new(1, 1, 0),
-        )
-        .unwrap();
+        let mut file_ctx =
+            FileContext::new_from("Job-name", &ota_job, 0, &Config::default()).unwrap();
+        let updated_by: Version = Version::new(1, 1, 0);
 
         let context = agent.state.context_mut();
+        context.job_status_details = job_status_details(None, &updated_by).unwrap();
 
         assert_eq!(context.handle_self_test_job(&mut file_ctx), Ok(()));
 
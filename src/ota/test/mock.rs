@@ -1,6 +1,10 @@
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+
 use crate::ota::{
     encoding::FileContext,
-    pal::{ImageState, OtaPal, OtaPalError, PalImageState, Version},
+    pal::{ImageReader, ImageState, OtaPal, OtaPalError, PalImageState, Version},
 };
 
 use super::TEST_TIMER_HZ;
@@ -10,10 +14,14 @@ use super::TEST_TIMER_HZ;
 ///
 pub struct MockTimer {
     pub is_started: bool,
+    pub now: u32,
 }
 impl MockTimer {
     pub fn new() -> Self {
-        Self { is_started: false }
+        Self {
+            is_started: false,
+            now: 0,
+        }
     }
 }
 
@@ -21,7 +29,7 @@ impl fugit_timer::Timer<TEST_TIMER_HZ> for MockTimer {
     type Error = ();
 
     fn now(&mut self) -> fugit_timer::TimerInstantU32<TEST_TIMER_HZ> {
-        todo!()
+        fugit_timer::TimerInstantU32::from_ticks(self.now)
     }
 
     fn start(
@@ -91,3 +99,169 @@ impl OtaPal for MockPal {
         Ok(Version::new(1, 0, 0))
     }
 }
+
+///
+/// In-memory platform abstraction layer used for unit tests, keeping the
+/// active image and the received image in memory.
+///
+#[derive(Default)]
+pub struct MemPal {
+    pub active: Vec<u8>,
+    pub image: Vec<u8>,
+    pub closed_size: Option<usize>,
+    /// Report the image as `PendingCommit`, booted in self test.
+    pub self_test: bool,
+    /// Set when the image is accepted or rejected.
+    pub accepted: Option<bool>,
+    pub activated: bool,
+    pub aborted: bool,
+}
+
+impl MemPal {
+    /// PAL running the `active` image.
+    pub fn running(active: &[u8]) -> Self {
+        Self {
+            active: active.to_vec(),
+            ..Self::default()
+        }
+    }
+}
+
+impl OtaPal for MemPal {
+    type Error = ();
+
+    fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.image.clear();
+        self.aborted = true;
+        Ok(())
+    }
+
+    fn create_file_for_rx(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.image.clear();
+        self.closed_size = None;
+        Ok(())
+    }
+
+    fn get_platform_image_state(&mut self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        if self.self_test && self.accepted.is_none() {
+            Ok(PalImageState::PendingCommit)
+        } else {
+            Ok(PalImageState::Valid)
+        }
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState<Self::Error>,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        match image_state {
+            ImageState::Accepted => self.accepted = Some(true),
+            ImageState::Rejected(_) => self.accepted = Some(false),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.activated = true;
+        Ok(())
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.closed_size = Some(file.filesize);
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        _file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        let end = block_offset + block_payload.len();
+        if self.image.len() < end {
+            self.image.resize(end, 0);
+        }
+        self.image[block_offset..end].copy_from_slice(block_payload);
+        Ok(block_payload.len())
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        Ok(Version::new(1, 0, 0))
+    }
+}
+
+impl ImageReader for MemPal {
+    fn read_active_image(
+        &mut self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        let data = self
+            .active
+            .get(offset..offset + buf.len())
+            .ok_or(OtaPalError::BadFileHandle)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+///
+/// In-memory NOR flash used for unit tests, asserting that only erased bytes
+/// are written.
+///
+pub struct MemFlash {
+    pub data: Vec<u8>,
+}
+
+impl MemFlash {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0xFF; size],
+        }
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 1024;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.data[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        for (old, new) in self.data[offset..].iter_mut().zip(bytes) {
+            assert_eq!(*old, 0xFF, "write to non-erased flash");
+            *old = *new;
+        }
+        Ok(())
+    }
+}
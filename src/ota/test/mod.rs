@@ -1,3 +1,6 @@
+use embedded_storage::nor_flash::NorFlash;
+
+use self::mock::MemFlash;
 use super::{
     config::Config,
     data_interface::Protocol,
@@ -5,13 +8,32 @@ use super::{
         json::{FileDescription, OtaJob},
         FileContext,
     },
-    pal::Version,
+    pal::dual_bank::{DualBankPal, Slot},
 };
 
 pub mod mock;
 
 pub const TEST_TIMER_HZ: u32 = 8_000_000;
 
+/// Banks of 0x800 bytes, on [`MemFlash`] of 0x1000 bytes.
+pub const DUAL_BANK_SLOTS: [Slot; 2] = [
+    Slot {
+        offset: 0,
+        size: 0x800,
+    },
+    Slot {
+        offset: 0x800,
+        size: 0x800,
+    },
+];
+
+/// Dual-bank PAL running the `active` image from bank A.
+pub fn dual_bank_pal(active: &[u8]) -> DualBankPal<MemFlash> {
+    let mut flash = MemFlash::new(0x1000);
+    flash.write(0, active).unwrap();
+    DualBankPal::new(flash, DUAL_BANK_SLOTS, || {}).unwrap()
+}
+
 pub fn test_job_doc() -> OtaJob<'static> {
     OtaJob {
         protocols: heapless::Vec::from_slice(&[Protocol::Mqtt]).unwrap(),
@@ -25,6 +47,7 @@ pub fn test_job_doc() -> OtaJob<'static> {
             auth_scheme: None,
             sha1_rsa: Some(""),
             file_type: Some(0),
+            encryption: None,
             sha256_rsa: None,
             sha1_ecdsa: None,
             sha256_ecdsa: None,
@@ -35,24 +58,45 @@ pub fn test_job_doc() -> OtaJob<'static> {
 
 pub fn test_file_ctx(config: &Config) -> FileContext {
     let ota_job = test_job_doc();
-    FileContext::new_from("Job-name", &ota_job, None, 0, config, Version::default()).unwrap()
+    FileContext::new_from("Job-name", &ota_job, 0, config).unwrap()
+}
+
+/// File with `file_type`, written through a pipeline stage.
+pub fn stage_file_ctx(file_type: Option<u32>) -> FileContext {
+    let mut file_ctx = test_file_ctx(&Config::default());
+    file_ctx.file_type = file_type;
+    file_ctx
 }
 
 pub mod ota_tests {
-    use crate::jobs::data_types::{DescribeJobExecutionResponse, JobExecution, JobStatus};
+    use crate::jobs::data_types::{
+        DescribeJobExecutionResponse, ErrorResponse, JobExecution, JobStatus,
+    };
+    use crate::jobs::{
+        update_payload_len, StatusDetails, StatusDetailsOwned, MAX_STATUS_DETAILS,
+        MAX_STATUS_DETAIL_KEY_LEN, MAX_STATUS_DETAIL_VALUE_LEN, MAX_UPDATE_PAYLOAD_LEN,
+    };
     use crate::ota::data_interface::Protocol;
-    use crate::ota::encoding::json::{FileDescription, OtaJob};
+    use crate::ota::encoding::json::{FileDescription, JobStatusReason, OtaJob, Signature};
+    use crate::ota::encoding::{FileContext, JobDocument};
     use crate::ota::error::OtaError;
     use crate::ota::state::{Error, Events, States};
     use crate::ota::test::test_job_doc;
     use crate::ota::{
         agent::OtaAgent,
+        backoff::Rng,
+        config::Config,
         control_interface::ControlInterface,
         data_interface::{DataInterface, NoInterface},
-        pal::OtaPal,
-        test::mock::{MockPal, MockTimer},
+        health::{HealthCheck, HealthStatus},
+        observer::{OtaObserver, OtaObserverEvent},
+        pal::{router::RouterPal, OtaPal, OtaPalError, PalErrorCode},
+        pipeline::{delta::DeltaPal, reorder::ReorderPal},
+        policy::{PolicyDecision, UpdatePolicy},
+        test::mock::{MemPal, MockPal, MockTimer},
     };
     use crate::test::MockMqtt;
+    use core::cell::RefCell;
     use mqttrust::encoding::v4::{decode_slice, utils::Pid, PacketType};
     use mqttrust::{MqttError, Packet, QoS, SubscribeTopic};
     use serde::Deserialize;
@@ -62,6 +106,7 @@ pub mod ota_tests {
 
     /// All known job document that the device knows how to process.
     #[derive(Debug, PartialEq, Deserialize)]
+    #[allow(clippy::large_enum_variant)]
     pub enum JobDetails<'a> {
         #[serde(rename = "afr_ota")]
         #[serde(borrow)]
@@ -84,8 +129,25 @@ pub mod ota_tests {
             .build()
     }
 
-    fn run_to_state<'a, C, DP, DS, T, ST, PAL, const TIMER_HZ: u32>(
-        agent: &mut OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ>,
+    fn run_to_state<
+        'a,
+        C,
+        DP,
+        DS,
+        T,
+        ST,
+        PAL,
+        const TIMER_HZ: u32,
+        OB,
+        UP,
+        HC,
+        R,
+        const K: usize,
+        const V: usize,
+        const N: usize,
+        const P: usize,
+    >(
+        agent: &mut OtaAgent<'a, C, DP, DS, T, ST, PAL, TIMER_HZ, OB, UP, HC, R, K, V, N, P>,
         state: States,
     ) where
         C: ControlInterface,
@@ -94,6 +156,10 @@ pub mod ota_tests {
         T: fugit_timer::Timer<TIMER_HZ>,
         ST: fugit_timer::Timer<TIMER_HZ>,
         PAL: OtaPal,
+        OB: OtaObserver,
+        UP: UpdatePolicy,
+        HC: HealthCheck,
+        R: Rng,
     {
         if agent.state.state() == &state {
             return;
@@ -118,6 +184,14 @@ pub mod ota_tests {
                 agent.job_update("Test-job", &job_doc, None).unwrap();
                 agent.state.context_mut().events.dequeue();
             }
+            States::WaitingForStreamDescription => {
+                println!(
+                    "Running to 'States::WaitingForStreamDescription', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::CreatingFile);
+                agent.state.process_event(Events::DescribeStream).unwrap();
+            }
             States::RequestingFileBlock => {
                 println!(
                     "Running to 'States::RequestingFileBlock', events: {}",
@@ -161,7 +235,7 @@ pub mod ota_tests {
                 run_to_state(agent, States::RequestingJob);
                 agent.check_for_update().unwrap();
             }
-            States::Restarting => {}
+            States::ReadyToActivate | States::SelfTesting | States::Restarting => {}
         }
     }
 
@@ -286,10 +360,16 @@ pub mod ota_tests {
 
         assert_eq!(
             topics,
-            vec![SubscribeTopic {
-                topic_path: "$aws/things/test_client/jobs/notify-next",
-                qos: QoS::AtLeastOnce
-            }]
+            vec![
+                SubscribeTopic {
+                    topic_path: "$aws/things/test_client/jobs/notify-next",
+                    qos: QoS::AtLeastOnce
+                },
+                SubscribeTopic {
+                    topic_path: "$aws/things/test_client/jobs/+/update/rejected",
+                    qos: QoS::AtLeastOnce
+                }
+            ]
         );
 
         let mut bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
@@ -396,6 +476,241 @@ pub mod ota_tests {
         assert_eq!(mqtt.tx.borrow_mut().len(), 2);
     }
 
+    fn new_describing_agent(
+        mqtt: &MockMqtt,
+    ) -> OtaAgent<'_, MockMqtt, &MockMqtt, NoInterface, MockTimer, MockTimer, MemPal, TEST_TIMER_HZ>
+    {
+        OtaAgent::builder(mqtt, mqtt, MockTimer::new(), MemPal::default())
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .describe_stream()
+            .build()
+    }
+
+    /// CBOR encoded stream description of a single file with ID 0
+    fn stream_description(file_size: u32) -> Vec<u8> {
+        let mut description = vec![
+            0xA3, 0x61, b's', 0x01, 0x61, b'd', 0x60, 0x61, b'r', 0x81, 0xA2, 0x61, b'f', 0x00,
+            0x61, b'z', 0x1A,
+        ];
+        description.extend_from_slice(&file_size.to_be_bytes());
+        description
+    }
+
+    #[test]
+    fn describe_stream_before_create_file() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_describing_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        let job_doc = test_job_doc();
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::WaitingForStreamDescription
+        ));
+
+        let bytes = mqtt.tx.borrow_mut().pop_back().unwrap();
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => assert_eq!(
+                p.topic_name,
+                "$aws/things/test_client/streams/test_stream/describe/cbor"
+            ),
+            _ => panic!(),
+        }
+
+        assert!(matches!(
+            ota_agent
+                .handle_stream_description(&mut stream_description(123456))
+                .unwrap(),
+            &States::CreatingFile
+        ));
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::RequestingFileBlock
+        ));
+    }
+
+    #[test]
+    fn describe_stream_size_mismatch() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_describing_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForStreamDescription);
+        assert!(matches!(
+            ota_agent.state(),
+            &States::WaitingForStreamDescription
+        ));
+
+        assert_eq!(
+            ota_agent
+                .handle_stream_description(&mut stream_description(1234))
+                .err(),
+            Some(Error::GuardFailed(OtaError::FileSizeMismatch))
+        );
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+
+        // The job is failed and the agent waits for the next job, without
+        // aborting the file that was never created
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::WaitingForJob
+        ));
+        let ctx = ota_agent.state.context();
+        assert!(ctx.active_interface.is_none());
+        assert!(!ctx.pal.aborted);
+    }
+
+    /// Custom job document, with a single component
+    struct ComponentJob {
+        component: &'static str,
+        url: &'static str,
+        size: usize,
+        signature: &'static str,
+    }
+
+    impl JobDocument for ComponentJob {
+        fn protocols(&self) -> &[Protocol] {
+            &[Protocol::Mqtt]
+        }
+
+        fn stream_name(&self) -> &str {
+            self.component
+        }
+
+        fn file(&self, file_idx: usize) -> Option<FileDescription<'_>> {
+            (file_idx == 0).then_some(FileDescription {
+                filepath: self.component,
+                filesize: self.size,
+                fileid: 0,
+                certfile: "",
+                update_data_url: Some(self.url),
+                auth_scheme: None,
+                sha1_rsa: None,
+                sha256_rsa: None,
+                sha1_ecdsa: None,
+                sha256_ecdsa: Some(self.signature),
+                file_type: Some(0),
+                encryption: None,
+            })
+        }
+    }
+
+    #[test]
+    fn custom_job_document() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let job_doc = ComponentJob {
+            component: "app",
+            url: "https://example.com/app.bin",
+            size: 300,
+            signature: "c2lnbmF0dXJl",
+        };
+        assert!(matches!(
+            ota_agent.job_update("Test-job", &job_doc, None).unwrap(),
+            &States::CreatingFile
+        ));
+
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.filepath.as_str(), "app");
+        assert_eq!(file_ctx.stream_name.as_str(), "app");
+        assert_eq!(file_ctx.filesize, 300);
+        assert_eq!(
+            file_ctx.update_data_url.as_deref(),
+            Some("https://example.com/app.bin")
+        );
+        assert_eq!(
+            file_ctx.signature,
+            Signature::Sha256Ecdsa(heapless::String::from("c2lnbmF0dXJl"))
+        );
+
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::RequestingFileBlock
+        ));
+    }
+
+    #[test]
+    fn resume_multi_file_job() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let mut job_doc = test_job_doc();
+        let mut modem = job_doc.files[0].clone();
+        modem.filepath = "modem";
+        modem.fileid = 1;
+        modem.file_type = Some(1);
+        job_doc.files.push(modem).unwrap();
+
+        // The first file was received before the device reconnected
+        let mut status_details = StatusDetails::new();
+        status_details.insert("file", "1").unwrap();
+        assert!(matches!(
+            ota_agent
+                .job_update("Test-job", &job_doc, Some(&status_details))
+                .unwrap(),
+            &States::CreatingFile
+        ));
+
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.file_idx, 1);
+        assert_eq!(file_ctx.file_count, 2);
+        assert_eq!(file_ctx.filepath.as_str(), "modem");
+        assert_eq!(file_ctx.fileid, 1);
+    }
+
+    #[test]
+    fn resume_routed_job() {
+        let mqtt = MockMqtt::new();
+        let pal = RouterPal::new((MemPal::default(), MemPal::default()), |file| {
+            file.file_type.unwrap_or_default() as usize
+        });
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), pal)
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let mut job_doc = test_job_doc();
+        let mut modem = job_doc.files[0].clone();
+        modem.fileid = 1;
+        modem.file_type = Some(1);
+        job_doc.files.push(modem).unwrap();
+
+        // The MCU image was received before the device restarted
+        let mut status_details = StatusDetails::new();
+        status_details.insert("file", "1").unwrap();
+        ota_agent
+            .job_update("Test-job", &job_doc, Some(&status_details))
+            .unwrap();
+        ota_agent.process_event().unwrap();
+
+        let pal = &mut ota_agent.state.context_mut().pal;
+        pal.activate_new_image().unwrap();
+        let (mcu, modem) = pal.inner();
+        assert!(mcu.activated);
+        assert!(modem.activated);
+    }
+
     #[test]
     fn request_file_block_mqtt() {
         let mqtt = MockMqtt::new();
@@ -450,6 +765,897 @@ pub mod ota_tests {
         assert_eq!(mqtt.tx.borrow_mut().len(), 3);
     }
 
+    /// CBOR encoded file block of `len` bytes for file ID 0
+    #[test]
+    fn throttle_file_block_requests() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .max_bytes_per_second(8192)
+            .build();
+
+        run_to_state(&mut ota_agent, States::RequestingFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        // Each request is for 31 blocks of 256 bytes
+        ota_agent
+            .state
+            .process_event(Events::RequestFileBlock)
+            .unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 1);
+
+        // Retrying right away exceeds the byte rate, so the request is
+        // postponed without increasing the momentum
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 1);
+        assert_eq!(ota_agent.state.context().request_momentum, 1);
+
+        ota_agent.state.context_mut().request_timer.now += TEST_TIMER_HZ;
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 2);
+
+        // The limit can be lifted at runtime
+        ota_agent.set_max_bytes_per_second(0);
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 3);
+    }
+
+    fn file_block(block_id: u8, len: u16) -> Vec<u8> {
+        data_block(block_id, &vec![0xAA; len as usize])
+    }
+
+    fn data_block(block_id: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u16).to_be_bytes();
+        let mut block = vec![
+            0xA4, 0x61, b'f', 0x00, 0x61, b'i', block_id, 0x61, b'l', 0x19,
+        ];
+        block.extend_from_slice(&len);
+        block.extend_from_slice(&[0x61, b'p', 0x59]);
+        block.extend_from_slice(&len);
+        block.extend_from_slice(payload);
+        block
+    }
+
+    /// Patch adding 1 to the 100 bytes of the active image, followed by 180
+    /// new bytes
+    fn delta_patch() -> Vec<u8> {
+        let mut patch = b"DLT1".to_vec();
+        patch.extend_from_slice(&280u32.to_le_bytes());
+        patch.extend_from_slice(&100u32.to_le_bytes());
+        patch.extend_from_slice(&180u32.to_le_bytes());
+        patch.extend_from_slice(&0i32.to_le_bytes());
+        patch.extend_from_slice(&[1; 100]);
+        patch.extend_from_slice(&[0xAA; 180]);
+        patch
+    }
+
+    #[test]
+    fn delta_update_reordered_blocks() {
+        let mqtt = MockMqtt::new();
+        let pal: ReorderPal<_, 1, 256> =
+            ReorderPal::new(DeltaPal::new(MemPal::running(&[1; 100]), 3, 1024));
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), pal)
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let patch = delta_patch();
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filesize = patch.len();
+        job_doc.files[0].file_type = Some(3);
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        // The second block is held until the first block is written
+        ota_agent
+            .handle_message(&mut data_block(1, &patch[256..]))
+            .unwrap();
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.blocks_remaining, 1);
+        assert!(!file_ctx.bitmap.get(1));
+        assert!(ota_agent
+            .state
+            .context()
+            .pal
+            .inner()
+            .inner()
+            .image
+            .is_empty());
+
+        ota_agent.process_event().unwrap();
+        ota_agent
+            .handle_message(&mut data_block(0, &patch[..256]))
+            .unwrap();
+
+        let pal = ota_agent.state.context().pal.inner().inner();
+        assert_eq!(pal.closed_size, Some(280));
+        assert_eq!(&pal.image[..100], &[2; 100]);
+        assert_eq!(&pal.image[100..], &[0xAA; 180]);
+    }
+
+    #[test]
+    fn delta_update_sequential_blocks() {
+        let mqtt = MockMqtt::new();
+        let pal = DeltaPal::new(MemPal::running(&[1; 100]), 3, 1024);
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), pal)
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let patch = delta_patch();
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filesize = patch.len();
+        job_doc.files[0].file_type = Some(3);
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        // The second block is discarded until the first block is written
+        ota_agent
+            .handle_message(&mut data_block(1, &patch[256..]))
+            .unwrap();
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.blocks_remaining, 2);
+        assert!(file_ctx.bitmap.get(1));
+
+        ota_agent
+            .handle_message(&mut data_block(0, &patch[..256]))
+            .unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent
+            .handle_message(&mut data_block(1, &patch[256..]))
+            .unwrap();
+
+        let pal = ota_agent.state.context().pal.inner();
+        assert_eq!(pal.closed_size, Some(280));
+        assert_eq!(&pal.image[..100], &[2; 100]);
+        assert_eq!(&pal.image[100..], &[0xAA; 180]);
+    }
+
+    #[test]
+    fn observer_reports_progress() {
+        let mqtt = MockMqtt::new();
+        let events = RefCell::new(Vec::new());
+
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_observer(|e: &OtaObserverEvent<'_>| {
+                events.borrow_mut().push(format!("{:?}", e));
+            })
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        events.borrow_mut().clear();
+
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filesize = 300;
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        // The first request went unanswered
+        ota_agent.timer_callback().unwrap();
+
+        ota_agent.handle_message(&mut file_block(0, 256)).unwrap();
+        ota_agent.handle_message(&mut file_block(0, 256)).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.handle_message(&mut file_block(1, 44)).unwrap();
+        ota_agent.process_event().unwrap();
+
+        assert_eq!(
+            events.borrow().as_slice(),
+            &[
+                "JobAccepted { job_name: \"Test-job\" }",
+                "StateChanged { from: WaitingForJob, to: CreatingFile }",
+                "FileStarted { file_id: 0, file_size: 300, total_blocks: 2 }",
+                "StateChanged { from: CreatingFile, to: RequestingFileBlock }",
+                "StateChanged { from: RequestingFileBlock, to: WaitingForFileBlock }",
+                "RequestRetry { attempt: 2 }",
+                "BlockReceived { block_id: 0, received: 1, total: 2 }",
+                "DuplicateBlock { block_id: 0 }",
+                "BlockReceived { block_id: 1, received: 2, total: 2 }",
+                "SignatureResult { valid: true }",
+                "StateChanged { from: WaitingForFileBlock, to: Restarting }",
+            ]
+        );
+    }
+
+    struct TestPolicy {
+        job: PolicyDecision,
+        activate: PolicyDecision,
+    }
+
+    impl UpdatePolicy for TestPolicy {
+        fn check_job(&mut self, _file_ctx: &FileContext) -> PolicyDecision {
+            self.job
+        }
+
+        fn check_activate(&mut self, _file_ctx: &FileContext) -> PolicyDecision {
+            self.activate
+        }
+    }
+
+    #[test]
+    fn job_canceled_notify_next() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        // The active job is still the next pending job
+        ota_agent.handle_notify_next(Some("Test-job")).unwrap();
+        assert!(matches!(ota_agent.state(), &States::WaitingForFileBlock));
+
+        assert!(matches!(
+            ota_agent.handle_notify_next(None).unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+
+        // The job status is not updated after cancellation
+        assert!(!mqtt.tx.borrow_mut().iter().any(|bytes| {
+            let (header, _) = mqttrust::encoding::v4::decoder::read_header(bytes, &mut 0)
+                .unwrap()
+                .unwrap();
+            header.typ == PacketType::Publish
+        }));
+    }
+
+    #[test]
+    fn job_canceled_update_rejected() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        let (throttled, _) = from_slice::<ErrorResponse>(
+            br#"{"code":"RequestThrottled","message":"Throttled","timestamp":1}"#,
+        )
+        .unwrap();
+        ota_agent
+            .handle_update_rejected("Test-job", &throttled)
+            .unwrap();
+        assert!(matches!(ota_agent.state(), &States::WaitingForFileBlock));
+
+        let (terminal, _) = from_slice::<ErrorResponse>(
+            br#"{"code":"TerminalStateReached","message":"Canceled","timestamp":1}"#,
+        )
+        .unwrap();
+        ota_agent
+            .handle_update_rejected("Other-job", &terminal)
+            .unwrap();
+        assert!(matches!(ota_agent.state(), &States::WaitingForFileBlock));
+
+        assert!(matches!(
+            ota_agent
+                .handle_update_rejected("Test-job", &terminal)
+                .unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+    }
+
+    #[test]
+    fn update_policy_defers_job() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_update_policy(TestPolicy {
+                job: PolicyDecision::Defer,
+                activate: PolicyDecision::Allow,
+            })
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        let job_doc = test_job_doc();
+        assert_eq!(
+            ota_agent.job_update("Test-job", &job_doc, None).err(),
+            Some(Error::GuardFailed(OtaError::UpdateDeferred))
+        );
+
+        // The job is left untouched, and the agent keeps waiting for it
+        assert!(matches!(ota_agent.state(), &States::WaitingForJob));
+        assert!(ota_agent.state.context().active_interface.is_none());
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+
+        ota_agent.state.context_mut().policy.job = PolicyDecision::Allow;
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        assert!(matches!(ota_agent.state(), &States::CreatingFile));
+    }
+
+    #[test]
+    fn update_policy_rejects_job() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_update_policy(TestPolicy {
+                job: PolicyDecision::Reject(JobStatusReason::Rejected),
+                activate: PolicyDecision::Allow,
+            })
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        let job_doc = test_job_doc();
+        assert_eq!(
+            ota_agent.job_update("Test-job", &job_doc, None).err(),
+            Some(Error::GuardFailed(OtaError::UpdateRejected))
+        );
+        assert!(matches!(ota_agent.state(), &States::WaitingForJob));
+
+        let mut bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        set_pid(bytes.as_mut_slice(), Pid::new()).expect("Failed to set valid PID");
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => {
+                assert_eq!(p.topic_name, "$aws/things/test_client/jobs/Test-job/update");
+                let payload = core::str::from_utf8(p.payload).unwrap();
+                assert!(payload.contains(r#""status":"REJECTED""#));
+                assert!(payload.contains(r#""self_test":"rejected""#));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn pal_error_code_reported() {
+        let mqtt = MockMqtt::new();
+        let config = Config::default();
+        let file_ctx = super::test_file_ctx(&config);
+        let mut status_details: StatusDetailsOwned = StatusDetailsOwned::new();
+
+        assert_eq!(
+            OtaError::from(OtaPalError::<()>::SignatureCheckFailed),
+            OtaError::Pal(0xE3)
+        );
+
+        // Platform errors report their own code
+        struct FlashError;
+        impl PalErrorCode for FlashError {
+            fn code(&self) -> u32 {
+                0x1005
+            }
+        }
+        assert_eq!(
+            OtaError::from(OtaPalError::Custom(FlashError)),
+            OtaError::Pal(0x1005)
+        );
+        assert_eq!(OtaError::from(OtaPalError::Custom(())), OtaError::Pal(0xFF));
+
+        mqtt.update_job_status::<
+            MAX_STATUS_DETAIL_KEY_LEN,
+            MAX_STATUS_DETAIL_VALUE_LEN,
+            MAX_STATUS_DETAILS,
+            MAX_UPDATE_PAYLOAD_LEN,
+        >(
+            &file_ctx,
+            &mut status_details,
+            &config,
+            JobStatus::Failed,
+            JobStatusReason::Pal(0xE3),
+        )
+        .unwrap();
+
+        let mut bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        set_pid(bytes.as_mut_slice(), Pid::new()).expect("Failed to set valid PID");
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => {
+                let payload = core::str::from_utf8(p.payload).unwrap();
+                assert!(payload.contains(r#""status":"FAILED""#));
+                assert!(payload.contains(r#""reason":"0x000000e3""#));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn custom_status_details() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+
+        ota_agent
+            .set_status_detail("bootloader_version", "2.1.0")
+            .unwrap();
+        assert_eq!(
+            ota_agent.set_status_detail("a_status_detail_key_too_long", "1"),
+            Err(OtaError::Overflow)
+        );
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        let job_doc = test_job_doc();
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.set_status_detail("free_flash", "524288").unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        let status_details = &ota_agent.state.context().job_status_details;
+        assert_eq!(
+            status_details
+                .get(&heapless::String::from("bootloader_version"))
+                .map(|v| v.as_str()),
+            Some("2.1.0")
+        );
+        assert_eq!(
+            status_details
+                .get(&heapless::String::from("free_flash"))
+                .map(|v| v.as_str()),
+            Some("524288")
+        );
+    }
+
+    #[test]
+    fn sized_status_details() {
+        const PAYLOAD_LEN: usize = update_payload_len(32, 64, 12);
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_status_details::<32, 64, 12, PAYLOAD_LEN>()
+            .build();
+
+        // Fill the status details up, leaving room for `updated_by` and
+        // `self_test`
+        for i in 0..10 {
+            let key = format!("{:0>32}", i);
+            let value = format!("{:0>64}", i);
+            ota_agent.set_status_detail(&key, &value).unwrap();
+        }
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        let job_doc = test_job_doc();
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.abort().unwrap();
+
+        let status_details = &ota_agent.state.context().job_status_details;
+        assert_eq!(status_details.len(), status_details.capacity());
+
+        let publishes: Vec<_> = mqtt
+            .tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|mut bytes| {
+                set_pid(bytes.as_mut_slice(), Pid::new()).expect("Failed to set valid PID");
+                match decode_slice(bytes.as_slice()).unwrap() {
+                    Some(Packet::Publish(p)) if p.topic_name.ends_with("/update") => {
+                        Some(core::str::from_utf8(p.payload).unwrap().to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let payload = publishes.last().expect("No job status update");
+        assert!(payload.len() > MAX_UPDATE_PAYLOAD_LEN);
+        assert!(payload.contains(&format!(r#""{:0>32}":"{:0>64}""#, 9, 9)));
+        assert!(payload.contains(r#""self_test":"aborted""#));
+    }
+
+    #[test]
+    fn update_policy_defers_activation() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_update_policy(TestPolicy {
+                job: PolicyDecision::Allow,
+                activate: PolicyDecision::Defer,
+            })
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filesize = 300;
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        ota_agent.handle_message(&mut file_block(0, 256)).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.handle_message(&mut file_block(1, 44)).unwrap();
+
+        // Activation is deferred without failing the update, until the
+        // request timer fires
+        for _ in 0..10 {
+            ota_agent.process_event().unwrap();
+        }
+        assert!(matches!(ota_agent.state(), &States::ReadyToActivate));
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+        assert!(ota_agent.state.context().request_timer.is_started);
+
+        ota_agent.timer_callback().unwrap();
+        for _ in 0..10 {
+            ota_agent.process_event().unwrap();
+        }
+        assert!(matches!(ota_agent.state(), &States::ReadyToActivate));
+        assert!(ota_agent.state.context().active_interface.is_some());
+
+        ota_agent.state.context_mut().policy.activate = PolicyDecision::Allow;
+        ota_agent.timer_callback().unwrap();
+        for _ in 0..10 {
+            ota_agent.process_event().unwrap();
+        }
+        assert!(ota_agent.state.context().active_interface.is_none());
+    }
+
+    #[test]
+    fn manual_activation() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .manual_activation()
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].filesize = 300;
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+
+        ota_agent.handle_message(&mut file_block(0, 256)).unwrap();
+        ota_agent.process_event().unwrap();
+        ota_agent.handle_message(&mut file_block(1, 44)).unwrap();
+
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::ReadyToActivate
+        ));
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+        assert!(ota_agent.state.context().active_interface.is_some());
+
+        // The job reports the image as ready, and stays in progress
+        let mut bytes = mqtt.tx.borrow_mut().pop_back().unwrap();
+        set_pid(bytes.as_mut_slice(), Pid::new()).expect("Failed to set valid PID");
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => {
+                let payload = core::str::from_utf8(p.payload).unwrap();
+                assert!(payload.contains(r#""status":"IN_PROGRESS""#));
+                assert!(payload.contains(r#""self_test":"ready""#));
+            }
+            _ => panic!(),
+        }
+
+        assert!(matches!(ota_agent.activate().unwrap(), &States::Restarting));
+        while ota_agent.state.context().active_interface.is_some() {
+            assert!(matches!(
+                ota_agent.process_event().unwrap(),
+                &States::Restarting
+            ));
+        }
+    }
+
+    #[test]
+    fn scheduled_activation() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal {})
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .manual_activation()
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        ota_agent
+            .state
+            .process_event(Events::AwaitActivation)
+            .unwrap();
+        assert!(matches!(ota_agent.state(), &States::ReadyToActivate));
+
+        assert!(matches!(
+            ota_agent.schedule_activation(60_000).unwrap(),
+            &States::ReadyToActivate
+        ));
+        assert!(ota_agent.state.context().request_timer.is_started);
+
+        ota_agent.timer_callback().unwrap();
+        assert!(matches!(ota_agent.state(), &States::Restarting));
+        assert!(!ota_agent.state.context().request_timer.is_started);
+    }
+
+    struct TestHealth(HealthStatus);
+
+    impl HealthCheck for TestHealth {
+        fn check(&mut self) -> HealthStatus {
+            self.0
+        }
+    }
+
+    fn new_self_test_agent(
+        mqtt: &MockMqtt,
+    ) -> OtaAgent<
+        '_,
+        MockMqtt,
+        &MockMqtt,
+        NoInterface,
+        MockTimer,
+        MockTimer,
+        MemPal,
+        TEST_TIMER_HZ,
+        crate::ota::observer::NoObserver,
+        crate::ota::policy::AllowAll,
+        TestHealth,
+    > {
+        let pal = MemPal {
+            self_test: true,
+            ..MemPal::default()
+        };
+        let mut ota_agent = OtaAgent::builder(mqtt, mqtt, MockTimer::new(), pal)
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .with_health_check(TestHealth(HealthStatus::Pending))
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        // The job reports the image as ready for self test
+        let mut status_details = StatusDetails::new();
+        status_details.insert("self_test", "ready").unwrap();
+        let job_doc = test_job_doc();
+        ota_agent
+            .job_update("Test-job", &job_doc, Some(&status_details))
+            .unwrap();
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::SelfTesting
+        ));
+        ota_agent
+    }
+
+    fn last_status(mqtt: &MockMqtt) -> String {
+        mqtt.tx
+            .borrow_mut()
+            .iter_mut()
+            .rev()
+            .find_map(|bytes| {
+                set_pid(bytes.as_mut_slice(), Pid::new()).expect("Failed to set valid PID");
+                match decode_slice(bytes.as_slice()).unwrap() {
+                    Some(Packet::Publish(p)) => {
+                        Some(String::from(core::str::from_utf8(p.payload).unwrap()))
+                    }
+                    _ => None,
+                }
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn self_test_health_check_passed() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        // The image isn't accepted while the health checks are pending
+        for _ in 0..5 {
+            assert!(matches!(
+                ota_agent.process_event().unwrap(),
+                &States::SelfTesting
+            ));
+        }
+        assert_eq!(ota_agent.state.context().pal.accepted, None);
+
+        ota_agent.state.context_mut().health_check.0 = HealthStatus::Passed;
+        ota_agent.process_event().unwrap();
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::WaitingForJob
+        ));
+        assert_eq!(ota_agent.state.context().pal.accepted, Some(true));
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"SUCCEEDED""#));
+        assert!(payload.contains(r#""self_test":"accepted""#));
+    }
+
+    #[test]
+    fn notify_next_after_self_test() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        ota_agent.state.context_mut().health_check.0 = HealthStatus::Passed;
+        ota_agent.process_event().unwrap();
+        ota_agent.process_event().unwrap();
+        assert!(ota_agent.state.context().active_interface.is_none());
+
+        // The succeeded job is no longer the next pending job execution
+        assert!(matches!(
+            ota_agent.handle_notify_next(None).unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(matches!(
+            ota_agent.handle_notify_next(Some("Next-job")).unwrap(),
+            &States::WaitingForJob
+        ));
+    }
+
+    #[test]
+    fn self_test_health_check_failed() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        ota_agent.state.context_mut().health_check.0 = HealthStatus::Failed("sensor");
+        ota_agent.process_event().unwrap();
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::Restarting
+        ));
+        assert_eq!(ota_agent.state.context().pal.accepted, Some(false));
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""self_test":"rejected""#));
+        assert!(payload.contains(r#""health_check":"sensor""#));
+    }
+
+    #[test]
+    fn self_test_rejected_with_full_status_details() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        let mut i = 0;
+        while ota_agent
+            .set_status_detail(&format!("detail_{}", i), "1")
+            .is_ok()
+        {
+            i += 1;
+        }
+
+        ota_agent.state.context_mut().health_check.0 = HealthStatus::Failed("sensor");
+        ota_agent.process_event().unwrap();
+        assert!(matches!(
+            ota_agent.process_event().unwrap(),
+            &States::Restarting
+        ));
+        let ctx = ota_agent.state.context();
+        assert_eq!(ctx.pal.accepted, Some(false));
+        assert!(!ctx.self_test_timer.as_ref().unwrap().is_started);
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(!payload.contains(r#""health_check""#));
+    }
+
+    #[test]
+    fn self_test_timeout() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        // `MockTimer` always expires, so the timeout is signaled directly
+        assert!(matches!(
+            ota_agent
+                .state
+                .process_event(Events::SelfTestTimeout)
+                .unwrap(),
+            &States::Restarting
+        ));
+        assert_eq!(ota_agent.state.context().pal.accepted, Some(false));
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""health_check":"timeout""#));
+    }
+
+    #[test]
+    fn self_test_abort() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        assert!(matches!(ota_agent.abort().unwrap(), &States::WaitingForJob));
+        let ctx = ota_agent.state.context();
+        assert_eq!(ctx.pal.accepted, Some(false));
+        assert!(!ctx.self_test_timer.as_ref().unwrap().is_started);
+        assert!(ctx.active_interface.is_none());
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""self_test":"aborted""#));
+    }
+
+    #[test]
+    fn self_test_shutdown() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        assert!(matches!(
+            ota_agent.state.process_event(Events::Shutdown).unwrap(),
+            &States::Ready
+        ));
+        let ctx = ota_agent.state.context();
+        assert_eq!(ctx.pal.accepted, Some(false));
+        assert!(!ctx.self_test_timer.as_ref().unwrap().is_started);
+        assert!(ctx.active_interface.is_none());
+    }
+
+    #[test]
+    fn self_test_canceled() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+        mqtt.tx.borrow_mut().clear();
+
+        assert!(matches!(
+            ota_agent.handle_notify_next(None).unwrap(),
+            &States::WaitingForJob
+        ));
+        let ctx = ota_agent.state.context();
+        assert_eq!(ctx.pal.accepted, Some(false));
+        assert!(!ctx.self_test_timer.as_ref().unwrap().is_started);
+        assert!(ctx.active_interface.is_none());
+
+        // The canceled job is not updated anymore
+        assert!(mqtt
+            .tx
+            .borrow()
+            .iter()
+            .all(|bytes| !matches!(decode_slice(bytes.as_slice()), Ok(Some(Packet::Publish(_))))));
+    }
+
+    #[test]
+    fn self_test_suspend() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_self_test_agent(&mqtt);
+
+        assert!(matches!(ota_agent.suspend().unwrap(), &States::Suspended));
+        assert_eq!(ota_agent.state.context().pal.accepted, None);
+        assert!(matches!(
+            ota_agent.resume().unwrap(),
+            &States::RequestingJob
+        ));
+    }
+
+    #[test]
+    fn rollback_reported() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MemPal::default())
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        // The job is in self test, but the bootloader reverted to the firmware
+        // that performed the update
+        let mut status_details = StatusDetails::new();
+        status_details.insert("self_test", "active").unwrap();
+        status_details.insert("updated_by", "1.0.0").unwrap();
+        let job_doc = test_job_doc();
+        assert_eq!(
+            ota_agent
+                .job_update("Test-job", &job_doc, Some(&status_details))
+                .err(),
+            Some(Error::GuardFailed(OtaError::RolledBack))
+        );
+        assert!(matches!(ota_agent.state(), &States::WaitingForJob));
+        assert!(ota_agent.state.context().active_interface.is_none());
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        let payload = last_status(&mqtt);
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""self_test":"rolled-back""#));
+    }
+
     #[test]
     fn deserialize_describe_job_execution_response_ota() {
         let payload = br#"{
@@ -502,6 +1708,7 @@ pub mod ota_tests {
                             sha1_ecdsa: None,
                             sha256_ecdsa: Some("This is my signature! Better believe it!"),
                             file_type: Some(0),
+                            encryption: None,
                         }])
                         .unwrap(),
                     })),
@@ -0,0 +1,109 @@
+//! Byte rate limit of the file block requests, so the download doesn't
+//! saturate a constrained link.
+//!
+//! A token bucket, refilled at `max_bytes_per_second` from the clock of the
+//! request timer, paces the requests. The bucket holds up to a second worth of
+//! bytes, or a single request if more, and starts out full.
+
+use fugit_timer::{TimerDurationU32, TimerInstantU32};
+
+pub(crate) struct Throttle<const TIMER_HZ: u32> {
+    /// Available bytes, scaled by `TIMER_HZ` to refill without rounding
+    tokens: u64,
+    last: Option<TimerInstantU32<TIMER_HZ>>,
+}
+
+impl<const TIMER_HZ: u32> Throttle<TIMER_HZ> {
+    pub(crate) fn new() -> Self {
+        Self {
+            tokens: 0,
+            last: None,
+        }
+    }
+
+    /// Take `bytes` from the bucket refilled at `bytes_per_second`, or return
+    /// the time to wait for enough bytes to be available, leaving the bucket
+    /// untouched.
+    pub(crate) fn acquire(
+        &mut self,
+        bytes_per_second: u32,
+        now: TimerInstantU32<TIMER_HZ>,
+        bytes: u32,
+    ) -> Option<TimerDurationU32<TIMER_HZ>> {
+        let rate = bytes_per_second as u64;
+        let capacity = rate.max(bytes as u64) * TIMER_HZ as u64;
+        let needed = bytes as u64 * TIMER_HZ as u64;
+
+        self.tokens = match self.last {
+            Some(last) => {
+                let elapsed = now
+                    .checked_duration_since(last)
+                    .map_or(0, |elapsed| elapsed.ticks());
+                self.tokens
+                    .saturating_add((elapsed as u64).saturating_mul(rate))
+                    .min(capacity)
+            }
+            None => capacity,
+        };
+        self.last = Some(now);
+
+        if self.tokens >= needed {
+            self.tokens -= needed;
+            None
+        } else {
+            let wait = (needed - self.tokens + rate - 1) / rate;
+            Some(TimerDurationU32::from_ticks(
+                wait.min(u32::MAX as u64) as u32
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HZ: u32 = 1000;
+
+    fn at(ms: u32) -> TimerInstantU32<HZ> {
+        TimerInstantU32::from_ticks(ms)
+    }
+
+    #[test]
+    fn burst_then_pace() {
+        let mut throttle = Throttle::<HZ>::new();
+
+        // A second worth of bytes is available right away
+        assert_eq!(throttle.acquire(1024, at(0), 512), None);
+        assert_eq!(throttle.acquire(1024, at(0), 512), None);
+        assert_eq!(
+            throttle.acquire(1024, at(0), 512),
+            Some(TimerDurationU32::millis(500))
+        );
+
+        assert_eq!(
+            throttle.acquire(1024, at(250), 512),
+            Some(TimerDurationU32::millis(250))
+        );
+        assert_eq!(throttle.acquire(1024, at(500), 512), None);
+
+        // The bucket doesn't fill beyond its capacity while idle
+        assert_eq!(throttle.acquire(1024, at(10_000), 1024), None);
+        assert_eq!(
+            throttle.acquire(1024, at(10_000), 256),
+            Some(TimerDurationU32::millis(250))
+        );
+    }
+
+    #[test]
+    fn request_larger_than_rate() {
+        let mut throttle = Throttle::<HZ>::new();
+
+        assert_eq!(throttle.acquire(100, at(0), 400), None);
+        assert_eq!(
+            throttle.acquire(100, at(1000), 400),
+            Some(TimerDurationU32::millis(3000))
+        );
+        assert_eq!(throttle.acquire(100, at(4000), 400), None);
+    }
+}
@@ -0,0 +1,590 @@
+//! # AWS IoT MQTT-based file delivery
+//!
+//! Streams created in AWS IoT can deliver arbitrary files to devices over
+//! MQTT, such as ML models or audio assets, independently of OTA firmware
+//! updates. Each stream contains one or more files, identified by a file ID,
+//! which are downloaded in blocks:
+//! - `$aws/things/{thingName}/streams/{streamId}/describe/cbor` requests the
+//!   description of the stream, published on `.../description/cbor`.
+//! - `$aws/things/{thingName}/streams/{streamId}/get/cbor` requests a window of
+//!   blocks, published on `.../data/cbor`.
+//! - Errors are published on `.../rejected/cbor`.
+//!
+//! [`Download`] downloads a single file of a stream into a [`StreamSink`],
+//! using the same windowed block requests and retry logic as the OTA agent.
+//!
+//! <https://docs.aws.amazon.com/iot/latest/developerguide/mqtt-based-file-delivery.html>
+use fugit_timer::ExtU32;
+use mqttrust::{Mqtt, QoS, SubscribeTopic};
+
+use crate::jobs::{MAX_STREAM_ID_LEN, MAX_THING_NAME_LEN};
+use crate::ota::data_interface::mqtt::{Encoding, OtaTopic, Topic};
+use crate::ota::data_interface::FileBlock;
+use crate::ota::encoding::cbor::{
+    self, DescribeStreamRequest, DescribeStreamResponse, GetStreamRequest, GetStreamResponse,
+    StreamError,
+};
+use crate::ota::encoding::Bitmap;
+use crate::ota::error::OtaError;
+
+pub const MAX_TOPIC_LEN: usize = MAX_STREAM_ID_LEN + MAX_THING_NAME_LEN + 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Overflow,
+    Encoding,
+    Mqtt(mqttrust::MqttError),
+    /// The stream service rejected a request, eg. because the stream does not
+    /// exist or has expired.
+    Rejected,
+    /// The stream does not contain the requested file.
+    FileNotFound,
+    /// Too many requests were sent without receiving a response.
+    MomentumAbort,
+    BlockOutOfRange,
+    Sink,
+    Timer,
+}
+
+impl From<mqttrust::MqttError> for Error {
+    fn from(e: mqttrust::MqttError) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+impl From<OtaError> for Error {
+    fn from(e: OtaError) -> Self {
+        match e {
+            OtaError::Mqtt(e) => Self::Mqtt(e),
+            OtaError::Encoding => Self::Encoding,
+            _ => Self::Overflow,
+        }
+    }
+}
+
+/// Destination of a downloaded stream file.
+pub trait StreamSink {
+    type Error;
+
+    /// Called once the size of the file is known, before any block is written.
+    fn begin(&mut self, _file_size: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Write a block of the file at `offset`. Blocks may arrive out of order.
+    fn write_block(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Called once every block of the file has been written.
+    fn complete(&mut self) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Progress {
+    /// Waiting for the stream description.
+    Describing,
+    Downloading {
+        blocks_remaining: usize,
+    },
+    Complete,
+}
+
+/// Downloads a single file of a stream.
+pub struct Download<'a, M, S, T, const TIMER_HZ: u32>
+where
+    M: Mqtt,
+    S: StreamSink,
+    T: fugit_timer::Timer<TIMER_HZ>,
+{
+    mqtt: &'a M,
+    sink: S,
+    request_timer: T,
+    stream_name: &'a str,
+    file_id: u8,
+    file_size: Option<usize>,
+
+    block_size: usize,
+    max_request_momentum: u8,
+    request_wait_ms: u32,
+
+    block_offset: u32,
+    blocks_remaining: usize,
+    request_block_remaining: u32,
+    bitmap: Option<Bitmap>,
+    request_momentum: u8,
+}
+
+impl<'a, M, S, T, const TIMER_HZ: u32> Download<'a, M, S, T, TIMER_HZ>
+where
+    M: Mqtt,
+    S: StreamSink,
+    T: fugit_timer::Timer<TIMER_HZ>,
+{
+    pub fn new(mqtt: &'a M, stream_name: &'a str, file_id: u8, sink: S, request_timer: T) -> Self {
+        Self {
+            mqtt,
+            sink,
+            request_timer,
+            stream_name,
+            file_id,
+            file_size: None,
+            block_size: 256,
+            max_request_momentum: 3,
+            request_wait_ms: 8000,
+            block_offset: 0,
+            blocks_remaining: 0,
+            request_block_remaining: 0,
+            bitmap: None,
+            request_momentum: 0,
+        }
+    }
+
+    /// Skip describing the stream when the size of the file is already known,
+    /// eg. from a job document.
+    pub fn file_size(mut self, file_size: usize) -> Self {
+        self.file_size = Some(file_size);
+        self
+    }
+
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn max_request_momentum(mut self, max_request_momentum: u8) -> Self {
+        self.max_request_momentum = max_request_momentum;
+        self
+    }
+
+    pub fn request_wait_ms(mut self, request_wait_ms: u32) -> Self {
+        self.request_wait_ms = request_wait_ms;
+        self
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn progress(&self) -> Progress {
+        match self.bitmap {
+            None => Progress::Describing,
+            Some(_) if self.blocks_remaining == 0 => Progress::Complete,
+            Some(_) => Progress::Downloading {
+                blocks_remaining: self.blocks_remaining,
+            },
+        }
+    }
+
+    /// Subscribe to the stream topics, and request either the stream
+    /// description or the first window of blocks.
+    pub fn start(&mut self) -> Result<Progress, Error> {
+        let client_id = self.mqtt.client_id();
+        let data =
+            OtaTopic::Data(Encoding::Cbor, self.stream_name).format::<MAX_TOPIC_LEN>(client_id)?;
+        let description = OtaTopic::Description(Encoding::Cbor, self.stream_name)
+            .format::<MAX_TOPIC_LEN>(client_id)?;
+        let rejected = OtaTopic::Rejected(Encoding::Cbor, self.stream_name)
+            .format::<MAX_TOPIC_LEN>(client_id)?;
+
+        self.mqtt.subscribe(&[
+            SubscribeTopic {
+                topic_path: data.as_str(),
+                qos: QoS::AtLeastOnce,
+            },
+            SubscribeTopic {
+                topic_path: description.as_str(),
+                qos: QoS::AtLeastOnce,
+            },
+            SubscribeTopic {
+                topic_path: rejected.as_str(),
+                qos: QoS::AtLeastOnce,
+            },
+        ])?;
+
+        match self.file_size {
+            Some(file_size) => self.begin(file_size)?,
+            None => self.request()?,
+        }
+
+        Ok(self.progress())
+    }
+
+    /// Must be called periodically to re-send requests that did not receive a
+    /// response within `request_wait_ms`.
+    pub fn timer_callback(&mut self) -> Result<(), Error> {
+        if self.request_timer.wait().is_ok() && self.progress() != Progress::Complete {
+            self.request()?;
+        }
+        Ok(())
+    }
+
+    /// Handle an incoming message on one of the stream topics.
+    ///
+    /// Returns `Ok(None)` if `topic_name` is not a topic of this stream.
+    pub fn handle_message(
+        &mut self,
+        topic_name: &str,
+        payload: &mut [u8],
+    ) -> Result<Option<Progress>, Error> {
+        match Topic::from_str(topic_name) {
+            Some(Topic::Description(Encoding::Cbor, stream)) if stream == self.stream_name => {
+                if self.bitmap.is_none() {
+                    let description: DescribeStreamResponse =
+                        serde_cbor::de::from_mut_slice(payload).map_err(|_| Error::Encoding)?;
+
+                    let file = description
+                        .files
+                        .iter()
+                        .find(|f| f.file_id == self.file_id)
+                        .ok_or(Error::FileNotFound)?;
+
+                    self.begin(file.file_size)?;
+                }
+            }
+            Some(Topic::Data(Encoding::Cbor, stream)) if stream == self.stream_name => {
+                let block: FileBlock = serde_cbor::de::from_mut_slice::<GetStreamResponse>(payload)
+                    .map_err(|_| Error::Encoding)?
+                    .into();
+
+                if block.file_id == self.file_id {
+                    self.ingest_block(block)?;
+                }
+            }
+            Some(Topic::Rejected(Encoding::Cbor, stream)) if stream == self.stream_name => {
+                self.request_timer.cancel().map_err(|_| Error::Timer)?;
+
+                let error: StreamError =
+                    serde_cbor::de::from_mut_slice(payload).map_err(|_| Error::Encoding)?;
+                error!(
+                    "Stream request rejected: {:?} {:?}",
+                    error.error_code, error.error_message
+                );
+                return Err(Error::Rejected);
+            }
+            _ => return Ok(None),
+        }
+
+        Ok(Some(self.progress()))
+    }
+
+    fn begin(&mut self, file_size: usize) -> Result<(), Error> {
+        if file_size == 0 {
+            return Err(Error::FileNotFound);
+        }
+
+        self.sink.begin(file_size).map_err(|_| Error::Sink)?;
+
+        self.file_size = Some(file_size);
+        self.block_offset = 0;
+        self.blocks_remaining = (file_size + self.block_size - 1) / self.block_size;
+        self.bitmap = Some(Bitmap::new(file_size, self.block_size, 0));
+        self.request_momentum = 0;
+
+        self.request()
+    }
+
+    fn ingest_block(&mut self, block: FileBlock<'_>) -> Result<(), Error> {
+        let (file_size, bitmap) = match (self.file_size, self.bitmap.as_mut()) {
+            (Some(file_size), Some(bitmap)) if self.blocks_remaining > 0 => (file_size, bitmap),
+            _ => return Ok(()),
+        };
+
+        if !block.validate(self.block_size, file_size) {
+            error!(
+                "Block {:?} out of expected range! Size {:?}",
+                block.block_id, block.block_size
+            );
+            return Err(Error::BlockOutOfRange);
+        }
+
+        if block.block_id < self.block_offset as usize
+            || !bitmap.get(block.block_id - self.block_offset as usize)
+        {
+            debug!("Block {:?} is a DUPLICATE", block.block_id);
+            return Ok(());
+        }
+
+        self.sink
+            .write_block(block.block_id * self.block_size, block.block_payload)
+            .map_err(|_| Error::Sink)?;
+
+        bitmap.set(block.block_id - self.block_offset as usize, false);
+        self.blocks_remaining -= 1;
+        self.request_momentum = 0;
+
+        if self.blocks_remaining == 0 {
+            self.request_timer.cancel().map_err(|_| Error::Timer)?;
+            self.sink.complete().map_err(|_| Error::Sink)?;
+            return Ok(());
+        }
+
+        if bitmap.is_empty() {
+            self.block_offset += 31;
+            *bitmap = Bitmap::new(file_size, self.block_size, self.block_offset);
+        }
+
+        if self.request_block_remaining > 1 {
+            self.request_block_remaining -= 1;
+            Ok(())
+        } else {
+            self.request()
+        }
+    }
+
+    /// Request the stream description, or the current window of blocks.
+    fn request(&mut self) -> Result<(), Error> {
+        if self.request_momentum > self.max_request_momentum {
+            self.request_timer.cancel().map_err(|_| Error::Timer)?;
+            self.request_momentum = 0;
+            return Err(Error::MomentumAbort);
+        }
+
+        self.request_timer
+            .start(self.request_wait_ms.millis())
+            .map_err(|_| Error::Timer)?;
+        self.request_momentum += 1;
+
+        let buf = &mut [0u8; 32];
+        let (topic, len) = match self.bitmap {
+            None => (
+                OtaTopic::Describe(Encoding::Cbor, self.stream_name),
+                cbor::to_slice(&DescribeStreamRequest { client_token: None }, buf),
+            ),
+            Some(ref bitmap) => {
+                self.request_block_remaining = bitmap.len() as u32;
+                (
+                    OtaTopic::Get(Encoding::Cbor, self.stream_name),
+                    cbor::to_slice(
+                        &GetStreamRequest {
+                            client_token: None,
+                            stream_version: None,
+                            file_id: self.file_id,
+                            block_size: self.block_size,
+                            block_offset: Some(self.block_offset),
+                            block_bitmap: Some(bitmap),
+                            number_of_blocks: None,
+                        },
+                        buf,
+                    ),
+                )
+            }
+        };
+        let len = len.map_err(|_| Error::Encoding)?;
+
+        self.mqtt.publish(
+            topic
+                .format::<MAX_TOPIC_LEN>(self.mqtt.client_id())?
+                .as_str(),
+            &buf[..len],
+            QoS::AtMostOnce,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl<'a, M, S, T, const TIMER_HZ: u32> Drop for Download<'a, M, S, T, TIMER_HZ>
+where
+    M: Mqtt,
+    S: StreamSink,
+    T: fugit_timer::Timer<TIMER_HZ>,
+{
+    fn drop(&mut self) {
+        self.request_timer.cancel().ok();
+
+        let client_id = self.mqtt.client_id();
+        if let (Ok(data), Ok(description), Ok(rejected)) = (
+            OtaTopic::Data(Encoding::Cbor, self.stream_name).format::<MAX_TOPIC_LEN>(client_id),
+            OtaTopic::Description(Encoding::Cbor, self.stream_name)
+                .format::<MAX_TOPIC_LEN>(client_id),
+            OtaTopic::Rejected(Encoding::Cbor, self.stream_name).format::<MAX_TOPIC_LEN>(client_id),
+        ) {
+            self.mqtt
+                .unsubscribe(&[data.as_str(), description.as_str(), rejected.as_str()])
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::ota::test::{mock::MockTimer, TEST_TIMER_HZ};
+    use crate::test::MockMqtt;
+
+    #[derive(Default)]
+    struct VecSink {
+        data: Vec<u8>,
+        completed: bool,
+    }
+
+    impl StreamSink for VecSink {
+        type Error = ();
+
+        fn begin(&mut self, file_size: usize) -> Result<(), Self::Error> {
+            self.data = vec![0; file_size];
+            Ok(())
+        }
+
+        fn write_block(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn complete(&mut self) -> Result<(), Self::Error> {
+            self.completed = true;
+            Ok(())
+        }
+    }
+
+    /// CBOR encoded `GetStreamResponse` for file 0
+    fn block(block_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut v = vec![0xA4, 0x61, b'f', 0x00, 0x61, b'i', block_id];
+        v.extend_from_slice(&[0x61, b'l', payload.len() as u8, 0x61, b'p']);
+        v.push(0x40 | payload.len() as u8);
+        v.extend_from_slice(payload);
+        v
+    }
+
+    fn published_topics(mqtt: &MockMqtt) -> Vec<String> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some(p.topic_name.to_owned()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn download_with_description() {
+        let mqtt = &MockMqtt::new();
+
+        let mut download = Download::<_, _, _, TEST_TIMER_HZ>::new(
+            mqtt,
+            "test_stream",
+            0,
+            VecSink::default(),
+            MockTimer::new(),
+        )
+        .block_size(4);
+
+        assert_eq!(download.start(), Ok(Progress::Describing));
+        assert_eq!(
+            published_topics(mqtt),
+            vec!["$aws/things/test_client/streams/test_stream/describe/cbor"]
+        );
+
+        // {"s": 1, "d": "", "r": [{"f": 0, "z": 10}]}
+        let description = &mut [
+            0xA3, 0x61, b's', 0x01, 0x61, b'd', 0x60, 0x61, b'r', 0x81, 0xA2, 0x61, b'f', 0x00,
+            0x61, b'z', 0x0A,
+        ];
+        assert_eq!(
+            download.handle_message(
+                "$aws/things/test_client/streams/test_stream/description/cbor",
+                description
+            ),
+            Ok(Some(Progress::Downloading {
+                blocks_remaining: 3
+            }))
+        );
+        assert_eq!(
+            published_topics(mqtt),
+            vec!["$aws/things/test_client/streams/test_stream/get/cbor"]
+        );
+
+        let data = "$aws/things/test_client/streams/test_stream/data/cbor";
+        assert_eq!(
+            download.handle_message(data, &mut block(2, b"89")),
+            Ok(Some(Progress::Downloading {
+                blocks_remaining: 2
+            }))
+        );
+        // Duplicates are ignored
+        assert_eq!(
+            download.handle_message(data, &mut block(2, b"89")),
+            Ok(Some(Progress::Downloading {
+                blocks_remaining: 2
+            }))
+        );
+        download
+            .handle_message(data, &mut block(0, b"0123"))
+            .unwrap();
+        assert_eq!(
+            download.handle_message(data, &mut block(1, b"4567")),
+            Ok(Some(Progress::Complete))
+        );
+
+        assert_eq!(download.sink().data, b"0123456789");
+        assert!(download.sink().completed);
+
+        assert_eq!(
+            download.handle_message("$aws/things/test_client/jobs/notify-next", &mut []),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn momentum_abort() {
+        let mqtt = &MockMqtt::new();
+
+        let mut download = Download::<_, _, _, TEST_TIMER_HZ>::new(
+            mqtt,
+            "test_stream",
+            0,
+            VecSink::default(),
+            MockTimer::new(),
+        )
+        .file_size(10)
+        .max_request_momentum(2);
+
+        assert_eq!(
+            download.start(),
+            Ok(Progress::Downloading {
+                blocks_remaining: 1
+            })
+        );
+
+        // The mock timer always expires
+        download.timer_callback().unwrap();
+        download.timer_callback().unwrap();
+        assert_eq!(download.timer_callback(), Err(Error::MomentumAbort));
+
+        assert_eq!(
+            published_topics(mqtt),
+            vec!["$aws/things/test_client/streams/test_stream/get/cbor"; 3]
+        );
+    }
+
+    #[test]
+    fn rejected() {
+        let mqtt = &MockMqtt::new();
+
+        let mut download = Download::<_, _, _, TEST_TIMER_HZ>::new(
+            mqtt,
+            "test_stream",
+            0,
+            VecSink::default(),
+            MockTimer::new(),
+        );
+        download.start().unwrap();
+
+        // {"o": "NotFound", "m": ""}
+        let payload = &mut [
+            0xA2, 0x61, b'o', 0x68, b'N', b'o', b't', b'F', b'o', b'u', b'n', b'd', 0x61, b'm',
+            0x60,
+        ];
+        assert_eq!(
+            download.handle_message(
+                "$aws/things/test_client/streams/test_stream/rejected/cbor",
+                payload
+            ),
+            Err(Error::Rejected)
+        );
+    }
+}
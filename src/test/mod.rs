@@ -25,7 +25,7 @@ impl MockMqtt {
 
 impl Mqtt for MockMqtt {
     fn send(&self, packet: Packet<'_>) -> Result<(), MqttError> {
-        let v = &mut [0u8; 1024];
+        let v = &mut [0u8; 2048];
 
         let len = encode_slice(&packet, v).map_err(|_| MqttError::Full)?;
         let packet = v[..len].iter().cloned().collect();
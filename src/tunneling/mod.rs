@@ -0,0 +1,260 @@
+//! # AWS IoT Secure Tunneling
+//!
+//! When a tunnel is opened, AWS IoT Secure Tunneling delivers a client access
+//! token for the destination device on the MQTT topic
+//! `$aws/things/{thingName}/tunnels/notify`. The device uses this token to
+//! connect its local proxy to the secure tunneling service over a WebSocket.
+//!
+//! [`SecureTunneling`] subscribes to the notify topic and hands every parsed
+//! [`TunnelNotification`] to a user callback. The [`proxy`] module implements
+//! the local proxy protocol (v2 and v3) framing, for devices that run the
+//! local proxy themselves.
+//!
+//! <https://docs.aws.amazon.com/iot/latest/developerguide/secure-tunneling.html>
+pub mod proxy;
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use mqttrust::{Mqtt, QoS, SubscribeTopic};
+use serde::Deserialize;
+
+use crate::jobs::MAX_THING_NAME_LEN;
+
+/// A tunnel can forward at most three services.
+pub const MAX_SERVICES: usize = 3;
+
+pub const MAX_TOPIC_LEN: usize = MAX_THING_NAME_LEN + 27;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Overflow,
+    InvalidPayload,
+    Mqtt(mqttrust::MqttError),
+}
+
+impl From<mqttrust::MqttError> for Error {
+    fn from(e: mqttrust::MqttError) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientMode {
+    #[serde(rename = "source")]
+    Source,
+    #[serde(rename = "destination")]
+    Destination,
+}
+
+/// Subscribe to `$aws/things/{thingName}/tunnels/notify` to receive this
+/// notification when a tunnel is opened for the device.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TunnelNotification<'a> {
+    /// The access token used by the local proxy to connect to the tunnel.
+    #[serde(rename = "clientAccessToken")]
+    pub client_access_token: &'a str,
+
+    /// The local proxy mode, which is always `destination` for devices.
+    #[serde(rename = "clientMode")]
+    pub client_mode: ClientMode,
+
+    /// The AWS region of the secure tunneling endpoint to connect to.
+    #[serde(rename = "region")]
+    pub region: &'a str,
+
+    /// The services configured for the tunnel, eg. `SSH`.
+    #[serde(rename = "services")]
+    pub services: heapless::Vec<&'a str, MAX_SERVICES>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Topic {
+    /// `$aws/things/{thingName}/tunnels/notify`
+    Notify,
+}
+
+impl FromStr for Topic {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tt = s.splitn(5, '/').collect::<heapless::Vec<&str, 5>>();
+        match (tt.first(), tt.get(1), tt.get(3), tt.get(4)) {
+            (Some(&"$aws"), Some(&"things"), Some(&"tunnels"), Some(&"notify")) => {
+                Ok(Topic::Notify)
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl Topic {
+    const PREFIX: &'static str = "$aws/things";
+
+    pub fn check(s: &str) -> bool {
+        s.starts_with(Self::PREFIX) && s.ends_with("/tunnels/notify")
+    }
+
+    pub fn format<const L: usize>(&self, client_id: &str) -> Result<heapless::String<L>, Error> {
+        let mut topic_path = heapless::String::new();
+        match self {
+            Self::Notify => topic_path.write_fmt(format_args!(
+                "{}/{}/tunnels/notify",
+                Self::PREFIX,
+                client_id
+            )),
+        }
+        .map_err(|_| Error::Overflow)?;
+
+        Ok(topic_path)
+    }
+}
+
+/// Listens for Secure Tunneling notifications, handing each of them to
+/// `on_notify`.
+pub struct SecureTunneling<'a, M, F>
+where
+    M: Mqtt,
+    F: FnMut(&TunnelNotification<'_>),
+{
+    mqtt: &'a M,
+    on_notify: F,
+}
+
+impl<'a, M, F> SecureTunneling<'a, M, F>
+where
+    M: Mqtt,
+    F: FnMut(&TunnelNotification<'_>),
+{
+    pub fn new(mqtt: &'a M, on_notify: F) -> Self {
+        Self { mqtt, on_notify }
+    }
+
+    /// Subscribe to the notify topic of this thing.
+    pub fn initialize(&self) -> Result<(), Error> {
+        let topic = Topic::Notify.format::<MAX_TOPIC_LEN>(self.mqtt.client_id())?;
+
+        self.mqtt.subscribe(&[SubscribeTopic {
+            topic_path: topic.as_str(),
+            qos: QoS::AtLeastOnce,
+        }])?;
+
+        Ok(())
+    }
+
+    /// Handle an incoming message, invoking the notification callback if it
+    /// was received on the notify topic.
+    ///
+    /// Returns `Ok(false)` if `topic_name` is not the notify topic.
+    pub fn handle_message(&mut self, topic_name: &str, payload: &[u8]) -> Result<bool, Error> {
+        if Topic::from_str(topic_name) != Ok(Topic::Notify) {
+            return Ok(false);
+        }
+
+        let (notification, _) = serde_json_core::from_slice::<TunnelNotification>(payload)
+            .map_err(|_| Error::InvalidPayload)?;
+
+        debug!(
+            "Tunnel opened in {:?} for services {:?}",
+            notification.region,
+            notification.services.as_slice()
+        );
+
+        (self.on_notify)(&notification);
+
+        Ok(true)
+    }
+}
+
+impl<'a, M, F> Drop for SecureTunneling<'a, M, F>
+where
+    M: Mqtt,
+    F: FnMut(&TunnelNotification<'_>),
+{
+    fn drop(&mut self) {
+        if let Ok(topic) = Topic::Notify.format::<MAX_TOPIC_LEN>(self.mqtt.client_id()) {
+            self.mqtt.unsubscribe(&[topic.as_str()]).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::test::MockMqtt;
+
+    #[test]
+    fn topic() {
+        let path = Topic::Notify
+            .format::<MAX_TOPIC_LEN>("test_client")
+            .unwrap();
+        assert_eq!(path.as_str(), "$aws/things/test_client/tunnels/notify");
+        assert!(Topic::check(path.as_str()));
+        assert_eq!(Topic::from_str(path.as_str()), Ok(Topic::Notify));
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/jobs/notify"),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn handle_notification() {
+        let mqtt = &MockMqtt::new();
+        let mut received = None;
+
+        let mut tunneling = SecureTunneling::new(mqtt, |n: &TunnelNotification<'_>| {
+            received = Some((
+                heapless::String::<64>::from(n.client_access_token),
+                n.client_mode,
+                n.services.len(),
+            ));
+        });
+        tunneling.initialize().unwrap();
+
+        let payload = br#"{"clientAccessToken":"destination-client-access-token","clientMode":"destination","region":"eu-west-1","services":["SSH","RDP"]}"#;
+
+        assert_eq!(
+            tunneling.handle_message("$aws/things/test_client/jobs/notify", payload),
+            Ok(false)
+        );
+        assert_eq!(
+            tunneling.handle_message("$aws/things/test_client/tunnels/notify", payload),
+            Ok(true)
+        );
+        assert_eq!(
+            tunneling.handle_message("$aws/things/test_client/tunnels/notify", b"{}"),
+            Err(Error::InvalidPayload)
+        );
+        drop(tunneling);
+
+        assert_eq!(
+            received,
+            Some((
+                heapless::String::from("destination-client-access-token"),
+                ClientMode::Destination,
+                2
+            ))
+        );
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Subscribe(s)) => assert_eq!(
+                s.topics().next().unwrap().topic_path,
+                "$aws/things/test_client/tunnels/notify"
+            ),
+            _ => panic!(),
+        }
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        assert!(matches!(
+            decode_slice(bytes.as_slice()).unwrap(),
+            Some(Packet::Unsubscribe(_))
+        ));
+    }
+}
@@ -0,0 +1,605 @@
+//! Local proxy protocol framing.
+//!
+//! Every message exchanged with the secure tunneling service over the
+//! WebSocket connection is a frame consisting of a 2 byte big endian length,
+//! followed by a protobuf encoded `Message`:
+//!
+//! ```text
+//! message Message {
+//!     Type    type                         = 1;
+//!     int32   streamId                     = 2;
+//!     bool    ignorable                    = 3;
+//!     bytes   payload                      = 4;
+//!     string  serviceId                    = 5; // v2 and later
+//!     repeated string availableServiceIds  = 6; // v2 and later
+//!     uint32  connectionId                 = 7; // v3 and later
+//! }
+//! ```
+//!
+//! <https://github.com/aws-samples/aws-iot-securetunneling-localproxy/blob/main/V2WebSocketProtocolGuide.md>
+
+use super::MAX_SERVICES;
+
+/// Length of the frame header holding the message size.
+pub const FRAME_HEADER_LEN: usize = 2;
+
+/// Maximum size of a single encoded message, as defined by the protocol.
+pub const MAX_MESSAGE_LEN: usize = 63 * 1024;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolVersion {
+    V2,
+    V3,
+}
+
+impl ProtocolVersion {
+    /// The WebSocket sub-protocol to request when connecting to the tunnel.
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Self::V2 => "aws.iot.securetunneling-2.0",
+            Self::V3 => "aws.iot.securetunneling-3.0",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    Data,
+    StreamStart,
+    StreamReset,
+    SessionReset,
+    ServiceIds,
+    ConnectionStart,
+    ConnectionReset,
+    Unknown(u32),
+}
+
+impl From<u32> for MessageType {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => Self::Data,
+            2 => Self::StreamStart,
+            3 => Self::StreamReset,
+            4 => Self::SessionReset,
+            5 => Self::ServiceIds,
+            6 => Self::ConnectionStart,
+            7 => Self::ConnectionReset,
+            v => Self::Unknown(v),
+        }
+    }
+}
+
+impl From<MessageType> for u32 {
+    fn from(t: MessageType) -> Self {
+        match t {
+            MessageType::Data => 1,
+            MessageType::StreamStart => 2,
+            MessageType::StreamReset => 3,
+            MessageType::SessionReset => 4,
+            MessageType::ServiceIds => 5,
+            MessageType::ConnectionStart => 6,
+            MessageType::ConnectionReset => 7,
+            MessageType::Unknown(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodecError {
+    /// The output buffer is too small to hold the encoded frame.
+    Overflow,
+    /// The frame could not be decoded.
+    Malformed,
+    /// The message uses a field not supported by the protocol version.
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProxyError<E> {
+    Codec(CodecError),
+    WebSocket(E),
+}
+
+impl<E> From<CodecError> for ProxyError<E> {
+    fn from(e: CodecError) -> Self {
+        Self::Codec(e)
+    }
+}
+
+/// A single local proxy protocol message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Message<'a> {
+    pub message_type: MessageType,
+    pub stream_id: i32,
+    /// Receivers that do not understand the message type may ignore it.
+    pub ignorable: bool,
+    pub payload: &'a [u8],
+    pub service_id: Option<&'a str>,
+    pub available_service_ids: heapless::Vec<&'a str, MAX_SERVICES>,
+    /// Only available in protocol v3 and later, otherwise always `0`.
+    pub connection_id: u32,
+}
+
+impl<'a> Message<'a> {
+    pub fn new(message_type: MessageType, stream_id: i32) -> Self {
+        Self {
+            message_type,
+            stream_id,
+            ignorable: false,
+            payload: &[],
+            service_id: None,
+            available_service_ids: heapless::Vec::new(),
+            connection_id: 0,
+        }
+    }
+
+    pub fn data(stream_id: i32, payload: &'a [u8]) -> Self {
+        Self {
+            payload,
+            ..Self::new(MessageType::Data, stream_id)
+        }
+    }
+
+    pub fn service_id(self, service_id: &'a str) -> Self {
+        Self {
+            service_id: Some(service_id),
+            ..self
+        }
+    }
+
+    pub fn connection_id(self, connection_id: u32) -> Self {
+        Self {
+            connection_id,
+            ..self
+        }
+    }
+
+    /// Encode the message as a complete frame into `buf`, returning the
+    /// number of bytes written.
+    pub fn encode(&self, version: ProtocolVersion, buf: &mut [u8]) -> Result<usize, CodecError> {
+        if version == ProtocolVersion::V2 && self.connection_id != 0 {
+            return Err(CodecError::Unsupported);
+        }
+
+        let body = buf
+            .get_mut(FRAME_HEADER_LEN..)
+            .ok_or(CodecError::Overflow)?;
+        let mut w = Writer { buf: body, pos: 0 };
+
+        w.varint_field(1, u32::from(self.message_type) as u64)?;
+        // Negative int32 values are sign extended to 64 bits.
+        w.varint_field(2, self.stream_id as i64 as u64)?;
+        w.varint_field(3, self.ignorable as u64)?;
+        w.bytes_field(4, self.payload)?;
+        if let Some(service_id) = self.service_id {
+            w.bytes_field(5, service_id.as_bytes())?;
+        }
+        for service_id in &self.available_service_ids {
+            w.key(6, WIRE_LEN)?;
+            w.varint(service_id.len() as u64)?;
+            w.bytes(service_id.as_bytes())?;
+        }
+        w.varint_field(7, self.connection_id as u64)?;
+
+        let len = w.pos;
+        if len > MAX_MESSAGE_LEN {
+            return Err(CodecError::Overflow);
+        }
+        buf[..FRAME_HEADER_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+
+        Ok(FRAME_HEADER_LEN + len)
+    }
+
+    /// Decode a frame from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` does not yet hold a complete frame,
+    /// otherwise the message along with the number of bytes consumed.
+    pub fn decode(buf: &'a [u8]) -> Result<Option<(Self, usize)>, CodecError> {
+        let len = match buf.get(..FRAME_HEADER_LEN) {
+            Some(header) => u16::from_be_bytes([header[0], header[1]]) as usize,
+            None => return Ok(None),
+        };
+
+        let body = match buf.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len) {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+
+        let mut r = Reader { buf: body, pos: 0 };
+        let mut message = Self::new(MessageType::Unknown(0), 0);
+
+        while !r.is_empty() {
+            let key = r.varint()?;
+            let (field, wire_type) = (key >> 3, (key & 0x07) as u8);
+
+            match (field, wire_type) {
+                (1, WIRE_VARINT) => message.message_type = MessageType::from(r.varint()? as u32),
+                (2, WIRE_VARINT) => message.stream_id = r.varint()? as i32,
+                (3, WIRE_VARINT) => message.ignorable = r.varint()? != 0,
+                (4, WIRE_LEN) => message.payload = r.len_delimited()?,
+                (5, WIRE_LEN) => message.service_id = Some(r.str()?),
+                (6, WIRE_LEN) => {
+                    let service_id = r.str()?;
+                    message
+                        .available_service_ids
+                        .push(service_id)
+                        .map_err(|_| CodecError::Malformed)?;
+                }
+                (7, WIRE_VARINT) => message.connection_id = r.varint()? as u32,
+                // Skip unknown fields, to stay forward compatible
+                (_, WIRE_VARINT) => {
+                    r.varint()?;
+                }
+                (_, WIRE_FIXED64) => {
+                    r.take(8)?;
+                }
+                (_, WIRE_LEN) => {
+                    r.len_delimited()?;
+                }
+                (_, WIRE_FIXED32) => {
+                    r.take(4)?;
+                }
+                _ => return Err(CodecError::Malformed),
+            }
+        }
+
+        Ok(Some((message, FRAME_HEADER_LEN + len)))
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, data: &[u8]) -> Result<(), CodecError> {
+        self.buf
+            .get_mut(self.pos..self.pos + data.len())
+            .ok_or(CodecError::Overflow)?
+            .copy_from_slice(data);
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn varint(&mut self, mut v: u64) -> Result<(), CodecError> {
+        loop {
+            let byte = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                return self.bytes(&[byte]);
+            }
+            self.bytes(&[byte | 0x80])?;
+        }
+    }
+
+    fn key(&mut self, field: u8, wire_type: u8) -> Result<(), CodecError> {
+        self.varint(((field << 3) | wire_type) as u64)
+    }
+
+    /// Write a varint field, omitting it if it holds the default value.
+    fn varint_field(&mut self, field: u8, v: u64) -> Result<(), CodecError> {
+        if v == 0 {
+            return Ok(());
+        }
+        self.key(field, WIRE_VARINT)?;
+        self.varint(v)
+    }
+
+    /// Write a length delimited field, omitting it if it is empty.
+    fn bytes_field(&mut self, field: u8, data: &[u8]) -> Result<(), CodecError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.key(field, WIRE_LEN)?;
+        self.varint(data.len() as u64)?;
+        self.bytes(data)
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CodecError> {
+        let end = self.pos.checked_add(n).ok_or(CodecError::Malformed)?;
+        let data = self.buf.get(self.pos..end).ok_or(CodecError::Malformed)?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn varint(&mut self) -> Result<u64, CodecError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            v |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(CodecError::Malformed)
+    }
+
+    fn len_delimited(&mut self) -> Result<&'a [u8], CodecError> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str, CodecError> {
+        core::str::from_utf8(self.len_delimited()?).map_err(|_| CodecError::Malformed)
+    }
+}
+
+/// A WebSocket connection to the secure tunneling service, provided by the
+/// application.
+///
+/// The connection must be opened against
+/// `wss://data.tunneling.iot.{region}.amazonaws.com:443/tunnel?local-proxy-mode=destination`
+/// using the `access-token` header set to the client access token, and the
+/// sub-protocol given by [`ProtocolVersion::subprotocol`].
+pub trait WebSocket {
+    type Error;
+
+    /// Send `data` as a single binary WebSocket message.
+    fn send(&mut self, data: &[u8]) -> nb::Result<(), Self::Error>;
+
+    /// Receive the payload of binary WebSocket messages into `buf`,
+    /// returning the number of bytes received.
+    fn receive(&mut self, buf: &mut [u8]) -> nb::Result<usize, Self::Error>;
+}
+
+/// Drives the local proxy protocol over a [`WebSocket`], buffering up to `N`
+/// bytes of incoming frames.
+pub struct LocalProxy<W: WebSocket, const N: usize> {
+    ws: W,
+    version: ProtocolVersion,
+    rx: [u8; N],
+    rx_len: usize,
+    consumed: usize,
+}
+
+impl<W: WebSocket, const N: usize> LocalProxy<W, N> {
+    pub fn new(ws: W, version: ProtocolVersion) -> Self {
+        Self {
+            ws,
+            version,
+            rx: [0; N],
+            rx_len: 0,
+            consumed: 0,
+        }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn into_inner(self) -> W {
+        self.ws
+    }
+
+    /// Encode `message` using `buf` as scratch space, and send it over the
+    /// WebSocket.
+    pub fn send(
+        &mut self,
+        message: &Message<'_>,
+        buf: &mut [u8],
+    ) -> nb::Result<(), ProxyError<W::Error>> {
+        let len = message
+            .encode(self.version, buf)
+            .map_err(ProxyError::from)?;
+        self.ws
+            .send(&buf[..len])
+            .map_err(|e| e.map(ProxyError::WebSocket))
+    }
+
+    /// Poll for the next message from the tunnel.
+    ///
+    /// The returned message borrows the receive buffer, and is discarded on
+    /// the next call to `poll`.
+    pub fn poll(&mut self) -> nb::Result<Message<'_>, ProxyError<W::Error>> {
+        // Discard the previously returned frame
+        self.rx.copy_within(self.consumed..self.rx_len, 0);
+        self.rx_len -= self.consumed;
+        self.consumed = 0;
+
+        if !self.has_frame()? {
+            let len = self
+                .ws
+                .receive(&mut self.rx[self.rx_len..])
+                .map_err(|e| e.map(ProxyError::WebSocket))?;
+            self.rx_len += len;
+
+            if !self.has_frame()? {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+
+        let (message, consumed) = Message::decode(&self.rx[..self.rx_len])
+            .map_err(ProxyError::from)?
+            .ok_or(nb::Error::WouldBlock)?;
+        self.consumed = consumed;
+
+        Ok(message)
+    }
+
+    fn has_frame(&self) -> Result<bool, ProxyError<W::Error>> {
+        if self.rx_len < FRAME_HEADER_LEN {
+            return Ok(false);
+        }
+
+        let len = FRAME_HEADER_LEN + u16::from_be_bytes([self.rx[0], self.rx[1]]) as usize;
+        if len > N {
+            // The frame can never fit in the receive buffer
+            return Err(ProxyError::Codec(CodecError::Overflow));
+        }
+
+        Ok(self.rx_len >= len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// StreamStart, stream 1, service "SSH", connection 1
+    const STREAM_START: &[u8] = &[
+        0x00, 0x0B, 0x08, 0x02, 0x10, 0x01, 0x2A, 0x03, b'S', b'S', b'H', 0x38, 0x01,
+    ];
+
+    /// ServiceIds, available services "SSH" and "RDP"
+    const SERVICE_IDS: &[u8] = &[
+        0x00, 0x0C, 0x08, 0x05, 0x32, 0x03, b'S', b'S', b'H', 0x32, 0x03, b'R', b'D', b'P',
+    ];
+
+    /// Data, stream 1, payload "hello"
+    const DATA: &[u8] = &[
+        0x00, 0x0B, 0x08, 0x01, 0x10, 0x01, 0x22, 0x05, b'h', b'e', b'l', b'l', b'o',
+    ];
+
+    #[test]
+    fn decode_frames() {
+        let (message, len) = Message::decode(STREAM_START).unwrap().unwrap();
+        assert_eq!(len, STREAM_START.len());
+        assert_eq!(
+            message,
+            Message::new(MessageType::StreamStart, 1)
+                .service_id("SSH")
+                .connection_id(1)
+        );
+
+        let (message, _) = Message::decode(SERVICE_IDS).unwrap().unwrap();
+        assert_eq!(message.message_type, MessageType::ServiceIds);
+        assert_eq!(message.available_service_ids.as_slice(), &["SSH", "RDP"]);
+
+        let (message, _) = Message::decode(DATA).unwrap().unwrap();
+        assert_eq!(message, Message::data(1, b"hello"));
+    }
+
+    #[test]
+    fn decode_partial_and_malformed() {
+        assert_eq!(Message::decode(&DATA[..1]), Ok(None));
+        assert_eq!(Message::decode(&DATA[..DATA.len() - 1]), Ok(None));
+        // Length delimited payload running past the end of the frame
+        assert_eq!(
+            Message::decode(&[0x00, 0x04, 0x08, 0x01, 0x22, 0x05]),
+            Err(CodecError::Malformed)
+        );
+        // Length overflowing the read position
+        let mut frame = vec![0x00, 0x0B, 0x22];
+        frame.extend_from_slice(&[0xFF; 9]);
+        frame.push(0x01);
+        assert_eq!(Message::decode(&frame), Err(CodecError::Malformed));
+        // Unknown fields are skipped
+        let (message, _) = Message::decode(&[0x00, 0x04, 0x08, 0x03, 0x40, 0x01])
+            .unwrap()
+            .unwrap();
+        assert_eq!(message, Message::new(MessageType::StreamReset, 0));
+    }
+
+    #[test]
+    fn encode_frames() {
+        let buf = &mut [0u8; 32];
+
+        let message = Message::new(MessageType::StreamStart, 1)
+            .service_id("SSH")
+            .connection_id(1);
+        let len = message.encode(ProtocolVersion::V3, buf).unwrap();
+        assert_eq!(&buf[..len], STREAM_START);
+        assert_eq!(
+            message.encode(ProtocolVersion::V2, buf),
+            Err(CodecError::Unsupported)
+        );
+
+        let len = Message::data(1, b"hello")
+            .encode(ProtocolVersion::V2, buf)
+            .unwrap();
+        assert_eq!(&buf[..len], DATA);
+
+        assert_eq!(
+            Message::data(1, b"hello").encode(ProtocolVersion::V2, &mut [0u8; 8]),
+            Err(CodecError::Overflow)
+        );
+
+        // Negative stream ids round trip through their 10 byte varint encoding
+        let len = Message::new(MessageType::StreamReset, -1)
+            .encode(ProtocolVersion::V2, buf)
+            .unwrap();
+        let (message, _) = Message::decode(&buf[..len]).unwrap().unwrap();
+        assert_eq!(message.stream_id, -1);
+    }
+
+    #[derive(Default)]
+    struct MockWebSocket {
+        rx: VecDeque<Vec<u8>>,
+        tx: Vec<Vec<u8>>,
+    }
+
+    impl WebSocket for &mut MockWebSocket {
+        type Error = ();
+
+        fn send(&mut self, data: &[u8]) -> nb::Result<(), Self::Error> {
+            self.tx.push(data.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self, buf: &mut [u8]) -> nb::Result<usize, Self::Error> {
+            let data = self.rx.pop_front().ok_or(nb::Error::WouldBlock)?;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn local_proxy() {
+        let mut ws = MockWebSocket::default();
+        // Two frames in one WebSocket message, followed by a frame split
+        // across two messages.
+        ws.rx.push_back([SERVICE_IDS, STREAM_START].concat());
+        ws.rx.push_back(DATA[..4].to_vec());
+        ws.rx.push_back(DATA[4..].to_vec());
+
+        let mut proxy = LocalProxy::<_, 64>::new(&mut ws, ProtocolVersion::V3);
+
+        assert_eq!(proxy.poll().unwrap().message_type, MessageType::ServiceIds);
+        assert_eq!(proxy.poll().unwrap().message_type, MessageType::StreamStart);
+        assert_eq!(proxy.poll(), Err(nb::Error::WouldBlock));
+        assert_eq!(proxy.poll().unwrap(), Message::data(1, b"hello"));
+        assert_eq!(proxy.poll(), Err(nb::Error::WouldBlock));
+
+        proxy
+            .send(&Message::data(1, b"hello"), &mut [0u8; 32])
+            .unwrap();
+
+        assert_eq!(proxy.into_inner().tx, vec![DATA.to_vec()]);
+    }
+
+    #[test]
+    fn local_proxy_frame_too_large() {
+        let mut ws = MockWebSocket::default();
+        ws.rx.push_back(vec![0x01, 0x00]);
+
+        let mut proxy = LocalProxy::<_, 64>::new(&mut ws, ProtocolVersion::V2);
+        assert_eq!(
+            proxy.poll(),
+            Err(nb::Error::Other(ProxyError::Codec(CodecError::Overflow)))
+        );
+    }
+}
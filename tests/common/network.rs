@@ -250,3 +250,35 @@ impl TcpClientStack for Network<TcpStream> {
         Ok(())
     }
 }
+
+impl rustot::http::HttpTransport for Network<TlsStream<TcpStream>> {
+    type Error = std::io::Error;
+
+    fn request(
+        &mut self,
+        host: &str,
+        port: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let (connector, _) = self.tls_connector.as_ref().unwrap();
+
+        let soc = TcpStream::connect((host, port))?;
+        let mut tls_stream = connector
+            .connect(host, soc)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+        tls_stream.write_all(request)?;
+
+        let mut len = 0;
+        loop {
+            match tls_stream.read(&mut response[len..]) {
+                Ok(0) => return Ok(len),
+                Ok(n) => len += n,
+                // Some servers close the connection without a `close_notify`
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(len),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//!
+//! ## Integration test of the `AWS IoT credential provider`
+//!
+//!
+//! This test exchanges the device certificate for temporary AWS credentials,
+//! using the same mutually authenticated TLS setup as the MQTT tests.
+//!
+//! It requires a role alias (`AWS_ROLE_ALIAS`, defaulting to
+//! `rustot-test-role-alias`) that the device certificate is allowed to assume,
+//! and the account specific credential provider endpoint in
+//! `AWS_CREDENTIALS_HOSTNAME`.
+//!
+
+mod common;
+
+use common::{credentials, network::Network};
+use native_tls::TlsConnector;
+use rustot::credentials::CredentialsProvider;
+
+#[test]
+#[ignore = "requires a configured role alias"]
+fn test_credentials_provider() {
+    env_logger::init();
+
+    let hostname = option_env!("AWS_CREDENTIALS_HOSTNAME").unwrap();
+    let role_alias = option_env!("AWS_ROLE_ALIAS").unwrap_or("rustot-test-role-alias");
+    let (thing_name, identity) = credentials::identity();
+
+    let connector = TlsConnector::builder()
+        .identity(identity)
+        .add_root_certificate(credentials::root_ca())
+        .build()
+        .unwrap();
+
+    let network = Network::new_tls(connector, std::string::String::from(hostname));
+    let mut provider = CredentialsProvider::new(network, hostname, role_alias, thing_name);
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let buf = &mut [0u8; 8192];
+    let credentials = provider.get(now, buf).unwrap().clone();
+    assert!(!credentials.is_expired(now));
+    assert!(!credentials.access_key_id.is_empty());
+    assert!(!credentials.session_token.is_empty());
+
+    // Served from the cache
+    assert_eq!(provider.get(now, buf).unwrap(), &credentials);
+}
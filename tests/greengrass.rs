@@ -0,0 +1,104 @@
+//!
+//! ## Integration test of `AWS IoT Greengrass discovery`
+//!
+//!
+//! This test runs the discovery request against a local fake discovery
+//! endpoint over plain TCP, standing in for the mutually authenticated TLS
+//! connection used against AWS IoT:
+//! 1. Start a fake server responding with a canned discovery document
+//! 2. Discover the core devices of the thing through the crate API
+//! 3. Assert on the request received by the server
+//! 4. Assert on the parsed groups, cores and CA certificates
+//!
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use rustot::greengrass::discovery::{unescape, Discover, DiscoverResponse};
+use rustot::http::HttpTransport;
+
+const DISCOVER_RESPONSE: &str = r#"{"GGGroups":[{"GGGroupId":"greengrassV2-coreDevice-MyGreengrassCore","Cores":[{"thingArn":"arn:aws:iot:us-west-2:123456789012:thing/MyGreengrassCore","Connectivity":[{"Id":"AUTOIP_192.168.1.4_1","HostAddress":"192.168.1.5","PortNumber":8883,"Metadata":""},{"Id":"AUTOIP_127.0.0.1_0","HostAddress":"127.0.0.1","PortNumber":8883,"Metadata":""}]}],"CAs":["-----BEGIN CERTIFICATE-----\nMIICiT...EXAMPLE=\n-----END CERTIFICATE-----\n"]}]}"#;
+
+struct TcpTransport;
+
+impl HttpTransport for TcpTransport {
+    type Error = std::io::Error;
+
+    fn request(
+        &mut self,
+        host: &str,
+        port: u16,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let mut stream = TcpStream::connect((host, port))?;
+        stream.write_all(request)?;
+
+        let mut len = 0;
+        loop {
+            match stream.read(&mut response[len..])? {
+                0 => return Ok(len),
+                n => len += n,
+            }
+        }
+    }
+}
+
+fn fake_server(listener: TcpListener) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut request = Vec::new();
+        let mut buf = [0u8; 256];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            DISCOVER_RESPONSE.len(),
+            DISCOVER_RESPONSE
+        )
+        .unwrap();
+
+        String::from_utf8(request).unwrap()
+    })
+}
+
+#[test]
+fn test_greengrass_discovery() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = fake_server(listener);
+
+    let buf = &mut [0u8; 2048];
+    let response: DiscoverResponse<'_, 1, 1, 2, 1> = Discover::new("127.0.0.1", "test_thing")
+        .port(port)
+        .send(&mut TcpTransport, buf)
+        .unwrap();
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("GET /greengrass/discover/thing/test_thing HTTP/1.1\r\n"));
+    assert!(request.contains("Host: 127.0.0.1\r\n"));
+
+    let group = &response.groups[0];
+    assert_eq!(group.group_id, "greengrassV2-coreDevice-MyGreengrassCore");
+
+    let core = &group.cores[0];
+    assert_eq!(
+        core.thing_arn,
+        "arn:aws:iot:us-west-2:123456789012:thing/MyGreengrassCore"
+    );
+    assert_eq!(core.connectivity.len(), 2);
+    assert_eq!(core.connectivity[1].host_address, "127.0.0.1");
+    assert_eq!(core.connectivity[1].port_number, 8883);
+
+    let pem = unescape(group.cas[0], &mut [0u8; 128]).map(String::from);
+    assert_eq!(
+        pem.as_deref(),
+        Some("-----BEGIN CERTIFICATE-----\nMIICiT...EXAMPLE=\n-----END CERTIFICATE-----\n")
+    );
+}
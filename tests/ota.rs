@@ -149,7 +149,7 @@ fn test_mqtt_ota_inner() {
                         file_info.replace(FileInfo {
                             file_path: file.filepath.to_string(),
                             filesize: file.filesize,
-                            signature: file.signature(),
+                            signature: file.signature().expect("File without signature"),
                         });
                         ota_agent
                             .job_update(job_id, &job_doc, status_details.as_ref())
@@ -159,12 +159,19 @@ fn test_mqtt_ota_inner() {
                         if ota_agent.handle_message(payload).is_err() {
                             match ota_agent.state() {
                                 States::CreatingFile => log::info!("State: CreatingFile"),
+                                States::WaitingForStreamDescription => {
+                                    log::info!("State: WaitingForStreamDescription")
+                                }
                                 States::Ready => log::info!("State: Ready"),
                                 States::RequestingFileBlock => {
                                     log::info!("State: RequestingFileBlock")
                                 }
                                 States::RequestingJob => log::info!("State: RequestingJob"),
+                                States::ReadyToActivate => {
+                                    log::info!("State: ReadyToActivate")
+                                }
                                 States::Restarting => log::info!("State: Restarting"),
+                                States::SelfTesting => log::info!("State: SelfTesting"),
                                 States::Suspended => log::info!("State: Suspended"),
                                 States::WaitingForFileBlock => {
                                     log::info!("State: WaitingForFileBlock")